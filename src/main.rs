@@ -1,45 +1,183 @@
 mod rs232_bridge;
+mod rs232_bridge_access;
+mod rs232_bridge_api;
+mod rs232_bridge_audit;
+mod rs232_bridge_config;
+mod rs232_bridge_credentials;
 mod rs232_bridge_def;
+mod rs232_bridge_events;
+mod rs232_bridge_idle;
+mod rs232_bridge_logger;
+mod rs232_bridge_metrics;
+mod rs232_bridge_mode;
+mod rs232_bridge_mqtt;
+mod rs232_bridge_reload;
+mod rs232_bridge_schedule;
+mod rs232_bridge_search;
+mod rs232_bridge_shutdown;
+mod rs232_bridge_state;
+mod rs232_bridge_systemd;
 
-use pjlink_bridge::*;
+use rs232_bridge_access::{BridgeAccessPolicy, BridgeAccessServer};
+use rs232_bridge_api::{BridgeApiServer};
+use rs232_bridge_audit::{BridgeAudit, BridgeAuditFilter};
+use rs232_bridge_config::{BridgeConfig, BridgeConfigAudit, BridgeConfigLog, BridgeConfigLogFormat, BridgeConfigLogOutput, BridgeConfigProjector};
+use rs232_bridge_credentials::{BridgeCredentials};
 use rs232_bridge_def::{BridgeDefinition};
+use rs232_bridge_events::{BridgeEvents, BridgeEventsServer};
+use rs232_bridge_idle::{BridgeIdle};
+use rs232_bridge_logger::{BridgeLogger};
+use rs232_bridge_metrics::{BridgeMetricsServer, BridgeMetricsProjectors};
+use rs232_bridge_mode::{BridgeMode, BridgeModeProjectors};
+use rs232_bridge_mqtt::{BridgeMqtt, BridgeMqttProjectors};
+use rs232_bridge_reload::{BridgeReload, BridgeReloadConfig, BridgeReloadOverrides, BridgeReloadProjectors};
+use rs232_bridge_schedule::{BridgeSchedule, BridgeScheduleProjectors};
+use rs232_bridge_search::{BridgeSearchServer};
+use rs232_bridge_shutdown::{BridgeShutdown, BridgeShutdownProjectors};
+use rs232_bridge_state::{BridgeState};
+use rs232_bridge_systemd::{BridgeSystemd, BridgeSystemdProjectors};
 
-use std::sync::{Arc, Mutex};
+use std::{net::{IpAddr, TcpListener}, process::exit, sync::{Arc, Mutex, atomic::AtomicBool}, thread::JoinHandle};
 use clap::{AppSettings, Clap};
-use log::{LevelFilter, error, info};
-use simple_logger::{SimpleLogger};
+use log::{LevelFilter, error, info, warn};
 use uuid::Uuid;
 
 use crate::rs232_bridge::{PjLinkRS232Projector, PjLinkRS232ProjectorOptions};
 
+// Command line options. Each option can also be set through the environment variable
+// named in `env`, and both take precedence over the configuration file.
 #[derive(Clap)]
 #[clap(version = "0.1.0", author = "Mateus Meyer Jiacomelli")]
 #[clap(setting = AppSettings::ColoredHelp)]
+#[clap(setting = AppSettings::SubcommandsNegateReqs)]
 struct Opts {
-    #[clap(short, long, default_value = "0.0.0.0")]
-    listen_address: String,
-    #[clap(short, long, default_value = "4352")]
-    port: String,
+    #[clap(long, env = "PJLINK_BRIDGE_CONFIG")]
+    config: Option<String>,
+    #[clap(short, long, env = "PJLINK_BRIDGE_LISTEN_ADDRESS")]
+    listen_address: Option<String>,
+    #[clap(short, long, env = "PJLINK_BRIDGE_PORT")]
+    port: Option<u16>,
     #[clap(short, long, parse(from_occurrences))]
     verbose: i32,
     #[clap(long)]
     no_log: bool,
+    #[clap(long, env = "PJLINK_BRIDGE_LOG_FORMAT", possible_values = &["text", "json"])]
+    log_format: Option<String>,
+    #[clap(long, env = "PJLINK_BRIDGE_LOG_FILE")]
+    log_file: Option<String>,
+    #[clap(long)]
+    syslog: bool,
     #[clap(short, long)]
     udp: bool,
-    #[clap(long, default_value = "0.0.0.0")]
-    udp_listen_address: String,
-    #[clap(long)]
-    projector_name: String,
-    #[clap(long)]
+    #[clap(long, env = "PJLINK_BRIDGE_UDP_LISTEN_ADDRESS")]
+    udp_listen_address: Option<String>,
+    #[clap(long, env = "PJLINK_BRIDGE_PROJECTOR_NAME", required_unless_present = "config")]
+    projector_name: Option<String>,
+    #[clap(long, env = "PJLINK_BRIDGE_SERIAL_NUMBER")]
     serial_number: Option<String>,
-    #[clap(long)]
+    #[clap(long, env = "PJLINK_BRIDGE_PASSWORD", hide_env_values = true)]
     password: Option<String>,
-    #[clap(required = true)]
-    serial_port: String,
-    #[clap(short, long)]
+    #[clap(long, env = "PJLINK_BRIDGE_PASSWORD_FILE", conflicts_with = "password")]
+    password_file: Option<String>,
+    #[clap(long, env = "PJLINK_BRIDGE_CREDENTIALS_FILE")]
+    credentials_file: Option<String>,
+    #[clap(long, env = "PJLINK_BRIDGE_STATE_DIRECTORY")]
+    state_directory: Option<String>,
+    #[clap(long)]
+    watch_definitions: bool,
+    #[clap(long, env = "PJLINK_BRIDGE_METRICS_LISTEN_ADDRESS")]
+    metrics_listen_address: Option<String>,
+    #[clap(long, env = "PJLINK_BRIDGE_EVENTS_LISTEN_ADDRESS")]
+    events_listen_address: Option<String>,
+    #[clap(long, env = "PJLINK_BRIDGE_EVENTS_TOKEN")]
+    events_token: Option<String>,
+    #[clap(long, env = "PJLINK_BRIDGE_AUDIT_FILE")]
+    audit_file: Option<String>,
+    #[clap(env = "PJLINK_BRIDGE_SERIAL_PORT", required_unless_present = "config")]
+    serial_port: Option<String>,
+    #[clap(short, long, env = "PJLINK_BRIDGE_BAUD_RATE")]
     baud_rate: Option<u32>,
-    #[clap(default_value = "projector_info.toml")]
-    projector_info_path: String,
+    #[clap(env = "PJLINK_BRIDGE_DEFINITION")]
+    projector_info_path: Option<String>,
+    #[clap(subcommand)]
+    subcommand: Option<BridgeSubcommand>,
+}
+
+#[derive(Clap)]
+enum BridgeSubcommand {
+    /// Prints the audit log of state-changing commands, oldest first
+    Audit(AuditOpts),
+}
+
+#[derive(Clap)]
+struct AuditOpts {
+    #[clap(long)]
+    projector: Option<String>,
+    /// PJLink command, with or without class (e.g. POWR or 1POWR)
+    #[clap(long)]
+    command: Option<String>,
+    /// RFC 3339 timestamp (e.g. 2021-03-01T08:00:00-03:00)
+    #[clap(long)]
+    since: Option<String>,
+    /// Prints only the most recent records
+    #[clap(short = 'n', long)]
+    limit: Option<usize>,
+    /// Prints records as JSON lines
+    #[clap(long)]
+    json: bool,
+}
+
+impl Opts {
+    /// Whether any projector specific option was set, through flags or environment.
+    #[inline(always)]
+    fn has_projector_overrides(&self) -> bool {
+        self.listen_address.is_some() || self.port.is_some() || self.udp
+            || self.udp_listen_address.is_some() || self.projector_name.is_some()
+            || self.serial_number.is_some() || self.password.is_some()
+            || self.password_file.is_some() || self.serial_port.is_some()
+            || self.baud_rate.is_some() || self.projector_info_path.is_some()
+    }
+
+    /// Applies log options over the `[log]` section of the configuration file.
+    /// A log file or `--syslog` adds an output, keeping the configured ones.
+    fn apply_log_overrides(&self, log_config: &mut BridgeConfigLog) {
+        match self.log_format.as_deref() {
+            Some("json") => log_config.format = BridgeConfigLogFormat::Json,
+            Some("text") => log_config.format = BridgeConfigLogFormat::Text,
+            _ => {}
+        }
+
+        if let Some(log_file) = &self.log_file {
+            log_config.file = Some(log_file.clone());
+
+            if !log_config.outputs.contains(&BridgeConfigLogOutput::File) {
+                log_config.outputs.push(BridgeConfigLogOutput::File);
+            }
+        }
+
+        if self.syslog && !log_config.outputs.contains(&BridgeConfigLogOutput::Syslog) {
+            log_config.outputs.push(BridgeConfigLogOutput::Syslog);
+        }
+    }
+
+    /// Applies projector specific options over a projector read from the configuration file.
+    fn apply_projector_overrides(&self, projector: &mut BridgeConfigProjector) {
+        if let Some(listen_address) = &self.listen_address {projector.listen_address = listen_address.clone();}
+        if let Some(port) = self.port {projector.port = port;}
+        if self.udp {projector.udp = true;}
+        if let Some(udp_listen_address) = &self.udp_listen_address {projector.udp_listen_address = udp_listen_address.clone();}
+        if let Some(projector_name) = &self.projector_name {projector.name = projector_name.clone();}
+        if let Some(serial_number) = &self.serial_number {projector.serial_number = Some(serial_number.clone());}
+        if let Some(serial_port) = &self.serial_port {projector.serial_port = serial_port.clone();}
+        if let Some(baud_rate) = self.baud_rate {projector.baud_rate = Some(baud_rate);}
+        if let Some(projector_info_path) = &self.projector_info_path {projector.definition = projector_info_path.clone();}
+
+        // A password given here replaces both password sources from the configuration file
+        if self.password.is_some() || self.password_file.is_some() {
+            projector.password = self.password.clone();
+            projector.password_file = self.password_file.clone();
+        }
+    }
 }
 
 #[inline(always)]
@@ -57,65 +195,425 @@ fn default_log_logging(selected_level: i32, default_level: LevelFilter) -> Level
 pub fn main() {
     let cmd_opts = Opts::parse();
 
-    if !cmd_opts.no_log {
-        SimpleLogger::new()
-            .with_level(default_log_logging(cmd_opts.verbose, LevelFilter::Warn))
-            .with_module_level("pjlink_rs232_bridge", default_log_logging(cmd_opts.verbose, LevelFilter::Info))
-            .with_module_level("pjlink_rs232_bridge::rs232_bridge", default_log_logging(cmd_opts.verbose, LevelFilter::Warn))
-            .with_module_level("pjlink_bridge", default_log_logging(cmd_opts.verbose, LevelFilter::Info))
+    let mut config_result = match &cmd_opts.config {
+        Some(config_path) => BridgeConfig::from_file(config_path.clone()),
+        None => Ok(BridgeConfig::default())
+    };
+
+    let (verbose, no_log, mut log_config) = match &mut config_result {
+        Ok(config) => (
+            if cmd_opts.verbose > 0 {cmd_opts.verbose} else {config.verbose.unwrap_or_default()},
+            cmd_opts.no_log || config.no_log,
+            std::mem::take(&mut config.log)
+        ),
+        Err(_) => (cmd_opts.verbose, cmd_opts.no_log, BridgeConfigLog::default())
+    };
+
+    cmd_opts.apply_log_overrides(&mut log_config);
+
+    if !no_log {
+        let logger_result = BridgeLogger::new(&log_config).and_then(|logger| logger
+            .with_level(default_log_logging(verbose, LevelFilter::Warn))
+            .with_module_level("pjlink_rs232_bridge", default_log_logging(verbose, LevelFilter::Info))
+            .with_module_level("pjlink_rs232_bridge::rs232_bridge", default_log_logging(verbose, LevelFilter::Warn))
+            .with_module_level("pjlink_bridge", default_log_logging(verbose, LevelFilter::Info))
             .init()
-            .unwrap();
+        );
+
+        if let Err(err) = logger_result {
+            eprintln!("{}", err.message);
+            exit(1);
+        }
+    }
+
+    let config = match config_result {
+        Ok(config) => config,
+        Err(err) => {
+            error!("{}", err.message);
+            exit(1)
+        }
+    };
+
+    let state_directory = cmd_opts.state_directory.clone().or(config.state_directory);
+    let watch_definitions = cmd_opts.watch_definitions || config.watch_definitions;
+    let metrics_listen_address = cmd_opts.metrics_listen_address.clone().or(config.metrics_listen_address);
+    let events_listen_address = cmd_opts.events_listen_address.clone().or(config.events_listen_address);
+    let events_token = cmd_opts.events_token.clone().or(config.events_token);
+    let credentials_file = cmd_opts.credentials_file.clone().or(config.credentials_file);
+    let mut audit = config.audit;
+    let mut projectors = config.projectors;
+
+    if let Some(audit_file) = &cmd_opts.audit_file {
+        match &mut audit {
+            Some(audit) => audit.file = audit_file.clone(),
+            None => audit = Some(BridgeConfigAudit {
+                file: audit_file.clone(),
+                max_file_size: rs232_bridge_config::default_audit_max_file_size(),
+                max_files: rs232_bridge_config::default_audit_max_files(),
+            })
+        }
+    }
+
+    if let Some(BridgeSubcommand::Audit(audit_opts)) = &cmd_opts.subcommand {
+        exit(print_audit(audit_opts, audit.as_ref()));
+    }
+
+    if projectors.is_empty() {
+        projectors.push(BridgeConfigProjector::default());
+    }
+
+    if cmd_opts.has_projector_overrides() {
+        if projectors.len() > 1 {
+            error!("Projector options can't be set from command line or environment when the configuration file has more than one projector");
+            exit(1);
+        }
+
+        cmd_opts.apply_projector_overrides(&mut projectors[0]);
+    }
+
+    if projectors.iter().any(|projector| projector.serial_port.is_empty()) {
+        error!("A serial port must be set for every projector");
+        exit(1);
+    }
+
+    if state_directory.is_some() {
+        if let Err(err) = BridgeState::check_state_ids(projectors.iter().map(|projector| projector.state_id())) {
+            error!("{}", err.message);
+            exit(1);
+        }
+    }
+
+    if config.mqtt.is_some() {
+        if let Err(err) = BridgeMqtt::check_topic_names(projectors.iter().map(|projector| projector.name.as_str())) {
+            error!("{}", err.message);
+            exit(1);
+        }
+    }
+
+    let mut activated_listeners = BridgeSystemd::activated_listeners();
+
+    let schedule = match BridgeSchedule::from_config(&config.schedules) {
+        Ok(schedule) => schedule,
+        Err(err) => {
+            error!("{}", err.message);
+            exit(1);
+        }
+    };
+
+    let credentials = match credentials_file {
+        Some(credentials_file) => match BridgeCredentials::from_file(credentials_file) {
+            Ok(credentials) => Some(Arc::new(credentials)),
+            Err(err) => {
+                error!("{}", err.message);
+                exit(1);
+            }
+        },
+        None => None
+    };
+
+    let events = Arc::new(BridgeEvents::default());
+
+    if let Some(events_listen_address) = events_listen_address {
+        BridgeEventsServer::spawn(events_listen_address, events_token, events.clone());
+    }
+
+    if let Some(audit) = &audit {
+        if let Err(err) = BridgeAudit::spawn(audit, &events) {
+            error!("{}", err.message);
+            exit(1);
+        }
     }
 
-    let tcp_bind_address = cmd_opts.listen_address;
-    let tcp_port = cmd_opts.port;
-    let password = cmd_opts.password;
+    let mut handles = Vec::<JoinHandle<()>>::new();
+    let stopping = Arc::new(AtomicBool::new(false));
+    let mut reload_projectors = BridgeReloadProjectors::new();
+
+    let mut failed_projectors = Vec::<String>::new();
+
+    // A projector that can't start is logged and skipped, so it doesn't take the others down with it.
+    for projector in projectors {
+        let activated_listener = take_activated_listener(&mut activated_listeners, &projector);
+
+        match start_projector(&projector, state_directory.as_deref(), &events, credentials.as_ref(), activated_listener, &stopping) {
+            Ok((shared_handler, access_policy, projector_handle)) => {
+                handles.push(projector_handle);
 
-    match BridgeDefinition::from_file(cmd_opts.projector_info_path) {
-        Ok(definition) => {
-            let mut options = PjLinkRS232ProjectorOptions::from_def(definition);
-            options.password = password;
-            options.projector_name = Vec::from(cmd_opts.projector_name.as_bytes());
-            options.serial_port = cmd_opts.serial_port;
-            if let Some(baud_rate) = cmd_opts.baud_rate {
-                options.baud_rate = baud_rate;
+                if let Some(idle) = &projector.idle {
+                    BridgeIdle::spawn(projector.name.clone(), idle, schedule.events_for(&projector.name), shared_handler.clone());
+                }
+
+                if let Some(api_listen_address) = &projector.api_listen_address {
+                    BridgeApiServer::spawn(
+                        projector.name.clone(),
+                        api_listen_address.clone(),
+                        projector.api_token.clone(),
+                        shared_handler.clone(),
+                        access_policy,
+                        stopping.clone()
+                    );
+                }
+
+                reload_projectors.push((projector, shared_handler));
+            },
+            Err(message) => {
+                error!("Projector {}: {}", projector.name, message);
+                failed_projectors.push(projector.name.clone());
             }
+        }
+    }
 
-            if let Some(serial_number) = cmd_opts.serial_number {
-                options.serial_number = Vec::from(serial_number.as_bytes());
-            } else {
-                options.serial_number = Vec::from(Uuid::new_v4().to_simple().to_string().as_bytes());
+    for listener in activated_listeners {
+        if let Ok(local_address) = listener.local_addr() {
+            warn!("systemd passed a listening socket on {}, but no projector listens there", local_address);
+        }
+    }
+
+    if handles.is_empty() {
+        exit(1);
+    }
+
+    let shutdown_projectors: BridgeShutdownProjectors = reload_projectors.iter()
+        .filter_map(|(projector, shared_handler)| match shared_handler.lock() {
+            Ok(handler) => Some((projector.name.clone(), shared_handler.clone(), handler.connector_health())),
+            Err(_) => {
+                error!("Projector {}: Handler is unavailable, it won't be shut down cleanly", projector.name);
+                None
             }
+        })
+        .collect();
+
+    let schedule_projectors: BridgeScheduleProjectors = reload_projectors.iter()
+        .map(|(projector, shared_handler)| (projector.name.clone(), shared_handler.clone()))
+        .collect();
+
+    schedule.spawn(schedule_projectors);
+
+    let mode_projectors: BridgeModeProjectors = reload_projectors.iter()
+        .map(|(projector, shared_handler)| (projector.name.clone(), shared_handler.clone()))
+        .collect();
+
+    let systemd_projectors: BridgeSystemdProjectors = reload_projectors.iter()
+        .filter_map(|(projector, shared_handler)| match shared_handler.lock() {
+            Ok(handler) => Some((projector.name.clone(), handler.connector_health())),
+            Err(_) => {
+                error!("Projector {}: Handler is unavailable, the watchdog won't check it", projector.name);
+                None
+            }
+        })
+        .collect();
+
+    if let Some(metrics_listen_address) = metrics_listen_address {
+        let metrics_projectors: BridgeMetricsProjectors = reload_projectors.iter()
+            .filter_map(|(projector, shared_handler)| match shared_handler.lock() {
+                Ok(handler) => Some((projector.name.clone(), handler.metrics(), handler.state())),
+                Err(_) => {
+                    error!("Projector {}: Handler is unavailable, its metrics won't be exported", projector.name);
+                    None
+                }
+            })
+            .collect();
+
+        BridgeMetricsServer::spawn(metrics_listen_address, metrics_projectors);
+    }
+
+    if let Some(mqtt) = config.mqtt {
+        let mqtt_projectors: BridgeMqttProjectors = reload_projectors.iter()
+            .map(|(projector, shared_handler)| (projector.name.clone(), shared_handler.clone()))
+            .collect();
+
+        BridgeMqtt::spawn(mqtt, mqtt_projectors);
+    }
+
+    let config_path = cmd_opts.config.clone();
+    let apply_overrides: Option<BridgeReloadOverrides> = if cmd_opts.has_projector_overrides() {
+        Some(Box::new(move |projector| cmd_opts.apply_projector_overrides(projector)))
+    } else {
+        None
+    };
+    let reload_config = config_path.map(|path| BridgeReloadConfig {path, apply_overrides});
+
+    BridgeMode::spawn(mode_projectors);
+    BridgeReload::spawn(reload_projectors, watch_definitions, credentials, reload_config);
+    BridgeSystemd::spawn_watchdog(systemd_projectors);
+    BridgeSystemd::notify_ready(&failed_projectors);
+
+    exit(BridgeShutdown::wait(shutdown_projectors, handles, stopping));
+}
+
+fn print_audit(audit_opts: &AuditOpts, audit: Option<&BridgeConfigAudit>) -> i32 {
+    let audit = match audit {
+        Some(audit) => audit,
+        None => {
+            error!("No audit log configured, set --audit-file or [audit] in the configuration file");
+            return 1;
+        }
+    };
+
+    let since = match audit_opts.since.as_deref().map(chrono::DateTime::parse_from_rfc3339) {
+        Some(Ok(since)) => Some(since.with_timezone(&chrono::Local)),
+        Some(Err(err)) => {
+            error!("Invalid --since timestamp. {}", err);
+            return 1;
+        }
+        None => None
+    };
+
+    let filter = BridgeAuditFilter {
+        projector: audit_opts.projector.clone(),
+        pjlink_command: audit_opts.command.clone(),
+        since,
+        limit: audit_opts.limit,
+    };
 
-            info!("Projector Manufacturer: {}", String::from_utf8(options.manufacturer_name.clone()).unwrap_or_default());
-            info!("Projector Model: {}", String::from_utf8(options.product_name.clone()).unwrap_or_default());
-            info!("Projector Name: {}", String::from_utf8(options.projector_name.clone()).unwrap_or_default());
-            info!("Projector Serial: {}", String::from_utf8(options.serial_number.clone()).unwrap_or_default());
-            info!(
-                "Serial Port: {}, {} baud, {}{}{}, {}",
-                options.serial_port.clone(),
-                options.baud_rate,
-                options.data_bits,
-                options.parity,
-                options.stop_bits,
-                if options.hardware_flow_control {"Hardware Flow Control"}
-                else if options.software_flow_control {"Software Flow Control"}
-                else {"No Flow Control"}
-            );
-
-            let handler = PjLinkRS232Projector::new(options);
-            let shared_handler = Arc::new(Mutex::new(handler));
-
-            if cmd_opts.udp {
-                let udp_bind_address = cmd_opts.udp_listen_address;
-                let (_, tcp_handle, _) = PjLinkServer::listen_tcp_udp(shared_handler, tcp_bind_address, udp_bind_address, tcp_port);
-
-                tcp_handle.join().unwrap();
-            } else {
-                let (_, tcp_handle) = PjLinkServer::listen_tcp_only(shared_handler, tcp_bind_address, tcp_port);
-                tcp_handle.join().unwrap();
+    match BridgeAudit::query(audit, &filter) {
+        Ok(records) => {
+            for record in records {
+                if audit_opts.json {
+                    println!("{}", serde_json::to_string(&record).unwrap_or_default());
+                } else {
+                    println!(
+                        "{} {} {} ConnectionId: {}, Client: {}, Authenticated: {}, Credential: {}, Command: {}, Tx: {}, Response: {}, Serial: {}",
+                        record.timestamp,
+                        record.projector,
+                        record.source,
+                        record.connection_id,
+                        record.client_address.as_deref().unwrap_or("-"),
+                        record.authenticated,
+                        record.credential.as_deref().unwrap_or("-"),
+                        record.pjlink_command,
+                        record.tx_param,
+                        record.response,
+                        record.serial_tx.join(" ")
+                    );
+                }
             }
+
+            0
         },
-        Err(err) => error!("{}", err.message)
+        Err(err) => {
+            error!("{}", err.message);
+            1
+        }
+    }
+}
+
+/// Socket passed by systemd for the projector listen address and port, if any.
+/// An unspecified listen address (e.g. `0.0.0.0`) matches sockets bound to any address.
+fn take_activated_listener(activated_listeners: &mut Vec<TcpListener>, projector: &BridgeConfigProjector) -> Option<TcpListener> {
+    let listen_address = projector.listen_address.parse::<IpAddr>().ok();
+
+    let position = activated_listeners.iter().position(|listener| match listener.local_addr() {
+        Ok(local_address) => local_address.port() == projector.port
+            && listen_address.is_none_or(|listen_address| listen_address.is_unspecified() || listen_address == local_address.ip()),
+        Err(_) => false
+    })?;
+
+    Some(activated_listeners.remove(position))
+}
+
+/// Handler, access policy (shared with the HTTP API) and PJLink listener thread of a started projector.
+type StartedProjector = (Arc<Mutex<PjLinkRS232Projector>>, Arc<BridgeAccessPolicy>, JoinHandle<()>);
+
+fn start_projector(
+    projector: &BridgeConfigProjector,
+    state_directory: Option<&str>,
+    events: &Arc<BridgeEvents>,
+    credentials: Option<&Arc<BridgeCredentials>>,
+    activated_listener: Option<TcpListener>,
+    stopping: &Arc<AtomicBool>
+) -> Result<StartedProjector, String> {
+    let definition = BridgeDefinition::from_file(projector.definition.clone()).map_err(|err| err.message)?;
+
+    let mut options = PjLinkRS232ProjectorOptions::from_def(definition);
+    options.password = projector.resolve_password().map_err(|err| err.message)?;
+    options.admin_password = projector.resolve_admin_password().map_err(|err| err.message)?;
+    options.projector_name = Vec::from(projector.name.as_bytes());
+    options.serial_port = projector.serial_port.clone();
+    if let Some(baud_rate) = projector.baud_rate {
+        options.baud_rate = baud_rate;
+    }
+
+    let mut state = match state_directory {
+        Some(state_directory) => Some(BridgeState::open(state_directory, projector.state_id()).map_err(|err| err.message)?),
+        None => None
+    };
+
+    if let Some(serial_number) = &projector.serial_number {
+        options.serial_number = Vec::from(serial_number.as_bytes());
+    } else if let Some(state) = &mut state {
+        let serial_number = state.serial_number
+            .get_or_insert_with(|| Uuid::new_v4().to_simple().to_string())
+            .clone();
+
+        if let Err(err) = state.save() {
+            error!("{}", err.message);
+        }
+
+        options.serial_number = Vec::from(serial_number.as_bytes());
+    } else {
+        warn!("No serial number or state directory provided, serial number will change on every start");
+        options.serial_number = Vec::from(Uuid::new_v4().to_simple().to_string().as_bytes());
     }
-}
\ No newline at end of file
+
+    info!("Projector Manufacturer: {}", String::from_utf8(options.manufacturer_name.clone()).unwrap_or_default());
+    info!("Projector Model: {}", String::from_utf8(options.product_name.clone()).unwrap_or_default());
+    info!("Projector Name: {}", String::from_utf8(options.projector_name.clone()).unwrap_or_default());
+    info!("Projector Serial: {}", String::from_utf8(options.serial_number.clone()).unwrap_or_default());
+    info!(
+        "Serial Port: {}, {} baud, {}{}{}, {}",
+        options.serial_port.clone(),
+        options.baud_rate,
+        options.data_bits,
+        options.parity,
+        options.stop_bits,
+        if options.hardware_flow_control {"Hardware Flow Control"}
+        else if options.software_flow_control {"Software Flow Control"}
+        else {"No Flow Control"}
+    );
+
+    let mut handler = PjLinkRS232Projector::new(options, events).map_err(|err| err.message)?;
+
+    if let Some(state) = state {
+        handler.restore_state(state);
+    }
+
+    let projector_credentials = credentials.map(|credentials| credentials.for_projector(projector.name.clone()));
+
+    if projector_credentials.is_some() && credentials.map_or(0, |credentials| credentials.count_for(&projector.name)) == 0 {
+        warn!("Projector {}: No credential applies to this projector, PJLink clients won't be able to authenticate", projector.name);
+    }
+
+    let projector_events = handler.events();
+    let shared_handler = Arc::new(Mutex::new(handler));
+
+    // PJLink clients are served by the access server rather than the pjlink_bridge listener,
+    // so that client addresses are known and more than one password can be checked
+    let listener = match activated_listener {
+        Some(listener) => listener,
+        None => {
+            let listen_address = format!("{}:{}", projector.listen_address, projector.port);
+            TcpListener::bind(&listen_address).map_err(|err| format!("Cannot listen on {}. {}", listen_address, err))?
+        }
+    };
+    let access_policy = Arc::new(
+        BridgeAccessPolicy::from_config(projector.name.clone(), &projector.access.clone().unwrap_or_default()).map_err(|err| err.message)?
+    );
+
+    if projector.udp {
+        if let Err(err) = BridgeSearchServer::spawn(projector.name.clone(), &projector.udp_listen_address, stopping.clone()) {
+            warn!("Projector {}: UDP search isn't available, listening on TCP only. {}", projector.name, err.message);
+        }
+    }
+
+    let tcp_handle = BridgeAccessServer::spawn(
+        projector.name.clone(),
+        listener,
+        access_policy.clone(),
+        projector_credentials,
+        shared_handler.clone(),
+        projector_events,
+        stopping.clone()
+    ).map_err(|err| err.message)?;
+
+    Ok((shared_handler, access_policy, tcp_handle))
+}
@@ -1,34 +1,182 @@
 use std::{
-    process::exit,
-    sync::{mpsc::{Receiver, RecvError, Sender, SyncSender, channel, sync_channel}},
-    thread::{self, JoinHandle},
-    time::Duration
+    convert::TryInto,
+    net::IpAddr,
+    sync::{Arc, Mutex, atomic::{AtomicBool, AtomicU64, Ordering}, mpsc::{Receiver, RecvError, RecvTimeoutError, Sender, SyncSender, channel, sync_channel}},
+    thread,
+    time::{Duration, Instant}
 };
 
-use log::{debug, error, info};
-use pjlink_bridge::{PjLinkCommand, PjLinkHandler, PjLinkRawPayload, PjLinkResponse};
+use log::{debug, error, info, warn};
+use pjlink_bridge::PjLinkResponse;
+use serde::{Deserialize, Serialize};
 
-use crate::rs232_bridge_def::{BridgeDefinition, BridgeDefinitionBehavior, BridgeDefinitionCommand, BridgeDefinitionCommandDefinition, BridgeDefinitionCommandDefinitionOutput, BridgeDefinitionCommandDefinitionOutputProjectorResponse, BridgeDefinitionCommandDefinitionOutputResponse, BridgeDefinitionCommandsMap};
+use crate::rs232_bridge_access::BridgeAccessHandler;
+use crate::rs232_bridge_api::API_CONNECTION_ID;
+use crate::rs232_bridge_config::BridgeConfigProjector;
+use crate::rs232_bridge_events::{BridgeEventKind, BridgeEvents, BridgeProjectorEvents};
+use crate::rs232_bridge_idle::IDLE_CONNECTION_ID;
+use crate::rs232_bridge_metrics::BridgeMetrics;
+use crate::rs232_bridge_mqtt::MQTT_CONNECTION_ID;
+use crate::rs232_bridge_schedule::SCHEDULE_CONNECTION_ID;
+use crate::rs232_bridge_state::BridgeState;
+use crate::rs232_bridge_def::{BridgeDefinition, BridgeDefinitionBehavior, BridgeDefinitionCommand, BridgeDefinitionCommandDefinition, BridgeDefinitionCommandDefinitionOutput, BridgeDefinitionCommandDefinitionOutputProjectorResponse, BridgeDefinitionCommandDefinitionOutputReceivedFormat, BridgeDefinitionCommandDefinitionOutputResponse, BridgeDefinitionCommandsMap, BridgeDefinitionReplyChecksum, BridgeDefinitionRetry, BridgeDefinitionRetryOutcome, BridgeDefinitionStaleInput, BridgeDefinitionUnsolicited, BridgeDefinitionWake};
 
-#[derive(Clone)]
+/// Restricts which commands are handled. Switched at runtime and persisted in the state directory.
+#[derive(Clone, Copy, PartialEq, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PjLinkRS232ProjectorMode {
+    #[default]
+    Normal,
+    /// Queries are answered, set commands get ERR3
+    ReadOnly,
+    /// Every command gets ERR3, except the ones sent with the admin password. Only entered when one is set
+    Maintenance
+}
+
+/// Credentials a command was sent with, as verified by its originator.
+#[derive(Clone, Copy, PartialEq)]
+pub enum PjLinkRS232Credentials {
+    None,
+    User,
+    Admin
+}
+
+/// Originator of a command, with the credentials it was sent with.
+pub struct PjLinkRS232Client {
+    pub connection_id: u64,
+    /// Address of PJLink and HTTP API clients, unknown for commands originated by the bridge
+    pub address: Option<IpAddr>,
+    pub credentials: PjLinkRS232Credentials,
+    /// Name of the credential from the credentials file, for PJLink clients that authenticated with one
+    pub credential: Option<String>,
+}
+
+/// Usage counter reset through `reset_usage`.
+#[derive(Clone, Copy, PartialEq, Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PjLinkRS232UsageCounter {
+    Lamp,
+    Filter
+}
+
+/// Powered-on time counted by the bridge, from tracked POWR state.
+/// Answers LAMP and FILT queries for projectors that can't report them.
+/// Shared with the connector thread, which accounts for power changes reported by unsolicited frames.
+struct PjLinkRS232Usage {
+    lamp: Duration,
+    filter: Duration,
+    accounted_at: Instant,
+    saved_at: Instant,
+    /// Whether the power state changed since the counters were last saved
+    power_changed: bool,
+}
+
+impl PjLinkRS232Usage {
+    fn new() -> Self {
+        PjLinkRS232Usage {
+            lamp: Duration::default(),
+            filter: Duration::default(),
+            accounted_at: Instant::now(),
+            saved_at: Instant::now(),
+            power_changed: false,
+        }
+    }
+
+    /// Adds the time elapsed since the last call to the counters, when the projector was on meanwhile.
+    fn account(&mut self, powered_on: bool) {
+        let now = Instant::now();
+
+        if powered_on {
+            let elapsed = now.duration_since(self.accounted_at);
+            self.lamp += elapsed;
+            self.filter += elapsed;
+        }
+
+        self.accounted_at = now;
+    }
+
+    /// Updates the state (see `PjLinkRS232ProjectorState::update`), accounting for the time
+    /// spent in the previous power state when it changes.
+    fn update_state(
+        usage: &Mutex<PjLinkRS232Usage>,
+        state: &Mutex<PjLinkRS232ProjectorState>,
+        request_body: &[u8; 5],
+        request_parameter: &[u8],
+        response: &PjLinkResponse
+    ) -> Option<Vec<u8>> {
+        // Always locked in this order, the usage first
+        let mut usage = usage.lock().ok()?;
+        let mut state = state.lock().ok()?;
+
+        let was_powered_on = state.power_on == b'1';
+        let previous_power_on = state.power_on;
+        let changed_value = state.update(request_body, request_parameter, response);
+
+        if state.power_on != previous_power_on {
+            usage.account(was_powered_on);
+            usage.power_changed = true;
+        }
+
+        changed_value
+    }
+}
+
+/// Last known projector state, as seen in command responses. Unknown values are zeroed.
+#[derive(Clone, Default, PartialEq)]
 pub struct PjLinkRS232ProjectorState {
-    power_on: u8,
-    error_fan_status: u8,
-    error_lamp_status: u8,
-    error_temperature_status: u8,
-    error_cover_open_status: u8,
-    error_filter_status: u8,
-    error_other_status: u8,
-    lamp_hours: Vec<u8>,
-    filter_hours: Vec<u8>,
-    mute_status: [u8; 2],
-    input_status: [u8; 2],
-    available_inputs: Vec<u8>,
-    freeze_status: u8,
+    pub power_on: u8,
+    pub error_fan_status: u8,
+    pub error_lamp_status: u8,
+    pub error_temperature_status: u8,
+    pub error_cover_open_status: u8,
+    pub error_filter_status: u8,
+    pub error_other_status: u8,
+    pub lamp_hours: Vec<u8>,
+    pub filter_hours: Vec<u8>,
+    pub mute_status: [u8; 2],
+    pub input_status: [u8; 2],
+    pub available_inputs: Vec<u8>,
+    pub freeze_status: u8,
+}
+
+impl PjLinkRS232ProjectorState {
+    /// Updates the state from a command response: query values are stored as they are,
+    /// set commands store their transmission parameter once the projector accepted them.
+    /// Returns the stored value when the state has changed.
+    pub fn update(&mut self, request_body: &[u8; 5], request_parameter: &[u8], response: &PjLinkResponse) -> Option<Vec<u8>> {
+        let value: &[u8] = match response {
+            PjLinkResponse::Ok if request_parameter != b"?" => request_parameter,
+            PjLinkResponse::Single(value) if request_parameter == b"?" => std::slice::from_ref(value),
+            PjLinkResponse::Multiple(value) if request_parameter == b"?" => value,
+            _ => return None
+        };
+        let previous_state = self.clone();
+
+        match (&request_body[1..], value) {
+            (b"POWR", [power_on]) => self.power_on = *power_on,
+            (b"INPT", [input_type, input_number]) => self.input_status = [*input_type, *input_number],
+            (b"AVMT", [mute_type, mute_on]) => self.mute_status = [*mute_type, *mute_on],
+            (b"FREZ", [freeze_status]) => self.freeze_status = *freeze_status,
+            (b"LAMP", _) => self.lamp_hours = value.split(|byte| *byte == b' ').next().map(Vec::from).unwrap_or_default(),
+            (b"FILT", _) => self.filter_hours = Vec::from(value),
+            (b"ERST", [fan, lamp, temperature, cover_open, filter, other]) => {
+                self.error_fan_status = *fan;
+                self.error_lamp_status = *lamp;
+                self.error_temperature_status = *temperature;
+                self.error_cover_open_status = *cover_open;
+                self.error_filter_status = *filter;
+                self.error_other_status = *other;
+            },
+            _ => {}
+        }
+
+        if *self != previous_state {Some(Vec::from(value))} else {None}
+    }
 }
 
 pub struct PjLinkRS232ProjectorOptions {
     pub password: Option<String>,
+    pub admin_password: Option<String>,
     pub class_type: u8,
     pub manufacturer_name: Vec<u8>,
     pub product_name: Vec<u8>,
@@ -37,8 +185,11 @@ pub struct PjLinkRS232ProjectorOptions {
     pub software_version: Vec<u8>,
     pub screen_resolution: Vec<u8>,
     pub recommended_screen_resolution: Vec<u8>,
+    pub lamp_replacement_model: Vec<u8>,
+    pub filter_replacement_model: Vec<u8>,
     pub commands: BridgeDefinitionCommandsMap,
     pub behavior: BridgeDefinitionBehavior,
+    pub unsolicited: Arc<Vec<BridgeDefinitionUnsolicited>>,
     pub serial_port: String,
     pub baud_rate: u32,
     pub data_bits: u8,
@@ -46,9 +197,35 @@ pub struct PjLinkRS232ProjectorOptions {
     pub stop_bits: u8,
     pub hardware_flow_control: bool,
     pub software_flow_control: bool,
+    pub command_gap: u32,
+    pub byte_delay: u32,
 }
 
 impl PjLinkRS232ProjectorOptions {
+    #[inline(always)]
+    fn connector_options(&self) -> PjLinkRS232ConnectorOptions {
+        PjLinkRS232ConnectorOptions {
+            serial_port: self.serial_port.clone(),
+            baud_rate: self.baud_rate,
+            data_bits: self.data_bits,
+            parity: self.parity,
+            stop_bits: self.stop_bits,
+            hardware_flow_control: self.hardware_flow_control,
+            software_flow_control: self.software_flow_control,
+        }
+    }
+
+    #[inline(always)]
+    fn connector_behavior(&self) -> PjLinkRS232ConnectorBehavior {
+        PjLinkRS232ConnectorBehavior {
+            unsolicited: self.unsolicited.clone(),
+            stale_input: self.behavior.stale_input,
+            wake: self.behavior.wake.clone(),
+            command_gap: Duration::from_millis(self.command_gap as u64),
+            byte_delay: Duration::from_millis(self.byte_delay as u64),
+        }
+    }
+
     pub fn from_def(def: BridgeDefinition) -> PjLinkRS232ProjectorOptions {
         let manufacturer_name = Vec::from(def.general.manufacturer_name.as_bytes());
         let product_name = Vec::from(def.general.product_name.as_bytes());
@@ -74,14 +251,23 @@ impl PjLinkRS232ProjectorOptions {
             software_version,
             recommended_screen_resolution: resolution_recommended,
             screen_resolution: resolution_current,
+            lamp_replacement_model: def.general.lamp_replacement_model.map(String::into_bytes).unwrap_or_default(),
+            filter_replacement_model: def.general.filter_replacement_model.map(String::into_bytes).unwrap_or_default(),
             password: Option::None,
+            admin_password: Option::None,
             projector_name: Vec::<u8>::new(),
             serial_number: Vec::<u8>::new(),
             commands: def.commands,
             behavior: def.behavior.unwrap_or(BridgeDefinitionBehavior {
                 send_on_start: None,
-                wait_for_response: Some(50)
+                send_on_shutdown: None,
+                wait_for_response: Some(50),
+                stale_input: BridgeDefinitionStaleInput::Discard,
+                retry: None,
+                reply_checksum: None,
+                wake: None
             }),
+            unsolicited: Arc::new(def.unsolicited),
             serial_port: String::from(""),
             baud_rate: def.connection.baud_rate,
             data_bits: def.connection.data_bits.unwrap_or(8),
@@ -89,6 +275,8 @@ impl PjLinkRS232ProjectorOptions {
             stop_bits: def.connection.stop_bits.unwrap_or(1),
             hardware_flow_control: def.connection.hardware_flow_control.unwrap_or(false),
             software_flow_control: def.connection.software_flow_control.unwrap_or(false),
+            command_gap: def.connection.command_gap.unwrap_or_default(),
+            byte_delay: def.connection.byte_delay.unwrap_or_default(),
         }
     }
 }
@@ -107,9 +295,90 @@ struct PjLinkRS232MessageResponse {
 struct PjLinkRS232Connector {
 }
 
+pub struct Error {
+    pub message: String
+}
+
+/// Connection id used in logs for requests originated by the bridge itself.
+const BRIDGE_CONNECTION_ID: u64 = u64::MAX;
+
+/// How often usage counters are saved to the state file while the projector is on.
+const USAGE_SAVE_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
 /// Minimum timeout allowed (in ms).
 const CONNECTOR_THREAD_MIN_TIMEOUT: u32 = 50;
 
+/// Time (in ms) a single serial exchange may take before the connector thread is considered hung.
+const CONNECTOR_THREAD_HUNG_TIMEOUT: u64 = 30000;
+
+/// How often the connector thread checks for the first reply bytes while waiting for a reply.
+const CONNECTOR_THREAD_REPLY_POLL_INTERVAL: Duration = Duration::from_millis(2);
+
+/// How often the connector thread reads unsolicited frames while no command is being sent.
+const CONNECTOR_THREAD_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Time given to a frame to arrive entirely once its first bytes are available.
+const CONNECTOR_THREAD_FRAME_SETTLE_TIME: Duration = Duration::from_millis(20);
+
+/// Liveness of the connector thread, readable without locking the projector handler.
+/// Also lets the connector thread be stopped while the handler is stuck in a serial exchange.
+pub struct PjLinkRS232ConnectorHealth {
+    started_at: Instant,
+    /// Time (in ms since `started_at`, plus one) when the exchange in progress began, 0 when idle.
+    busy_since: AtomicU64,
+    stopped: AtomicBool,
+    stop_requested: AtomicBool,
+}
+
+impl PjLinkRS232ConnectorHealth {
+    fn new() -> Self {
+        PjLinkRS232ConnectorHealth {
+            started_at: Instant::now(),
+            busy_since: AtomicU64::new(0),
+            stopped: AtomicBool::new(false),
+            stop_requested: AtomicBool::new(false),
+        }
+    }
+
+    /// Makes the connector thread close the serial port once the exchange in progress (if any) is over.
+    pub fn request_stop(&self) {
+        self.stop_requested.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::Relaxed)
+    }
+
+    /// Whether the connector thread is running and not stuck in a serial exchange.
+    pub fn is_healthy(&self) -> bool {
+        let busy_since = self.busy_since.load(Ordering::Relaxed);
+
+        !self.stopped.load(Ordering::Relaxed)
+            && (busy_since == 0 || self.elapsed() - busy_since < CONNECTOR_THREAD_HUNG_TIMEOUT)
+    }
+
+    #[inline(always)]
+    fn elapsed(&self) -> u64 {
+        self.started_at.elapsed().as_millis() as u64 + 1
+    }
+
+    #[inline(always)]
+    fn set_busy(&self, busy: bool) {
+        self.busy_since.store(if busy {self.elapsed()} else {0}, Ordering::Relaxed);
+    }
+}
+
+/// Definition settings the connector thread follows, replaced on definition reload without re-opening the serial port.
+#[derive(Clone, Default)]
+struct PjLinkRS232ConnectorBehavior {
+    unsolicited: Arc<Vec<BridgeDefinitionUnsolicited>>,
+    stale_input: BridgeDefinitionStaleInput,
+    wake: Option<BridgeDefinitionWake>,
+    command_gap: Duration,
+    byte_delay: Duration,
+}
+
+#[derive(Clone, PartialEq)]
 struct PjLinkRS232ConnectorOptions {
     serial_port: String,
     baud_rate: u32,
@@ -121,87 +390,371 @@ struct PjLinkRS232ConnectorOptions {
 }
 
 impl PjLinkRS232Connector {
+    fn open(
+        options: PjLinkRS232ConnectorOptions
+    ) -> Result<Box<dyn serialport::SerialPort>, Error> {
+        let serial_port = options.serial_port.clone();
+
+        Self::build_connection(options)?
+            .open()
+            .map_err(|err| Error {message: format!("Cannot start serial communication on {}! {}", serial_port, err)})
+    }
+
+    // Takes everything the connector thread shares with the handler
+    #[allow(clippy::too_many_arguments)]
     fn spawn(
-        options: PjLinkRS232ConnectorOptions,
-        transmission: (Sender<PjLinkRS232MessageResponse>, Receiver<PjLinkRS232MessageRequest>)
+        mut serial_conn_box: Box<dyn serialport::SerialPort>,
+        transmission: (Sender<PjLinkRS232MessageResponse>, Receiver<PjLinkRS232MessageRequest>),
+        health: Arc<PjLinkRS232ConnectorHealth>,
+        metrics: Arc<BridgeMetrics>,
+        events: Arc<BridgeProjectorEvents>,
+        state: Arc<Mutex<PjLinkRS232ProjectorState>>,
+        usage: Arc<Mutex<PjLinkRS232Usage>>,
+        behavior: Arc<Mutex<PjLinkRS232ConnectorBehavior>>
     ) {
         let (tx, rx) = transmission;
+        health.stopped.store(false, Ordering::Relaxed);
+        let serial_conn = serial_conn_box.as_mut(); 
+        let serial_port = serial_conn.name().unwrap_or_default();
+        let mut stopped = false;
+        let mut last_exchange = Instant::now();
+        let mut last_reply_at: Option<Instant> = None;
 
-        match Self::build_connection(options)
-            .open() 
-        {
-            Ok(mut serial_conn_box) => {
-                let serial_conn = serial_conn_box.as_mut(); 
-                while let Ok(message) = rx.recv() {
-                    let timeout = Duration::from_millis((
-                        if message.timeout >= CONNECTOR_THREAD_MIN_TIMEOUT {message.timeout}
-                        else {CONNECTOR_THREAD_MIN_TIMEOUT}
-                    ) as u64);
-                    let message_buffer = message.message;
-
-                    if let Err(err) = serial_conn.write_all(&message_buffer[0..message_buffer.len()]) {
-                        error!("Error when writing to serial connection. {}", err);
-                    }
+        loop {
+            if health.stop_requested.load(Ordering::Relaxed) {
+                break;
+            }
 
-                    if let Err(err) = serial_conn.set_timeout(timeout) {
-                        error!("Error when defining serial timeout. {}", err);
-                    }
+            let connector_behavior = behavior.lock().map(|behavior| behavior.clone()).unwrap_or_default();
+            let unsolicited = connector_behavior.unsolicited;
+
+            let message = match rx.recv_timeout(CONNECTOR_THREAD_POLL_INTERVAL) {
+                Ok(message) => message,
+                Err(RecvTimeoutError::Timeout) => {
+                    // Frames sent by the projector on its own are only read when the definition describes them
+                    if !unsolicited.is_empty() {
+                        let mut buffer = Self::read_pending(serial_conn, &serial_port, &metrics, &events);
 
-                    thread::sleep(timeout);
-                    let buffer_size = serial_conn.bytes_to_read().unwrap_or_default() as usize;
+                        if !buffer.is_empty() {
+                            last_exchange = Instant::now();
+                        }
 
-                    let mut buffer = vec! [0;1];
-                    buffer.resize(buffer_size, 0);
+                        Self::handle_unsolicited(&mut buffer, &unsolicited, &state, &usage, &events);
 
-                    if let Err(err) = serial_conn.read(buffer.as_mut_slice()) {
-                        error!("Error when reading from serial connection. {}", err);
+                        if !buffer.is_empty() {
+                            debug!("Discarded unexpected input from {}: {:02x?}", serial_port, buffer);
+                        }
                     }
-                    
-                    tx.send(PjLinkRS232MessageResponse {
-                        response: buffer,
-                        elapsed_time: 0
-                    }).unwrap_or_default();
+
+                    continue;
+                },
+                Err(RecvTimeoutError::Disconnected) => break
+            };
+
+            if message.stop_processing {
+                stopped = true;
+                break;
+            }
+
+            health.set_busy(true);
+
+            let timeout = Duration::from_millis((
+                if message.timeout >= CONNECTOR_THREAD_MIN_TIMEOUT {message.timeout}
+                else {CONNECTOR_THREAD_MIN_TIMEOUT}
+            ) as u64);
+            let message_buffer = message.message;
+
+            // Every caller goes through this thread, so the gap holds whoever sent the previous command
+            if let Some(last_reply_at) = last_reply_at {
+                if let Some(remaining_gap) = connector_behavior.command_gap.checked_sub(last_reply_at.elapsed()) {
+                    thread::sleep(remaining_gap);
+                }
+            }
+
+            // Late replies to earlier commands (or noise) would otherwise be read as part of this reply
+            let mut pending_input = Self::read_pending(serial_conn, &serial_port, &metrics, &events);
+
+            if !pending_input.is_empty() {
+                metrics.record_stale_input();
+                warn!("Flushing stale input from {}: {:02x?}", serial_port, pending_input);
+
+                if connector_behavior.stale_input == BridgeDefinitionStaleInput::Unsolicited {
+                    Self::handle_unsolicited(&mut pending_input, &unsolicited, &state, &usage, &events);
+                }
+            }
+
+            if let Some(wake) = &connector_behavior.wake {
+                let idle = wake.idle_after.is_some_and(|idle_after| last_exchange.elapsed() >= Duration::from_secs(idle_after));
+                let standby = wake.on_standby && state.lock().map(|state| state.power_on == b'0').unwrap_or_default();
+
+                if idle || standby {
+                    Self::wake(serial_conn, &serial_port, wake, connector_behavior.byte_delay, &metrics, &events);
+                }
+            }
+
+            let started_at = Instant::now();
+
+            if let Err(err) = Self::write(serial_conn, &message_buffer[0..message_buffer.len()], connector_behavior.byte_delay) {
+                events.publish(BridgeEventKind::ConnectorError {message: format!("Error when writing to serial connection {}. {}", serial_port, err)});
+                metrics.record_serial_error();
+            }
+
+            if let Err(err) = serial_conn.set_timeout(timeout) {
+                events.publish(BridgeEventKind::ConnectorError {message: format!("Error when defining serial timeout on {}. {}", serial_port, err)});
+                metrics.record_serial_error();
+            }
+
+            let first_byte_after = Self::wait_for_reply(serial_conn, Instant::now(), timeout);
+            let buffer_size = serial_conn.bytes_to_read().unwrap_or_default() as usize;
+
+            let mut buffer = vec! [0;1];
+            buffer.resize(buffer_size, 0);
+
+            if let Err(err) = serial_conn.read(buffer.as_mut_slice()) {
+                events.publish(BridgeEventKind::ConnectorError {message: format!("Error when reading from serial connection {}. {}", serial_port, err)});
+                metrics.record_serial_error();
+            }
+
+            // Unsolicited frames may arrive before or after the reply, they must not end up in it
+            Self::handle_unsolicited(&mut buffer, &unsolicited, &state, &usage, &events);
+
+            health.set_busy(false);
+            last_exchange = Instant::now();
+            last_reply_at = Some(last_exchange);
+
+            let elapsed_time = started_at.elapsed().as_millis() as u32;
+
+            // The reply is read once the whole timeout is over, the projector latency is up to its first byte
+            if let Some(first_byte_after) = first_byte_after {
+                metrics.record_serial_latency(first_byte_after.as_millis() as u32);
+            }
+
+            tx.send(PjLinkRS232MessageResponse {
+                response: buffer,
+                elapsed_time
+            }).unwrap_or_default();
+        }
+
+        // Port must be closed before acknowledging, as it may be re-opened right after
+        drop(serial_conn_box);
+        health.stopped.store(true, Ordering::Relaxed);
+
+        if stopped {
+            tx.send(PjLinkRS232MessageResponse {
+                response: vec! [],
+                elapsed_time: 0
+            }).unwrap_or_default();
+        }
+    }
+
+    /// Waits until `timeout` has elapsed since `written_at`, returning how long after `written_at` the first reply bytes arrived (if any did).
+    fn wait_for_reply(serial_conn: &mut dyn serialport::SerialPort, written_at: Instant, timeout: Duration) -> Option<Duration> {
+        let mut first_byte_after = None;
+
+        while let Some(remaining) = timeout.checked_sub(written_at.elapsed()) {
+            if first_byte_after.is_none() && serial_conn.bytes_to_read().unwrap_or_default() > 0 {
+                first_byte_after = Some(written_at.elapsed());
+            }
+
+            thread::sleep(if first_byte_after.is_some() {remaining} else {remaining.min(CONNECTOR_THREAD_REPLY_POLL_INTERVAL)});
+        }
+
+        first_byte_after
+    }
+
+    /// Sends the wake preamble: a serial BREAK, then the wake bytes, then waits for the projector to listen.
+    fn wake(
+        serial_conn: &mut dyn serialport::SerialPort,
+        serial_port: &str,
+        wake: &BridgeDefinitionWake,
+        byte_delay: Duration,
+        metrics: &BridgeMetrics,
+        events: &BridgeProjectorEvents
+    ) {
+        debug!("Waking up projector on {}", serial_port);
+
+        if let Some(break_duration) = wake.break_duration {
+            let result = serial_conn.set_break().and_then(|_| {
+                thread::sleep(Duration::from_millis(break_duration as u64));
+                serial_conn.clear_break()
+            });
+
+            if let Err(err) = result {
+                events.publish(BridgeEventKind::ConnectorError {message: format!("Error when sending serial break on {}. {}", serial_port, err)});
+                metrics.record_serial_error();
+            }
+        }
+
+        if !wake.send.is_empty() {
+            if let Err(err) = Self::write(serial_conn, &wake.send, byte_delay) {
+                events.publish(BridgeEventKind::ConnectorError {message: format!("Error when writing to serial connection {}. {}", serial_port, err)});
+                metrics.record_serial_error();
+            }
+        }
+
+        thread::sleep(Duration::from_millis(wake.delay as u64));
+
+        // Whatever the projector answers to the preamble isn't part of the command reply
+        let wake_reply = Self::read_pending(serial_conn, serial_port, metrics, events);
+
+        if !wake_reply.is_empty() {
+            debug!("Discarded wake-up reply from {}: {:02x?}", serial_port, wake_reply);
+        }
+    }
+
+    /// Writes `bytes`, one at a time when they must be paced by `byte_delay`.
+    fn write(serial_conn: &mut dyn serialport::SerialPort, bytes: &[u8], byte_delay: Duration) -> std::io::Result<()> {
+        if byte_delay.is_zero() {
+            return serial_conn.write_all(bytes);
+        }
+
+        for byte in bytes {
+            serial_conn.write_all(std::slice::from_ref(byte))?;
+            serial_conn.flush()?;
+            thread::sleep(byte_delay);
+        }
+
+        Ok(())
+    }
+
+    /// Reads every byte available, waiting a little after each read so frames being received are read whole.
+    fn read_pending(
+        serial_conn: &mut dyn serialport::SerialPort,
+        serial_port: &str,
+        metrics: &BridgeMetrics,
+        events: &BridgeProjectorEvents
+    ) -> Vec<u8> {
+        let mut buffer = vec! [];
+
+        loop {
+            let buffer_size = serial_conn.bytes_to_read().unwrap_or_default() as usize;
+
+            if buffer_size == 0 {
+                return buffer;
+            }
+
+            let mut read_buffer = vec! [0; buffer_size];
+
+            match serial_conn.read(read_buffer.as_mut_slice()) {
+                Ok(read_size) => buffer.extend_from_slice(&read_buffer[..read_size]),
+                Err(err) => {
+                    events.publish(BridgeEventKind::ConnectorError {message: format!("Error when reading from serial connection {}. {}", serial_port, err)});
+                    metrics.record_serial_error();
+                    return buffer;
                 }
             }
-            Err(e) => {
-                error!("Cannot start serial communication! {}", e);
-                exit(1)
+
+            thread::sleep(CONNECTOR_THREAD_FRAME_SETTLE_TIME);
+        }
+    }
+
+    /// Removes the unsolicited frames found in `buffer`, updating the state and notifying each of them.
+    fn handle_unsolicited(
+        buffer: &mut Vec<u8>,
+        unsolicited: &[BridgeDefinitionUnsolicited],
+        state: &Mutex<PjLinkRS232ProjectorState>,
+        usage: &Mutex<PjLinkRS232Usage>,
+        events: &BridgeProjectorEvents
+    ) {
+        let mut position = 0;
+
+        while position < buffer.len() && !unsolicited.is_empty() {
+            let found = unsolicited.iter().find_map(|definition| {
+                let remaining = &buffer[position..];
+
+                match &definition.output.on_received {
+                    BridgeDefinitionCommandDefinitionOutputProjectorResponse::Value(value) if !value.is_empty() && remaining.starts_with(value) =>
+                        Some((definition, value.len(), value.len())),
+                    BridgeDefinitionCommandDefinitionOutputProjectorResponse::Prefix(prefix) if !prefix.is_empty() && remaining.starts_with(prefix) =>
+                        Some((definition, prefix.len(), remaining.len().min(prefix.len() + definition.length))),
+                    _ => None
+                }
+            });
+
+            match found {
+                Some((definition, matched_length, frame_length)) => {
+                    let frame: Vec<u8> = buffer.drain(position..position + frame_length).collect();
+                    Self::handle_unsolicited_frame(&frame, &frame[matched_length..], definition, state, usage, events);
+                },
+                None => position += 1
             }
         }
     }
 
+    fn handle_unsolicited_frame(
+        frame: &[u8],
+        received_value: &[u8],
+        definition: &BridgeDefinitionUnsolicited,
+        state: &Mutex<PjLinkRS232ProjectorState>,
+        usage: &Mutex<PjLinkRS232Usage>,
+        events: &BridgeProjectorEvents
+    ) {
+        let response = match &definition.output.response {
+            BridgeDefinitionCommandDefinitionOutputResponse::Value(response_value)
+            | BridgeDefinitionCommandDefinitionOutputResponse::Default(response_value) => response_value.clone().into(),
+            BridgeDefinitionCommandDefinitionOutputResponse::Received(received_format) =>
+                match PjLinkRS232Projector::format_received_value(received_value, received_format) {
+                    Some(value) => PjLinkResponse::Multiple(value),
+                    None => {
+                        debug!("Unsolicited frame is too short for its format: {:02x?}", frame);
+                        return;
+                    }
+                }
+        };
+
+        events.publish(BridgeEventKind::Unsolicited {
+            serial_rx: BridgeProjectorEvents::hex(frame),
+            pjlink_command: definition.pjlink_command.clone(),
+            value: PjLinkRS232Projector::response_text(&response)
+        });
+
+        let pjlink_command = match &definition.pjlink_command {
+            Some(pjlink_command) => pjlink_command,
+            None => return
+        };
+
+        let request_body: [u8; 5] = match pjlink_command.as_bytes().try_into() {
+            Ok(request_body) => request_body,
+            Err(_) => {
+                debug!("Unsolicited frame maps to invalid command {}, expected a command with class, e.g. 1POWR", pjlink_command);
+                return;
+            }
+        };
+
+        // Reported values are stored as if the projector had been queried
+        let changed_value = PjLinkRS232Usage::update_state(usage, state, &request_body, b"?", &response);
+
+        if let Some(value) = changed_value {
+            events.publish(BridgeEventKind::StateChanged {
+                pjlink_command: pjlink_command.clone(),
+                value: String::from_utf8_lossy(&value).into_owned()
+            });
+        }
+    }
+
     #[inline(always)]
     fn build_connection(
         options: PjLinkRS232ConnectorOptions
-    ) -> serialport::SerialPortBuilder {
+    ) -> Result<serialport::SerialPortBuilder, Error> {
         let serial_data_bits = match options.data_bits {
             8 => serialport::DataBits::Eight,
             7 => serialport::DataBits::Seven,
             6 => serialport::DataBits::Six,
             5 => serialport::DataBits::Five,
-            _ => {
-                error!("Unsupported serial data bits: {}", options.data_bits);
-                exit(1)
-            } 
+            _ => return Err(Error {message: format!("Unsupported serial data bits: {}", options.data_bits)})
         };
 
         let serial_parity = match options.parity {
             'N' => serialport::Parity::None,
             'E' => serialport::Parity::Even,
             'O' => serialport::Parity::Odd,
-            _ => {
-                error!("Unsupported serial parity: {}", options.parity);
-                exit(1)
-            } 
+            _ => return Err(Error {message: format!("Unsupported serial parity: {}", options.parity)})
         };
 
         let serial_stop_bits = match options.stop_bits {
             1 => serialport::StopBits::One,
             2 => serialport::StopBits::Two,
-            _ => {
-                error!("Unsupported serial stop bits: {}", options.stop_bits);
-                exit(1)
-            } 
+            _ => return Err(Error {message: format!("Unsupported serial stop bits: {}", options.stop_bits)})
         };
 
         let serial_flow_control
@@ -209,11 +762,11 @@ impl PjLinkRS232Connector {
             else if options.software_flow_control {serialport::FlowControl::Software}
             else {serialport::FlowControl::None};
 
-        serialport::new(options.serial_port, options.baud_rate)
+        Ok(serialport::new(options.serial_port, options.baud_rate)
             .parity(serial_parity)
             .data_bits(serial_data_bits)
             .stop_bits(serial_stop_bits)
-            .flow_control(serial_flow_control)
+            .flow_control(serial_flow_control))
     }
 }
 
@@ -221,62 +774,414 @@ pub struct PjLinkRS232Projector {
     options: PjLinkRS232ProjectorOptions,
     tx: SyncSender<PjLinkRS232MessageRequest>,
     rx: Receiver<PjLinkRS232MessageResponse>,
-//    state: PjLinkRS232ProjectorState
+    shutting_down: bool,
+    health: Arc<PjLinkRS232ConnectorHealth>,
+    metrics: Arc<BridgeMetrics>,
+    state: Arc<Mutex<PjLinkRS232ProjectorState>>,
+    connector_behavior: Arc<Mutex<PjLinkRS232ConnectorBehavior>>,
+    events: Arc<BridgeProjectorEvents>,
+    mode: PjLinkRS232ProjectorMode,
+    state_file: Option<BridgeState>,
+    /// Last set command, from any originator but the idle policy
+    last_activity: Instant,
+    usage: Arc<Mutex<PjLinkRS232Usage>>,
 }
 
 impl PjLinkRS232Projector {
-    pub fn new(options: PjLinkRS232ProjectorOptions) -> Self {
+    /// Opens the serial port and sends the definition `send_on_start` sequence (if any).
+    pub fn new(options: PjLinkRS232ProjectorOptions, events: &Arc<BridgeEvents>) -> Result<Self, Error> {
+        let health = Arc::new(PjLinkRS232ConnectorHealth::new());
+        let metrics = Arc::new(BridgeMetrics::default());
+        let events = Arc::new(events.for_projector(String::from_utf8_lossy(&options.projector_name).into_owned()));
+        let state = Arc::new(Mutex::new(PjLinkRS232ProjectorState::default()));
+        let usage = Arc::new(Mutex::new(PjLinkRS232Usage::new()));
+        let connector_behavior = Arc::new(Mutex::new(options.connector_behavior()));
         let (tx, rx ) = Self::open_rs232_connector(
-            options.serial_port.clone(),
-            options.baud_rate,
-            options.data_bits,
-            options.parity,
-            options.stop_bits,
-            options.hardware_flow_control,
-            options.software_flow_control
-        );
+            options.connector_options(),
+            health.clone(),
+            metrics.clone(),
+            events.clone(),
+            state.clone(),
+            usage.clone(),
+            connector_behavior.clone()
+        )?;
 
-        PjLinkRS232Projector {
+        let projector = PjLinkRS232Projector {
             options,
             tx,
             rx,
+            shutting_down: false,
+            health,
+            metrics,
+            state,
+            connector_behavior,
+            events,
+            mode: PjLinkRS232ProjectorMode::Normal,
+            state_file: None,
+            last_activity: Instant::now(),
+            usage,
+        };
+
+        if let Some(send_on_start) = projector.options.behavior.send_on_start.clone() {
+            let timeout = projector.options.behavior.wait_for_response.unwrap_or_default();
+
+            match projector.send_and_receive_message(send_on_start, timeout, &BRIDGE_CONNECTION_ID) {
+                Ok(PjLinkRS232MessageResponse {response, elapsed_time: _}) => debug!(
+                    "Received from projector on start: Response: {:02x?}",
+                    response
+                ),
+                Err(err) => return Err(Error {message: format!("Can't send start sequence to {}! {}", projector.options.serial_port, err)})
+            }
+        }
+
+        Ok(projector)
+    }
+
+    pub fn connector_health(&self) -> Arc<PjLinkRS232ConnectorHealth> {
+        self.health.clone()
+    }
+
+    /// Response as it would be sent to a PJLink client, e.g. `OK`, `ERR2` or the queried value.
+    pub fn response_text(response: &PjLinkResponse) -> String {
+        match response {
+            PjLinkResponse::Ok => String::from("OK"),
+            PjLinkResponse::Undefined => String::from("ERR1"),
+            PjLinkResponse::OutOfParameter => String::from("ERR2"),
+            PjLinkResponse::UnavailableTime => String::from("ERR3"),
+            PjLinkResponse::Single(value) => String::from_utf8_lossy(&[*value]).into_owned(),
+            PjLinkResponse::Multiple(value) => String::from_utf8_lossy(value).into_owned(),
+            _ => String::from("ERR4")
+        }
+    }
+
+    pub fn options(&self) -> &PjLinkRS232ProjectorOptions {
+        &self.options
+    }
+
+    pub fn metrics(&self) -> Arc<BridgeMetrics> {
+        self.metrics.clone()
+    }
+
+    pub fn state(&self) -> Arc<Mutex<PjLinkRS232ProjectorState>> {
+        self.state.clone()
+    }
+
+    pub fn events(&self) -> Arc<BridgeProjectorEvents> {
+        self.events.clone()
+    }
+
+    pub fn last_activity(&self) -> Instant {
+        self.last_activity
+    }
+
+    /// Stops handling commands, sends the definition `send_on_shutdown` sequence (if any) and closes the serial port.
+    /// Commands received afterwards are answered with ERR3.
+    pub fn shutdown(&mut self) -> Result<(), Error> {
+        if self.shutting_down {
+            return Ok(());
+        }
+
+        self.shutting_down = true;
+        self.account_usage();
+        if let Err(err) = self.save_usage() {
+            error!("{}", err.message);
+        }
+        let mut result = Ok(());
+
+        if let Some(send_on_shutdown) = self.options.behavior.send_on_shutdown.clone() {
+            let timeout = self.options.behavior.wait_for_response.unwrap_or_default();
+
+            match self.send_and_receive_message(send_on_shutdown, timeout, &BRIDGE_CONNECTION_ID) {
+                Ok(PjLinkRS232MessageResponse {response, elapsed_time: _}) => debug!(
+                    "Received from projector on shutdown: Response: {:02x?}",
+                    response
+                ),
+                Err(err) => result = Err(Error {message: format!("Can't send shutdown sequence to {}! {}", self.options.serial_port, err)})
+            }
+        }
+
+        self.close_rs232_connector();
+        result
+    }
+
+    /// Applies a reloaded definition along with the serial port, baud rate and serial number of `projector`.
+    /// The serial port is re-opened when the connection settings changed or the connector is down.
+    pub fn reload_definition(&mut self, definition: BridgeDefinition, projector: &BridgeConfigProjector) -> Result<(), Error> {
+        let mut options = PjLinkRS232ProjectorOptions::from_def(definition);
+        options.password = self.options.password.clone();
+        options.admin_password = self.options.admin_password.clone();
+        options.projector_name = self.options.projector_name.clone();
+        options.serial_port = projector.serial_port.clone();
+        // Without a configured serial number the current one is kept, it may come from the state file
+        options.serial_number = match &projector.serial_number {
+            Some(serial_number) => Vec::from(serial_number.as_bytes()),
+            None => self.options.serial_number.clone()
+        };
+        if let Some(baud_rate) = projector.baud_rate {
+            options.baud_rate = baud_rate;
+        }
+
+        let connector_options = options.connector_options();
+        let previous_connector_options = self.options.connector_options();
+
+        if connector_options != previous_connector_options || self.health.is_stopped() {
+            info!("Connection settings changed, re-opening serial port {}", options.serial_port);
+            self.close_rs232_connector();
+            self.metrics.record_reopen();
+
+            match Self::open_rs232_connector(connector_options, self.health.clone(), self.metrics.clone(), self.events.clone(), self.state.clone(), self.usage.clone(), self.connector_behavior.clone()) {
+                Ok((tx, rx)) => {
+                    self.tx = tx;
+                    self.rx = rx;
+                },
+                Err(err) => {
+                    // Keep the projector reachable with the settings that were working
+                    match Self::open_rs232_connector(previous_connector_options, self.health.clone(), self.metrics.clone(), self.events.clone(), self.state.clone(), self.usage.clone(), self.connector_behavior.clone()) {
+                        Ok((tx, rx)) => {
+                            self.tx = tx;
+                            self.rx = rx;
+                            return Err(err);
+                        },
+                        Err(previous_err) => {
+                            // The connector is down: the watchdog reports it, and commands are answered with ERR3
+                            // until a reload opens the serial port again
+                            self.health.stopped.store(true, Ordering::Relaxed);
+                            return Err(Error {
+                                message: format!("{} Cannot re-open the serial port with the previous settings either, the projector is unavailable. {}", err.message, previous_err.message)
+                            });
+                        }
+                    }
+                }
+            }
+        }
+
+        if let Ok(mut connector_behavior) = self.connector_behavior.lock() {
+            *connector_behavior = options.connector_behavior();
+        }
+
+        self.options = options;
+        Ok(())
+    }
+
+    pub fn set_password(&mut self, password: Option<String>) {
+        self.options.password = password;
+    }
+
+    pub fn set_admin_password(&mut self, admin_password: Option<String>) {
+        self.options.admin_password = admin_password;
+    }
+
+    pub fn mode(&self) -> PjLinkRS232ProjectorMode {
+        self.mode
+    }
+
+    /// Restores the mode persisted in `state_file`, which then keeps track of mode changes.
+    pub fn restore_state(&mut self, state_file: BridgeState) {
+        self.mode = state_file.mode.unwrap_or_default();
+
+        if self.mode == PjLinkRS232ProjectorMode::Maintenance && self.options.admin_password.is_none() {
+            warn!("Maintenance mode requires an admin password, starting in normal mode");
+            self.mode = PjLinkRS232ProjectorMode::Normal;
+        }
+
+        if self.mode != PjLinkRS232ProjectorMode::Normal {
+            info!("Restored {:?} mode", self.mode);
+        }
+
+        if let Ok(mut usage) = self.usage.lock() {
+            usage.lamp = Duration::from_secs(state_file.lamp_seconds.unwrap_or_default());
+            usage.filter = Duration::from_secs(state_file.filter_seconds.unwrap_or_default());
+        }
+
+        self.state_file = Some(state_file);
+    }
+
+    /// Switches the mode, persisting it when a state directory is set.
+    pub fn set_mode(&mut self, mode: PjLinkRS232ProjectorMode) -> Result<(), Error> {
+        self.mode = mode;
+
+        match &mut self.state_file {
+            Some(state_file) => {
+                state_file.mode = Some(mode);
+                state_file.save().map_err(|err| Error {message: err.message})
+            },
+            None => Ok(())
+        }
+    }
+
+    /// Lamp and filter hours counted by the bridge.
+    pub fn usage_hours(&mut self) -> (u64, u64) {
+        self.account_usage();
+        self.usage.lock()
+            .map(|usage| (usage.lamp.as_secs() / 3600, usage.filter.as_secs() / 3600))
+            .unwrap_or_default()
+    }
+
+    /// Resets a usage counter after a lamp or filter replacement.
+    pub fn reset_usage(&mut self, counter: PjLinkRS232UsageCounter) -> Result<(), Error> {
+        self.account_usage();
+
+        match self.usage.lock() {
+            Ok(mut usage) => match counter {
+                PjLinkRS232UsageCounter::Lamp => usage.lamp = Duration::default(),
+                PjLinkRS232UsageCounter::Filter => usage.filter = Duration::default()
+            },
+            Err(_) => return Err(Error {message: String::from("Usage counters are unavailable")})
+        }
+
+        info!("{:?} usage counter reset", counter);
+        self.save_usage()
+    }
+
+    /// Adds the time elapsed since the last call to the usage counters, when the projector was last known to be on.
+    /// Counting only resumes from the saved counters after a restart, so they are saved once the projector
+    /// went on or off (here or in the connector thread), and regularly while it's on.
+    fn account_usage(&mut self) {
+        let save = match self.usage.lock() {
+            Ok(mut usage) => {
+                let powered_on = self.state.lock().map(|state| state.power_on == b'1').unwrap_or_default();
+                usage.account(powered_on);
+
+                usage.power_changed || (powered_on && usage.saved_at.elapsed() >= USAGE_SAVE_INTERVAL)
+            },
+            Err(_) => false
+        };
+
+        if save {
+            if let Err(err) = self.save_usage() {
+                error!("{}", err.message);
+            }
+        }
+    }
+
+    fn save_usage(&mut self) -> Result<(), Error> {
+        let (lamp_seconds, filter_seconds) = match self.usage.lock() {
+            Ok(mut usage) => {
+                usage.saved_at = Instant::now();
+                usage.power_changed = false;
+                (usage.lamp.as_secs(), usage.filter.as_secs())
+            },
+            Err(_) => return Err(Error {message: String::from("Usage counters are unavailable")})
+        };
+
+        match &mut self.state_file {
+            Some(state_file) => {
+                state_file.lamp_seconds = Some(lamp_seconds);
+                state_file.filter_seconds = Some(filter_seconds);
+                state_file.save().map_err(|err| Error {message: err.message})
+            },
+            None => Ok(())
+        }
+    }
+
+    /// Queries the definition doesn't map, answered by the bridge: LAMP and FILT from the usage counters,
+    /// RLMP and RFIL from the replacement models in `[general]` (empty when unknown, as PJLink requires).
+    fn bridge_query_response(&mut self, request_body: &[u8; 5], request_parameter: &[u8]) -> Option<PjLinkResponse> {
+        if request_parameter != b"?" || self.has_dynamic_content(request_body, request_parameter) {
+            return None;
+        }
+
+        match &request_body[1..] {
+            b"LAMP" => {
+                let (lamp_hours, _) = self.usage_hours();
+                let lamp_on = self.state.lock().map(|state| state.power_on == b'1').unwrap_or_default();
+                Some(PjLinkResponse::Multiple(format!("{} {}", lamp_hours, if lamp_on {1} else {0}).into_bytes()))
+            },
+            b"FILT" => {
+                let (_, filter_hours) = self.usage_hours();
+                Some(PjLinkResponse::Multiple(filter_hours.to_string().into_bytes()))
+            },
+            b"RLMP" => Some(PjLinkResponse::Multiple(self.options.lamp_replacement_model.clone())),
+            b"RFIL" => Some(PjLinkResponse::Multiple(self.options.filter_replacement_model.clone())),
+            _ => None
         }
     }
 
-    fn handle_dynamic_content(&mut self, _command: PjLinkCommand, raw_command: &PjLinkRawPayload, connection_id: &u64) -> PjLinkResponse {
-        let request_body = raw_command.command_body_with_class;
+    /// Whether the definition maps this command and transmission parameter to a serial request.
+    /// Used by identity queries, which fall back to static values when not mapped.
+    #[inline(always)]
+    pub fn has_dynamic_content(&self, request_body: &[u8; 5], request_parameter: &[u8]) -> bool {
+        self.options.commands.get(request_body)
+            .is_some_and(|command_spec| command_spec.inputs.contains_key(request_parameter))
+    }
+
+    fn handle_dynamic_content(&mut self, request_body: [u8; 5], request_parameter: &[u8], connection_id: &u64) -> PjLinkResponse {
+        if let Some(response) = self.bridge_query_response(&request_body, request_parameter) {
+            return response;
+        }
+
         let command_spec_result = self.options.commands.get(&request_body);
 
         if let Some(command_spec) = command_spec_result {
-            let request_parameter = raw_command.transmission_parameter.clone();
+            let request_parameter = Vec::from(request_parameter);
             if let Some(command_input_definition) = command_spec.inputs.get(&request_parameter) {
                 let timeout = self.get_timeout(&self.options.behavior, command_input_definition, command_spec);
-                let message = command_input_definition.send.clone();
-                let send_times = command_input_definition.send_times.unwrap_or(1);
+                let retry = self.get_retry(&self.options.behavior, command_input_definition, command_spec);
+                // A toggle received by the projector would be undone by its retry
+                let idempotent = request_parameter == b"?"
+                    || command_input_definition.idempotent.or(command_spec.idempotent).unwrap_or(false);
+                let mut retries = 0;
 
-                let recv_message = if send_times > 1 {
-                    let mut result: Result<PjLinkRS232MessageResponse, RecvError> = Ok(PjLinkRS232MessageResponse {response: vec! [], elapsed_time: 0});
+                loop {
+                    let response = match self.send_command_input(command_input_definition, timeout, connection_id) {
+                        Ok(response) => response,
+                        Err(err) => {
+                            self.events.publish(BridgeEventKind::ConnectorError {
+                                message: format!("Can't receive message from connector thread! ConnectionId: {}, {}", *connection_id, err)
+                            });
+                            return PjLinkResponse::UnavailableTime;
+                        }
+                    };
 
-                    for _ in 0..send_times {
-                        result = self.send_and_receive_message(message.clone(), timeout, connection_id);
-                    }
+                    self.events.publish(BridgeEventKind::SerialRx {
+                        connection_id: *connection_id,
+                        serial_rx: BridgeProjectorEvents::hex(&response.response),
+                        elapsed_ms: response.elapsed_time
+                    });
 
-                    result
-                } else {
-                    self.send_and_receive_message(message, timeout, connection_id)
-                };
+                    let failed_outcome = Self::failed_outcome(
+                        &response.response,
+                        &command_input_definition.outputs,
+                        self.options.behavior.reply_checksum
+                    );
+
+                    match (failed_outcome, retry) {
+                        (Some(outcome), Some(retry)) if idempotent
+                            && retries < retry.times
+                            && retry.on.as_ref().is_none_or(|on| on.contains(&outcome)) =>
+                        {
+                            retries += 1;
+                            self.metrics.record_retry(outcome);
+                            debug!(
+                                "Retrying command. ConnectionId: {}, Command: {}, Tx: {}, Reason: {}, Retry: {}/{}",
+                                *connection_id,
+                                std::str::from_utf8(&request_body).unwrap_or_default(),
+                                std::str::from_utf8(&request_parameter).unwrap_or_default(),
+                                outcome.name(),
+                                retries,
+                                retry.times
+                            );
 
-                match recv_message {
-                    Ok(response) => self.handle_connector_response(
-                        request_body,
-                        request_parameter,
-                        response,
-                        command_input_definition,
-                        connection_id
-                    ),
-                    Err(err) => {
-                        error!("Can't receive message from connector thread! ConnectionId: {}, {}", *connection_id, err);
-                        PjLinkResponse::UnavailableTime
+                            thread::sleep(Self::retry_backoff(retry.backoff, retries));
+                        },
+                        (Some(BridgeDefinitionRetryOutcome::Checksum), _) => {
+                            self.metrics.record_unmatched_reply();
+                            debug!(
+                                "Projector response has an invalid checksum. ConnectionId: {}, Command: {} , Tx: {}, Rx: {:02x?}",
+                                *connection_id,
+                                std::str::from_utf8(&request_body).unwrap_or_default(),
+                                std::str::from_utf8(&request_parameter).unwrap_or_default(),
+                                response.response
+                            );
+                            return PjLinkResponse::OutOfParameter;
+                        },
+                        _ => return self.handle_connector_response(
+                            request_body,
+                            request_parameter,
+                            response,
+                            command_input_definition,
+                            connection_id
+                        )
                     }
                 }
             } else {
@@ -307,33 +1212,18 @@ impl PjLinkRS232Projector {
         command_input_definition: &BridgeDefinitionCommandDefinition,
         connection_id: &u64
     ) -> PjLinkResponse {
-        let PjLinkRS232MessageResponse {response: projector_response, elapsed_time} = response;
-        debug!(
-            "Received from projector: ConnectionId: {}, Response: {:02x?}, ElapsedTime: {}",
-            *connection_id,
-            projector_response,
-            elapsed_time
-        );
+        let PjLinkRS232MessageResponse {response: projector_response, elapsed_time: _} = response;
 
-        for BridgeDefinitionCommandDefinitionOutput {
-            on_received: command_on_received,
-            response: command_response,
-        } in &command_input_definition.outputs {
-            match command_on_received {
-                BridgeDefinitionCommandDefinitionOutputProjectorResponse::Value(command_on_received_value) =>
-                    if let Some(handler_response_value) = self.handle_connector_response_value(
-                        &request_body,
-                        &projector_response,
-                        command_on_received_value,
-                        command_response,
-                        connection_id
-                    ) {
-                        return handler_response_value;
-                    }
-                BridgeDefinitionCommandDefinitionOutputProjectorResponse::RuleMap(_, _) => panic!("RuleMap not implemented")
-            }
+        if let Some((command_output, received_value)) = Self::find_output(&projector_response, &command_input_definition.outputs) {
+            return self.handle_connector_response_value(
+                &request_body,
+                received_value,
+                &command_output.response,
+                connection_id
+            );
         }
 
+        self.metrics.record_unmatched_reply();
         debug!(
             "Projector specification doesn't contain a mapping for provided projector response. ConnectionId: {}, Command: {} , Tx: {}, Rx: {}",
             *connection_id,
@@ -344,42 +1234,151 @@ impl PjLinkRS232Projector {
         PjLinkResponse::OutOfParameter
     }
 
+    /// Output matching the projector reply, with the bytes received after its prefix.
+    #[inline(always)]
+    fn find_output<'a, 'b>(
+        projector_response: &'b [u8],
+        outputs: &'a [BridgeDefinitionCommandDefinitionOutput]
+    ) -> Option<(&'a BridgeDefinitionCommandDefinitionOutput, &'b [u8])> {
+        for command_output in outputs {
+            match &command_output.on_received {
+                BridgeDefinitionCommandDefinitionOutputProjectorResponse::Value(command_on_received_value) =>
+                    // Output from projector is equal to output from projector spec
+                    if projector_response.eq(command_on_received_value.as_slice()) {
+                        return Some((command_output, &[]));
+                    }
+                BridgeDefinitionCommandDefinitionOutputProjectorResponse::Prefix(command_on_received_prefix) =>
+                    // Output from projector starts with output from projector spec
+                    if projector_response.starts_with(command_on_received_prefix) {
+                        return Some((command_output, &projector_response[command_on_received_prefix.len()..]));
+                    }
+                BridgeDefinitionCommandDefinitionOutputProjectorResponse::RuleMap(_, _) => panic!("RuleMap not implemented")
+            }
+        }
+
+        None
+    }
+
+    /// Why the projector reply can't be used, if it can't. An empty reply is only a failure
+    /// when no output expects it (`on_received = []`).
+    #[inline(always)]
+    fn failed_outcome(
+        projector_response: &[u8],
+        outputs: &[BridgeDefinitionCommandDefinitionOutput],
+        reply_checksum: Option<BridgeDefinitionReplyChecksum>
+    ) -> Option<BridgeDefinitionRetryOutcome> {
+        let matched = Self::find_output(projector_response, outputs).is_some();
+
+        if projector_response.is_empty() {
+            return if matched {None} else {Some(BridgeDefinitionRetryOutcome::Empty)};
+        }
+
+        if let Some(reply_checksum) = reply_checksum {
+            let valid = match projector_response.split_last() {
+                Some((checksum, bytes)) => *checksum == match reply_checksum {
+                    BridgeDefinitionReplyChecksum::Sum8 => bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)),
+                    BridgeDefinitionReplyChecksum::Xor8 => bytes.iter().fold(0u8, |xor, byte| xor ^ *byte)
+                },
+                None => false
+            };
+
+            if !valid {
+                return Some(BridgeDefinitionRetryOutcome::Checksum);
+            }
+        }
+
+        if matched {None} else {Some(BridgeDefinitionRetryOutcome::Unmatched)}
+    }
+
+    /// Wait before retry number `retry` (from 1): `backoff` ms, doubled before each following retry.
+    #[inline(always)]
+    fn retry_backoff(backoff: u32, retry: u32) -> Duration {
+        Duration::from_millis((backoff as u64).saturating_mul(1 << retry.saturating_sub(1).min(16)))
+    }
+
     #[inline(always)]
     fn handle_connector_response_value(
         &self,
         request_body: &[u8; 5],
-        projector_response: &[u8],
-        command_on_received: &[u8],
+        received_value: &[u8],
         command_response: &BridgeDefinitionCommandDefinitionOutputResponse,
         connection_id: &u64
-    ) -> Option<PjLinkResponse> {
-        // Output from projector is equal to output from projector spec
-        if projector_response.eq(command_on_received) {
-            match command_response {
-                BridgeDefinitionCommandDefinitionOutputResponse::Value(command_response_value) => {
-                    let handler_response_value: PjLinkResponse = command_response_value.clone().into();
-                    debug!(
-                        "Translated response: ConnectionId: {}, CmdBodyWithClass: {}, TxParam: {}",
-                        *connection_id,
-                        std::str::from_utf8(request_body).unwrap_or_default(),
-                        command_response_value
-                    );
+    ) -> PjLinkResponse {
+        match command_response {
+            BridgeDefinitionCommandDefinitionOutputResponse::Value(command_response_value) => {
+                let handler_response_value: PjLinkResponse = command_response_value.clone().into();
+                debug!(
+                    "Translated response: ConnectionId: {}, CmdBodyWithClass: {}, TxParam: {}",
+                    *connection_id,
+                    std::str::from_utf8(request_body).unwrap_or_default(),
+                    command_response_value
+                );
 
-                    Some(handler_response_value)
-                },
-                BridgeDefinitionCommandDefinitionOutputResponse::Default(command_response) => {
-                    let handler_response_value: PjLinkResponse = command_response.clone().into();
-                    debug!(
-                        "Translated response: ConnectionId: {}, CmdBodyWithClass: {}, TxParam: {}",
-                        *connection_id,
-                        std::str::from_utf8(request_body).unwrap_or_default(),
-                        command_response
-                    );
+                handler_response_value
+            },
+            BridgeDefinitionCommandDefinitionOutputResponse::Default(command_response) => {
+                let handler_response_value: PjLinkResponse = command_response.clone().into();
+                debug!(
+                    "Translated response: ConnectionId: {}, CmdBodyWithClass: {}, TxParam: {}",
+                    *connection_id,
+                    std::str::from_utf8(request_body).unwrap_or_default(),
+                    command_response
+                );
 
-                    Some(handler_response_value)
-                }
+                handler_response_value
+            },
+            BridgeDefinitionCommandDefinitionOutputResponse::Received(received_format) => {
+                let command_response_value = match Self::format_received_value(received_value, received_format) {
+                    Some(command_response_value) => command_response_value,
+                    None => {
+                        debug!(
+                            "Projector response is too short for its format. ConnectionId: {}, CmdBodyWithClass: {}, Rx: {:02x?}",
+                            *connection_id,
+                            std::str::from_utf8(request_body).unwrap_or_default(),
+                            received_value
+                        );
+                        return PjLinkResponse::ProjectorFailure;
+                    }
+                };
+                debug!(
+                    "Translated response: ConnectionId: {}, CmdBodyWithClass: {}, TxParam: {}",
+                    *connection_id,
+                    std::str::from_utf8(request_body).unwrap_or_default(),
+                    std::str::from_utf8(&command_response_value).unwrap_or_default()
+                );
+
+                PjLinkResponse::Multiple(command_response_value)
             }
-        } else {None}
+        }
+    }
+
+    /// Returns None when the received bytes are too short for the format (e.g. a truncated resolution).
+    #[inline(always)]
+    fn format_received_value(
+        received_value: &[u8],
+        received_format: &BridgeDefinitionCommandDefinitionOutputReceivedFormat
+    ) -> Option<Vec<u8>> {
+        match received_format {
+            BridgeDefinitionCommandDefinitionOutputReceivedFormat::Ascii => {
+                let end = received_value.iter()
+                    .rposition(|byte| !matches!(byte, 0x00 | b'\r' | b'\n'))
+                    .map_or(0, |position| position + 1);
+                Some(Vec::from(&received_value[..end]))
+            },
+            BridgeDefinitionCommandDefinitionOutputReceivedFormat::Hex =>
+                Some(received_value.iter().map(|byte| format!("{:02X}", byte)).collect::<String>().into()),
+            BridgeDefinitionCommandDefinitionOutputReceivedFormat::ResolutionLe if received_value.len() >= 4 => Some(format!(
+                "{}x{}",
+                u16::from_le_bytes([received_value[0], received_value[1]]),
+                u16::from_le_bytes([received_value[2], received_value[3]])
+            ).into()),
+            BridgeDefinitionCommandDefinitionOutputReceivedFormat::ResolutionBe if received_value.len() >= 4 => Some(format!(
+                "{}x{}",
+                u16::from_be_bytes([received_value[0], received_value[1]]),
+                u16::from_be_bytes([received_value[2], received_value[3]])
+            ).into()),
+            _ => None
+        }
     }
 
     #[inline(always)]
@@ -389,23 +1388,59 @@ impl PjLinkRS232Projector {
         timeout: u32,
         connection_id: &u64
     ) -> Result<PjLinkRS232MessageResponse, RecvError> {
-        debug!(
-            "Will send to projector: ConnectionId: {}, Request: {:02x?}",
-            *connection_id,
-            message,
-        );
+        self.events.publish(BridgeEventKind::SerialTx {
+            connection_id: *connection_id,
+            serial_tx: BridgeProjectorEvents::hex(&message)
+        });
 
         if let Err(err) = self.tx.send(PjLinkRS232MessageRequest {
             message,
             timeout,
             stop_processing: false
         }) {
-            error!("Can't send message to connector thread! ConnectionId: {}, {}", *connection_id, err);
+            self.events.publish(BridgeEventKind::ConnectorError {
+                message: format!("Can't send message to connector thread! ConnectionId: {}, {}", *connection_id, err)
+            });
         }
 
         self.rx.recv()
     }
 
+    /// Sends the input `send` sequence (`send_times` times), returning the last reply.
+    #[inline(always)]
+    fn send_command_input(
+        &self,
+        command_input_definition: &BridgeDefinitionCommandDefinition,
+        timeout: u32,
+        connection_id: &u64
+    ) -> Result<PjLinkRS232MessageResponse, RecvError> {
+        let message = command_input_definition.send.clone();
+        let send_times = command_input_definition.send_times.unwrap_or(1);
+
+        if send_times > 1 {
+            let mut result: Result<PjLinkRS232MessageResponse, RecvError> = Ok(PjLinkRS232MessageResponse {response: vec! [], elapsed_time: 0});
+
+            for _ in 0..send_times {
+                result = self.send_and_receive_message(message.clone(), timeout, connection_id);
+            }
+
+            result
+        } else {
+            self.send_and_receive_message(message, timeout, connection_id)
+        }
+    }
+
+    #[inline(always)]
+    fn get_retry<'a>(&self,
+        behavior: &'a BridgeDefinitionBehavior,
+        command_input_definition: &'a BridgeDefinitionCommandDefinition,
+        command_spec: &'a BridgeDefinitionCommand
+    ) -> Option<&'a BridgeDefinitionRetry> {
+        command_input_definition.retry.as_ref()
+            .or(command_spec.retry.as_ref())
+            .or(behavior.retry.as_ref())
+    }
+
     #[inline(always)]
     fn get_timeout(&self,
         behavior: &BridgeDefinitionBehavior,
@@ -418,93 +1453,508 @@ impl PjLinkRS232Projector {
     }
 
     fn open_rs232_connector(
-        serial_port: String,
-        baud_rate: u32,
-        data_bits: u8,
-        parity: char,
-        stop_bits: u8,
-        hardware_flow_control: bool,
-        software_flow_control: bool,
-    ) -> (SyncSender<PjLinkRS232MessageRequest>, Receiver<PjLinkRS232MessageResponse>) {
+        connector_options: PjLinkRS232ConnectorOptions,
+        health: Arc<PjLinkRS232ConnectorHealth>,
+        metrics: Arc<BridgeMetrics>,
+        events: Arc<BridgeProjectorEvents>,
+        state: Arc<Mutex<PjLinkRS232ProjectorState>>,
+        usage: Arc<Mutex<PjLinkRS232Usage>>,
+        behavior: Arc<Mutex<PjLinkRS232ConnectorBehavior>>
+    ) -> Result<(SyncSender<PjLinkRS232MessageRequest>, Receiver<PjLinkRS232MessageResponse>), Error> {
+        let serial_port = connector_options.serial_port.clone();
+        let serial_conn = PjLinkRS232Connector::open(connector_options)?;
+
         let (to_connector_tx, to_connector_rx) = sync_channel::<PjLinkRS232MessageRequest>(0);
         let (from_connector_tx, from_connector_rx) = channel::<PjLinkRS232MessageResponse>();
 
         let connector_channel = (from_connector_tx, to_connector_rx);
 
-        thread::spawn(move || {
-            PjLinkRS232Connector::spawn(
-                PjLinkRS232ConnectorOptions {
-                    serial_port,
-                    baud_rate, 
-                    data_bits,
-                    parity,
-                    stop_bits,
-                    hardware_flow_control,
-                    software_flow_control,
-                },
-                connector_channel
-            );
-        }); 
+        if let Err(err) = thread::Builder::new()
+            .name(format!("connector {}", serial_port))
+            .spawn(move || PjLinkRS232Connector::spawn(serial_conn, connector_channel, health, metrics, events, state, usage, behavior))
+        {
+            return Err(Error {message: format!("Cannot start connector thread for {}! {}", serial_port, err)});
+        }
 
-        (to_connector_tx, from_connector_rx)
+        Ok((to_connector_tx, from_connector_rx))
+    }
+
+    /// Stops the connector thread, waiting until it has closed the serial port.
+    fn close_rs232_connector(&self) {
+        if self.tx.send(PjLinkRS232MessageRequest {
+            message: vec! [],
+            timeout: 0,
+            stop_processing: true
+        }).is_ok() {
+            self.rx.recv().unwrap_or(PjLinkRS232MessageResponse {response: vec! [], elapsed_time: 0});
+        }
     }
 }
 
-impl PjLinkHandler for PjLinkRS232Projector {
-    fn handle_command(&mut self, command: PjLinkCommand, raw_command: &PjLinkRawPayload, connection_id: &u64) -> PjLinkResponse {
-        match command {
+impl BridgeAccessHandler for PjLinkRS232Projector {
+    /// In maintenance mode clients must authenticate with the admin password.
+    fn client_password(&self) -> Option<(String, PjLinkRS232Credentials)> {
+        match (&self.options.admin_password, &self.options.password) {
+            (Some(admin_password), _) if self.mode == PjLinkRS232ProjectorMode::Maintenance => Some((admin_password.clone(), PjLinkRS232Credentials::Admin)),
+            (_, Some(password)) => Some((password.clone(), PjLinkRS232Credentials::User)),
+            _ => None
+        }
+    }
+
+    fn handle_client_command(&mut self, client: &PjLinkRS232Client, request_body: [u8; 5], request_parameter: &[u8]) -> PjLinkResponse {
+        PjLinkRS232Projector::handle_client_command(self, client, request_body, request_parameter)
+    }
+}
+
+impl PjLinkRS232Projector {
+    /// Handles a command originated by the bridge itself (e.g. the HTTP API), going through
+    /// the same path as PJLink clients. `credentials` are the ones verified by the originator.
+    pub fn handle_bridge_command(
+        &mut self,
+        request_body: [u8; 5],
+        request_parameter: &[u8],
+        connection_id: &u64,
+        credentials: PjLinkRS232Credentials
+    ) -> PjLinkResponse {
+        let client = PjLinkRS232Client {
+            connection_id: *connection_id,
+            address: None,
+            credentials,
+            credential: None,
+        };
+
+        self.process_command(request_body, request_parameter, &client)
+    }
+
+    /// Handles a command from a client the originator has authenticated (PJLink clients of the access server
+    /// and HTTP API clients), going through the same path as the other commands.
+    pub fn handle_client_command(&mut self, client: &PjLinkRS232Client, request_body: [u8; 5], request_parameter: &[u8]) -> PjLinkResponse {
+        self.process_command(request_body, request_parameter, client)
+    }
+
+    fn process_command(
+        &mut self,
+        request_body: [u8; 5],
+        request_parameter: &[u8],
+        client: &PjLinkRS232Client
+    ) -> PjLinkResponse {
+        let connection_id = &client.connection_id;
+        let pjlink_command = String::from_utf8_lossy(&request_body).into_owned();
+
+        self.events.publish(BridgeEventKind::Request {
+            connection_id: *connection_id,
+            source: Self::command_source(connection_id),
+            client_address: client.address.map(|address| address.to_string()),
+            authenticated: client.credentials != PjLinkRS232Credentials::None,
+            credential: client.credential.clone(),
+            pjlink_command: pjlink_command.clone(),
+            tx_param: String::from_utf8_lossy(request_parameter).into_owned()
+        });
+
+        if request_parameter != b"?" && *connection_id != IDLE_CONNECTION_ID {
+            self.last_activity = Instant::now();
+        }
+
+        // Powered-on time is counted up to the command, which may change the power state
+        self.account_usage();
+
+        let response = if self.shutting_down {
+            debug!("Bridge is shutting down, rejecting command. ConnectionId: {}", *connection_id);
+            PjLinkResponse::UnavailableTime
+        } else if self.mode == PjLinkRS232ProjectorMode::Maintenance && self.options.admin_password.is_some()
+            && client.credentials != PjLinkRS232Credentials::Admin {
+            // Without an admin password (e.g. removed on reload) nobody could get through
+            debug!("Maintenance mode, rejecting command. ConnectionId: {}", *connection_id);
+            PjLinkResponse::UnavailableTime
+        } else if self.mode == PjLinkRS232ProjectorMode::ReadOnly && request_parameter != b"?" {
+            debug!("Read-only mode, rejecting set command. ConnectionId: {}", *connection_id);
+            PjLinkResponse::UnavailableTime
+        } else {
+            self.dispatch_command(request_body, request_parameter, connection_id)
+        };
+
+        self.metrics.record_command(&request_body, &response);
+
+        let changed_value = PjLinkRS232Usage::update_state(&self.usage, &self.state, &request_body, request_parameter, &response);
+
+        self.events.publish(BridgeEventKind::Response {
+            connection_id: *connection_id,
+            pjlink_command: pjlink_command.clone(),
+            response: Self::response_text(&response)
+        });
+
+        // Saves the usage counters when the command changed the power state
+        self.account_usage();
+
+        if let Some(value) = changed_value {
+            self.events.publish(BridgeEventKind::StateChanged {
+                pjlink_command,
+                value: String::from_utf8_lossy(&value).into_owned()
+            });
+        }
+
+        response
+    }
+
+    #[inline(always)]
+    fn command_source(connection_id: &u64) -> &'static str {
+        match *connection_id {
+            BRIDGE_CONNECTION_ID => "bridge",
+            API_CONNECTION_ID => "http_api",
+            MQTT_CONNECTION_ID => "mqtt",
+            SCHEDULE_CONNECTION_ID => "schedule",
+            IDLE_CONNECTION_ID => "idle",
+            _ => "pjlink"
+        }
+    }
+
+    fn dispatch_command(&mut self, request_body: [u8; 5], request_parameter: &[u8], connection_id: &u64) -> PjLinkResponse {
+        match &request_body {
             // #region Class Information Query / CLSS
-            PjLinkCommand::Class1 => {
+            b"1CLSS" => {
                 info!("Class Information Query");
                 PjLinkResponse::Single(self.options.class_type)
             }
             // #endregion
             // #region Serial Number Query / SNUM
-            PjLinkCommand::SerialNumber2 => {
+            b"2SNUM" => {
                 info!("Serial Number Query");
-                PjLinkResponse::Multiple(self.options.serial_number.clone())
+                if self.has_dynamic_content(&request_body, request_parameter) {self.handle_dynamic_content(request_body, request_parameter, connection_id)}
+                else {PjLinkResponse::Multiple(self.options.serial_number.clone())}
             }
             // #endregion
             // #region Software Version Query / SVER
-            PjLinkCommand::SoftwareVersion2 => {
+            b"2SVER" => {
                 info!("Software Version Query");
-                PjLinkResponse::Multiple(self.options.software_version.clone())
+                if self.has_dynamic_content(&request_body, request_parameter) {self.handle_dynamic_content(request_body, request_parameter, connection_id)}
+                else {PjLinkResponse::Multiple(self.options.software_version.clone())}
             }
             // #endregion
             // #region Projector/Display Name Query / NAME
-            PjLinkCommand::Name1 => {
+            b"1NAME" => {
                 info!("Name Query");
                 PjLinkResponse::Multiple(self.options.projector_name.clone())
             }
             // #endregion
             // #region Manufacture Name Information Query / INF1
-            PjLinkCommand::InfoManufacturer1 => {
+            b"1INF1" => {
                 info!("Info Manufacturer Query");
                 PjLinkResponse::Multiple(self.options.manufacturer_name.clone())
             }
             // #endregion
             // #region Product Name Information Query / INF2
-            PjLinkCommand::InfoProductName1 => {
+            b"1INF2" => {
                 info!("Info Product Name Query");
-                PjLinkResponse::Multiple(self.options.product_name.clone())
+                if self.has_dynamic_content(&request_body, request_parameter) {self.handle_dynamic_content(request_body, request_parameter, connection_id)}
+                else {PjLinkResponse::Multiple(self.options.product_name.clone())}
             }
             // #endregion
             // #region Input Resolution Query / IRES
-            PjLinkCommand::InputResolution2 => {
+            b"2IRES" => {
                 info!("Input Resolution Query");
-                PjLinkResponse::Multiple(self.options.screen_resolution.clone())
+                if self.has_dynamic_content(&request_body, request_parameter) {self.handle_dynamic_content(request_body, request_parameter, connection_id)}
+                else {PjLinkResponse::Multiple(self.options.screen_resolution.clone())}
             }
             // #endregion
             // #region Recommend Resolution Query / RRES
-            PjLinkCommand::RecommendResolution2 => {
+            b"2RRES" => {
                 info!("Recommend Resolution Query");
                 PjLinkResponse::Multiple(self.options.recommended_screen_resolution.clone())
             }
-            _ => self.handle_dynamic_content(command, raw_command, connection_id)
+            _ => self.handle_dynamic_content(request_body, request_parameter, connection_id)
+        }
+    }
+}
+
+/// Projector on a pseudo terminal. The other end of the terminal, returned along, stands for the projector.
+#[cfg(test)]
+pub fn test_projector(projector_name: &str, definition: &str) -> (serialport::TTYPort, PjLinkRS232Projector) {
+    let (serial_port, projector_port) = serialport::TTYPort::pair().unwrap();
+
+    let mut options = PjLinkRS232ProjectorOptions::from_def(toml::from_str::<BridgeDefinition>(definition).unwrap());
+    options.projector_name = Vec::from(projector_name.as_bytes());
+    options.serial_port = serialport::SerialPort::name(&projector_port).unwrap();
+
+    let projector = PjLinkRS232Projector::new(options, &Arc::new(BridgeEvents::default())).ok().unwrap();
+    (serial_port, projector)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_received_values() {
+        use BridgeDefinitionCommandDefinitionOutputReceivedFormat as Format;
+
+        assert_eq!(PjLinkRS232Projector::format_received_value(b"M0R002\r\n\0", &Format::Ascii), Some(b"M0R002".to_vec()));
+        assert_eq!(PjLinkRS232Projector::format_received_value(&[0x0a, 0xff], &Format::Hex), Some(b"0AFF".to_vec()));
+        assert_eq!(PjLinkRS232Projector::format_received_value(&[0x00, 0x04, 0x00, 0x03], &Format::ResolutionLe), Some(b"1024x768".to_vec()));
+        assert_eq!(PjLinkRS232Projector::format_received_value(&[0x04, 0x00, 0x03, 0x00], &Format::ResolutionBe), Some(b"1024x768".to_vec()));
+    }
+
+    fn outputs(toml: &str) -> Vec<BridgeDefinitionCommandDefinitionOutput> {
+        toml::from_str::<BridgeDefinitionCommandDefinition>(&format!("send = []\n{}", toml)).ok().unwrap().outputs
+    }
+
+    #[test]
+    fn classifies_failed_outcomes() {
+        let outputs = outputs(r#"outputs = [
+            {on_received_type = "value", on_received = [0x00, 0xff, 0x01], response_type = "value", response_value = "0"},
+            {on_received_type = "prefix", on_received = [0x1d], response_type = "received", response_value = "hex"}
+        ]"#);
+
+        assert_eq!(PjLinkRS232Projector::failed_outcome(&[0x00, 0xff, 0x01], &outputs, None), None);
+        assert_eq!(PjLinkRS232Projector::failed_outcome(&[0x1d, 0x01, 0x02], &outputs, None), None);
+        assert_eq!(PjLinkRS232Projector::failed_outcome(&[], &outputs, None), Some(BridgeDefinitionRetryOutcome::Empty));
+        assert_eq!(PjLinkRS232Projector::failed_outcome(&[0x00, 0xfe], &outputs, None), Some(BridgeDefinitionRetryOutcome::Unmatched));
+    }
+
+    #[test]
+    fn accepts_empty_replies_mapped_by_an_output() {
+        let outputs = outputs(r#"outputs = [{on_received_type = "value", on_received = [], response_type = "default", response_value = "OK"}]"#);
+
+        assert_eq!(PjLinkRS232Projector::failed_outcome(&[], &outputs, None), None);
+        assert_eq!(PjLinkRS232Projector::failed_outcome(&[], &outputs, Some(BridgeDefinitionReplyChecksum::Sum8)), None);
+    }
+
+    #[test]
+    fn checks_reply_checksums() {
+        let outputs = outputs(r#"outputs = [{on_received_type = "prefix", on_received = [0x1d], response_type = "received", response_value = "hex"}]"#);
+
+        // 0x1d + 0x01 + 0xf0 = 0x10e
+        assert_eq!(PjLinkRS232Projector::failed_outcome(&[0x1d, 0x01, 0xf0, 0x0e], &outputs, Some(BridgeDefinitionReplyChecksum::Sum8)), None);
+        assert_eq!(PjLinkRS232Projector::failed_outcome(&[0x1d, 0x01, 0xf0, 0x0f], &outputs, Some(BridgeDefinitionReplyChecksum::Sum8)), Some(BridgeDefinitionRetryOutcome::Checksum));
+        // 0x1d ^ 0x01 ^ 0xf0 = 0xec
+        assert_eq!(PjLinkRS232Projector::failed_outcome(&[0x1d, 0x01, 0xf0, 0xec], &outputs, Some(BridgeDefinitionReplyChecksum::Xor8)), None);
+        assert_eq!(PjLinkRS232Projector::failed_outcome(&[0x1e, 0x01, 0xf0, 0xef], &outputs, Some(BridgeDefinitionReplyChecksum::Xor8)), Some(BridgeDefinitionRetryOutcome::Unmatched));
+    }
+
+    #[test]
+    fn doubles_retry_backoff() {
+        assert_eq!(PjLinkRS232Projector::retry_backoff(100, 1), Duration::from_millis(100));
+        assert_eq!(PjLinkRS232Projector::retry_backoff(100, 2), Duration::from_millis(200));
+        assert_eq!(PjLinkRS232Projector::retry_backoff(100, 4), Duration::from_millis(800));
+        assert_eq!(PjLinkRS232Projector::retry_backoff(0, 3), Duration::from_millis(0));
+        assert_eq!(PjLinkRS232Projector::retry_backoff(u32::MAX, 40), Duration::from_millis(u32::MAX as u64 * (1 << 16)));
+    }
+
+    #[test]
+    fn measures_reply_latency_to_the_first_byte() {
+        use std::io::Write;
+
+        let (mut projector_port, mut serial_port) = serialport::TTYPort::pair().unwrap();
+
+        let written_at = Instant::now();
+        assert_eq!(PjLinkRS232Connector::wait_for_reply(&mut serial_port, written_at, Duration::from_millis(50)), None);
+        assert!(written_at.elapsed() >= Duration::from_millis(50));
+
+        let written_at = Instant::now();
+        let reply = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(30));
+            projector_port.write_all(&[0x06]).unwrap();
+            projector_port
+        });
+        let first_byte_after = PjLinkRS232Connector::wait_for_reply(&mut serial_port, written_at, Duration::from_millis(200)).unwrap();
+        let _projector_port = reply.join().unwrap();
+
+        // The whole timeout is waited for, the latency stops at the first byte
+        assert!(first_byte_after >= Duration::from_millis(30) && first_byte_after < Duration::from_millis(150), "{:?}", first_byte_after);
+        assert!(written_at.elapsed() >= Duration::from_millis(200));
+    }
+
+    fn unsolicited(toml: &str) -> Vec<BridgeDefinitionUnsolicited> {
+        #[derive(Deserialize)]
+        struct Definition {
+            unsolicited: Vec<BridgeDefinitionUnsolicited>,
+        }
+
+        toml::from_str::<Definition>(toml).unwrap().unsolicited
+    }
+
+    #[test]
+    fn accounts_usage_on_unsolicited_power_changes() {
+        let unsolicited = unsolicited(r#"
+            [[unsolicited]]
+            on_received_type = "value"
+            on_received = [0x50, 0x31]
+            pjlink_command = "1POWR"
+            response_type = "value"
+            response_value = "1"
+
+            [[unsolicited]]
+            on_received_type = "value"
+            on_received = [0x50, 0x30]
+            pjlink_command = "1POWR"
+            response_type = "value"
+            response_value = "0"
+        "#);
+        let state = Mutex::new(PjLinkRS232ProjectorState::default());
+        let usage = Mutex::new(PjLinkRS232Usage::new());
+        let events = Arc::new(BridgeEvents::default()).for_projector(String::from("Room 101"));
+
+        // On for 100 ms, then off for 100 ms, without any command
+        PjLinkRS232Connector::handle_unsolicited(&mut vec![0x50, 0x31], &unsolicited, &state, &usage, &events);
+        assert_eq!(state.lock().unwrap().power_on, b'1');
+        thread::sleep(Duration::from_millis(100));
+
+        PjLinkRS232Connector::handle_unsolicited(&mut vec![0x50, 0x30], &unsolicited, &state, &usage, &events);
+        assert_eq!(state.lock().unwrap().power_on, b'0');
+        let lamp = usage.lock().unwrap().lamp;
+        assert!(lamp >= Duration::from_millis(100), "{:?}", lamp);
+        thread::sleep(Duration::from_millis(100));
+
+        PjLinkRS232Connector::handle_unsolicited(&mut vec![0x50, 0x31], &unsolicited, &state, &usage, &events);
+        let usage = usage.lock().unwrap();
+        assert_eq!(usage.lamp, lamp);
+        assert_eq!(usage.filter, lamp);
+        assert!(usage.power_changed);
+    }
+
+    #[test]
+    fn removes_unsolicited_frames_from_other_input() {
+        let unsolicited = unsolicited(r#"
+            [[unsolicited]]
+            on_received_type = "value"
+            on_received = [0x50, 0x31]
+            pjlink_command = "1POWR"
+            response_type = "value"
+            response_value = "1"
+
+            [[unsolicited]]
+            on_received_type = "prefix"
+            on_received = [0x4c, 0x54]
+            length = 4
+            pjlink_command = "1LAMP"
+            response_type = "received"
+            response_value = "ascii"
+        "#);
+        let state = Mutex::new(PjLinkRS232ProjectorState::default());
+        let usage = Mutex::new(PjLinkRS232Usage::new());
+        let events = Arc::new(BridgeEvents::default()).for_projector(String::from("Room 101"));
+
+        // A reply with both frames in the middle of it
+        let mut buffer = vec![0x06, 0x50, 0x31, 0x10, 0x4c, 0x54, 0x31, 0x32, 0x30, 0x30, 0x0d];
+        PjLinkRS232Connector::handle_unsolicited(&mut buffer, &unsolicited, &state, &usage, &events);
+        assert_eq!(buffer, vec![0x06, 0x10, 0x0d]);
+        assert_eq!(state.lock().unwrap().power_on, b'1');
+        assert_eq!(state.lock().unwrap().lamp_hours, b"1200".to_vec());
+
+        // Partial patterns are left alone
+        let mut buffer = vec![0x50, 0x32, 0x4c];
+        PjLinkRS232Connector::handle_unsolicited(&mut buffer, &unsolicited, &state, &usage, &events);
+        assert_eq!(buffer, vec![0x50, 0x32, 0x4c]);
+
+        // Without definitions, nothing is removed
+        let mut buffer = vec![0x50, 0x31];
+        PjLinkRS232Connector::handle_unsolicited(&mut buffer, &[], &state, &usage, &events);
+        assert_eq!(buffer, vec![0x50, 0x31]);
+    }
+
+    #[test]
+    fn paces_bytes_with_the_byte_delay() {
+        use std::io::Read;
+
+        let (mut projector_port, mut serial_port) = serialport::TTYPort::pair().unwrap();
+        serialport::SerialPort::set_timeout(&mut projector_port, Duration::from_secs(10)).unwrap();
+        let mut received = [0u8; 4];
+
+        let written_at = Instant::now();
+        PjLinkRS232Connector::write(&mut serial_port, &[0x02, 0x50, 0x31, 0x03], Duration::from_millis(20)).unwrap();
+        assert!(written_at.elapsed() >= Duration::from_millis(80), "{:?}", written_at.elapsed());
+        projector_port.read_exact(&mut received).unwrap();
+        assert_eq!(received, [0x02, 0x50, 0x31, 0x03]);
+
+        let written_at = Instant::now();
+        PjLinkRS232Connector::write(&mut serial_port, &[0x02, 0x50, 0x30, 0x03], Duration::ZERO).unwrap();
+        assert!(written_at.elapsed() < Duration::from_millis(80), "{:?}", written_at.elapsed());
+        projector_port.read_exact(&mut received).unwrap();
+        assert_eq!(received, [0x02, 0x50, 0x30, 0x03]);
+    }
+
+    #[test]
+    fn holds_the_command_gap_after_each_reply() {
+        use std::io::{Read, Write};
+
+        let (mut projector_port, mut projector) = test_projector("Room 101", r#"
+            [general]
+            manufacturer_name = "Test"
+            product_name = "Projector"
+            software_version = "1"
+            class_type = 1
+
+            [connection]
+            baud_rate = 9600
+            command_gap = 150
+
+            [commands."1POWR".inputs."?"]
+            send = [0x02]
+            wait_for_response = 20
+            outputs = [
+                {on_received_type = "value", on_received = [0x10], response_type = "value", response_value = "0"}
+            ]
+        "#);
+
+        // The projector replies right away, noting when each command arrives
+        serialport::SerialPort::set_timeout(&mut projector_port, Duration::from_secs(10)).unwrap();
+        let projector_thread = thread::spawn(move || {
+            let mut received = [0u8; 1];
+            let mut received_at = vec![];
+
+            for _ in 0..2 {
+                projector_port.read_exact(&mut received).unwrap();
+                received_at.push(Instant::now());
+                projector_port.write_all(&[0x10]).unwrap();
+            }
+
+            (projector_port, received_at)
+        });
+
+        for _ in 0..2 {
+            let response = projector.handle_bridge_command(*b"1POWR", b"?", &API_CONNECTION_ID, PjLinkRS232Credentials::User);
+            assert!(matches!(response, PjLinkResponse::Single(b'0')));
         }
+
+        let (_projector_port, received_at) = projector_thread.join().unwrap();
+        let gap = received_at[1] - received_at[0];
+        assert!(gap >= Duration::from_millis(150), "{:?}", gap);
+    }
+
+    #[test]
+    fn holds_commands_back_in_maintenance_mode_while_an_admin_password_is_set() {
+        let (_projector_port, mut projector) = test_projector("Room 101", r#"
+            [general]
+            manufacturer_name = "Test"
+            product_name = "Projector"
+            software_version = "1"
+            class_type = 1
+
+            [connection]
+            baud_rate = 9600
+
+            [commands."1POWR".inputs."?"]
+            send = [0x02]
+            outputs = [
+                {on_received_type = "value", on_received = [0x10], response_type = "value", response_value = "0"}
+            ]
+        "#);
+        let name_query = |projector: &mut PjLinkRS232Projector, credentials| projector.handle_bridge_command(*b"1NAME", b"?", &API_CONNECTION_ID, credentials);
+
+        projector.set_admin_password(Some(String::from("admin")));
+        projector.set_mode(PjLinkRS232ProjectorMode::Maintenance).ok().unwrap();
+        assert!(matches!(name_query(&mut projector, PjLinkRS232Credentials::User), PjLinkResponse::UnavailableTime));
+        assert!(matches!(name_query(&mut projector, PjLinkRS232Credentials::Admin), PjLinkResponse::Multiple(_)));
+
+        // Removed on reload, nobody could get through anymore
+        projector.set_admin_password(None);
+        assert!(matches!(name_query(&mut projector, PjLinkRS232Credentials::User), PjLinkResponse::Multiple(_)));
     }
 
-    fn get_password(&mut self, _connection_id: &u64) -> Option<String> {
-        self.options.password.clone()
+    #[test]
+    fn rejects_truncated_resolutions() {
+        use BridgeDefinitionCommandDefinitionOutputReceivedFormat as Format;
+
+        assert_eq!(PjLinkRS232Projector::format_received_value(&[0x00, 0x04, 0x00], &Format::ResolutionLe), None);
+        assert_eq!(PjLinkRS232Projector::format_received_value(&[], &Format::ResolutionBe), None);
     }
-}
\ No newline at end of file
+}
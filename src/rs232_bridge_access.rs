@@ -0,0 +1,957 @@
+use std::{
+    collections::HashMap,
+    io::{self, BufRead, BufReader, ErrorKind, Read, Write},
+    net::{IpAddr, Shutdown, TcpListener, TcpStream},
+    sync::{Arc, Mutex, atomic::{AtomicBool, AtomicU64, Ordering}},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant}
+};
+
+use ipnet::IpNet;
+use log::{debug, error, info, warn};
+use pjlink_bridge::PjLinkResponse;
+
+use crate::{
+    rs232_bridge::{PjLinkRS232Client, PjLinkRS232Credentials, PjLinkRS232Projector},
+    rs232_bridge_config::BridgeConfigAccess,
+    rs232_bridge_credentials::{BridgeCredentialPermission, BridgeProjectorCredentials},
+    rs232_bridge_events::{BridgeEventKind, BridgeProjectorEvents}
+};
+
+/// Longest lockout applied to a client, however many times it has been locked out.
+const MAX_LOCKOUT: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// PJLink authentication digests are 32 hex characters sent before the first command.
+const AUTH_DIGEST_LENGTH: usize = 32;
+
+/// Longest PJLink command line: `%`, class, 4 character command, separator, 128 byte parameter and `\r`.
+/// Clients sending longer lines are disconnected.
+const MAX_LINE_LENGTH: usize = 136;
+
+/// Open connections per projector, further clients are disconnected before the greeting.
+const MAX_CONNECTIONS: usize = 32;
+
+/// PJLink clients are disconnected after 30 seconds without commands.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How often the listener checks whether the bridge is shutting down, while no client connects.
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Connection ids given to clients of every access server, in the same sequence.
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Allow and deny lists. Deny always wins; an empty allow list allows every address.
+struct BridgeAccessList {
+    allow: Vec<IpNet>,
+    deny: Vec<IpNet>,
+}
+
+struct BridgeAccessRule {
+    /// Commands without class (e.g. `POWR`), `*` matches every command
+    commands: Vec<String>,
+    queries: bool,
+    list: BridgeAccessList,
+}
+
+#[derive(Default)]
+struct BridgeAccessClient {
+    auth_failures: u32,
+    lockouts: u32,
+    locked_until: Option<Instant>,
+    last_failure: Option<Instant>,
+}
+
+/// Why a client or one of its commands was refused, recorded in `access_denied` events.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum BridgeAccessDenial {
+    NotAllowed,
+    LockedOut,
+    TooManyConnections,
+    AuthFailed,
+    CommandNotAllowed,
+}
+
+/// Allow and deny lists, command rules and lockouts of a projector, shared by its PJLink server and HTTP API,
+/// so that authentication failures on either count towards the same lockout.
+pub struct BridgeAccessPolicy {
+    projector_name: String,
+    list: BridgeAccessList,
+    rules: Vec<BridgeAccessRule>,
+    max_auth_failures: u32,
+    lockout: Duration,
+    clients: Mutex<HashMap<IpAddr, BridgeAccessClient>>,
+}
+
+/// Open client connections by connection id, counted against `MAX_CONNECTIONS` and closed when the bridge shuts down.
+type BridgeAccessConnections = Mutex<HashMap<u64, TcpStream>>;
+
+pub struct Error {
+    pub message: String
+}
+
+impl BridgeAccessList {
+    fn parse(allow: &[String], deny: &[String]) -> Result<BridgeAccessList, Error> {
+        Ok(BridgeAccessList {
+            allow: Self::parse_networks(allow)?,
+            deny: Self::parse_networks(deny)?,
+        })
+    }
+
+    /// Accepts networks in CIDR notation and single addresses.
+    fn parse_networks(networks: &[String]) -> Result<Vec<IpNet>, Error> {
+        networks.iter()
+            .map(|network| network.parse::<IpNet>()
+                .or_else(|_| network.parse::<IpAddr>().map(IpNet::from))
+                .map_err(|_| Error {message: format!("Invalid network or address: {}", network)}))
+            .collect()
+    }
+
+    fn permits(&self, address: &IpAddr) -> bool {
+        !self.deny.iter().any(|network| network.contains(address))
+            && (self.allow.is_empty() || self.allow.iter().any(|network| network.contains(address)))
+    }
+}
+
+impl BridgeAccessDenial {
+    fn reason(&self) -> &'static str {
+        match self {
+            BridgeAccessDenial::NotAllowed => "not_allowed",
+            BridgeAccessDenial::LockedOut => "locked_out",
+            BridgeAccessDenial::TooManyConnections => "too_many_connections",
+            BridgeAccessDenial::AuthFailed => "auth_failed",
+            BridgeAccessDenial::CommandNotAllowed => "command_not_allowed",
+        }
+    }
+}
+
+impl BridgeAccessPolicy {
+    pub fn from_config(projector_name: String, access: &BridgeConfigAccess) -> Result<BridgeAccessPolicy, Error> {
+        Ok(BridgeAccessPolicy {
+            projector_name,
+            list: BridgeAccessList::parse(&access.allow, &access.deny)?,
+            rules: access.rules.iter()
+                .map(|rule| BridgeAccessList::parse(&rule.allow, &rule.deny).map(|list| BridgeAccessRule {
+                    commands: rule.commands.clone(),
+                    queries: rule.queries,
+                    list,
+                }))
+                .collect::<Result<Vec<_>, Error>>()?,
+            max_auth_failures: access.max_auth_failures.max(1),
+            lockout: Duration::from_secs(access.lockout),
+            clients: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Whether connections from this address are allowed.
+    pub fn permits(&self, address: &IpAddr) -> bool {
+        self.list.permits(address)
+    }
+
+    /// Whether a command from this address is allowed. Commands must be given without class (e.g. `POWR`).
+    pub fn permits_command(&self, address: &IpAddr, command: &str, query: bool) -> bool {
+        self.rules.iter()
+            .filter(|rule| !query || rule.queries)
+            .filter(|rule| rule.commands.iter().any(|rule_command| rule_command == "*" || rule_command.eq_ignore_ascii_case(command)))
+            .all(|rule| rule.list.permits(address))
+    }
+
+    fn locked_until(&self, address: &IpAddr) -> Option<Instant> {
+        self.clients.lock().ok()?
+            .get(address)
+            .and_then(|client| client.locked_until)
+            .filter(|locked_until| *locked_until > Instant::now())
+    }
+
+    /// Checks a digest with `authenticate`, unless the client is locked out, and records the outcome under the same lock,
+    /// so that concurrent connections of a client don't get more attempts than `max_auth_failures`.
+    pub fn attempt_auth<R>(&self, address: &IpAddr, authenticate: impl FnOnce() -> Option<R>) -> Result<R, BridgeAccessDenial> {
+        let mut clients = self.clients.lock().map_err(|_| BridgeAccessDenial::LockedOut)?;
+        let now = Instant::now();
+
+        if clients.get(address).and_then(|client| client.locked_until).is_some_and(|locked_until| locked_until > now) {
+            return Err(BridgeAccessDenial::LockedOut);
+        }
+
+        match authenticate() {
+            Some(authenticated) => {
+                clients.remove(address);
+                Ok(authenticated)
+            },
+            None => {
+                self.record_auth_failure(&mut clients, address, now);
+                Err(BridgeAccessDenial::AuthFailed)
+            }
+        }
+    }
+
+    /// Locks the client out after `max_auth_failures` consecutive failures.
+    /// Each lockout lasts twice as long as the previous one, up to `MAX_LOCKOUT`.
+    /// Clients are forgotten once neither locked out nor failing for `MAX_LOCKOUT`.
+    fn record_auth_failure(&self, clients: &mut HashMap<IpAddr, BridgeAccessClient>, address: &IpAddr, now: Instant) {
+        clients.retain(|_, client| client.locked_until.is_some_and(|locked_until| locked_until > now)
+            || client.last_failure.is_some_and(|last_failure| now.duration_since(last_failure) < MAX_LOCKOUT));
+
+        let client = clients.entry(*address).or_default();
+        client.auth_failures += 1;
+        client.last_failure = Some(now);
+
+        if client.auth_failures >= self.max_auth_failures {
+            let lockout = self.lockout.checked_mul(1 << client.lockouts.min(16)).unwrap_or(MAX_LOCKOUT).min(MAX_LOCKOUT);
+
+            warn!(
+                "Projector {}: Too many authentication failures, locking out client {} for {} seconds",
+                self.projector_name,
+                address,
+                lockout.as_secs()
+            );
+
+            client.auth_failures = 0;
+            client.lockouts += 1;
+            client.locked_until = Some(now + lockout);
+        }
+    }
+}
+
+/// What the access server needs from the projector handler.
+pub trait BridgeAccessHandler {
+    /// Password a new client must authenticate with, and the credentials it grants.
+    fn client_password(&self) -> Option<(String, PjLinkRS232Credentials)>;
+    fn handle_client_command(&mut self, client: &PjLinkRS232Client, request_body: [u8; 5], request_parameter: &[u8]) -> PjLinkResponse;
+}
+
+/// PJLink TCP server of every projector, used instead of the pjlink_bridge listener, which doesn't expose
+/// client addresses to its handler nor checks more than one password.
+/// Applies allow and deny lists to connections and commands, authenticates clients and locks them out
+/// after repeated authentication failures, then hands commands over to the projector handler.
+pub struct BridgeAccessServer {
+}
+
+impl BridgeAccessServer {
+    /// Once `stopping` is set, the server stops accepting connections and closes the open ones.
+    pub fn spawn<T: BridgeAccessHandler + Send + 'static>(
+        projector_name: String,
+        listener: TcpListener,
+        policy: Arc<BridgeAccessPolicy>,
+        credentials: Option<BridgeProjectorCredentials>,
+        shared_handler: Arc<Mutex<T>>,
+        events: Arc<BridgeProjectorEvents>,
+        stopping: Arc<AtomicBool>
+    ) -> Result<JoinHandle<()>, Error> {
+        if let Ok(listen_address) = listener.local_addr() {
+            info!("Projector {}: Listening for PJLink clients on {}", projector_name, listen_address);
+        }
+
+        listener.set_nonblocking(true)
+            .map_err(|err| Error {message: format!("Cannot set up PJLink listener. {}", err)})?;
+
+        thread::Builder::new()
+            .name(format!("access server {}", projector_name))
+            .spawn(move || {
+                let connections = Arc::new(BridgeAccessConnections::default());
+
+                while !stopping.load(Ordering::Relaxed) {
+                    let (stream, peer) = match listener.accept() {
+                        Ok(accepted) => accepted,
+                        Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                            thread::sleep(ACCEPT_POLL_INTERVAL);
+                            continue;
+                        },
+                        Err(err) => {
+                            error!("Projector {}: Cannot accept PJLink connection. {}", policy.projector_name, err);
+                            continue;
+                        }
+                    };
+
+                    let pjlink_client = PjLinkRS232Client {
+                        connection_id: NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed),
+                        address: Some(peer.ip()),
+                        credentials: PjLinkRS232Credentials::None,
+                        credential: None,
+                    };
+
+                    let registered = match (stream.set_nonblocking(false).and_then(|_| stream.try_clone()), connections.lock()) {
+                        (Ok(connection), Ok(mut connections)) if connections.len() < MAX_CONNECTIONS => {
+                            connections.insert(pjlink_client.connection_id, connection);
+                            true
+                        },
+                        (Ok(_), Ok(_)) => {
+                            info!("Projector {}: Rejected connection from {}, too many connections", policy.projector_name, peer.ip());
+                            Self::publish_denial(&events, &pjlink_client, false, BridgeAccessDenial::TooManyConnections, None);
+                            false
+                        },
+                        (Err(err), _) => {
+                            error!("Projector {}: Cannot set up PJLink connection. {}", policy.projector_name, err);
+                            false
+                        },
+                        (_, Err(_)) => false
+                    };
+
+                    if !registered {
+                        stream.shutdown(Shutdown::Both).unwrap_or_default();
+                        continue;
+                    }
+
+                    let policy = policy.clone();
+                    let credentials = credentials.clone();
+                    let shared_handler = shared_handler.clone();
+                    let events = events.clone();
+                    let connections = connections.clone();
+                    let stopping = stopping.clone();
+
+                    thread::spawn(move || {
+                        let connection_id = pjlink_client.connection_id;
+                        Self::handle_client(stream, pjlink_client, &policy, credentials, &shared_handler, &events, &stopping);
+
+                        if let Some(connection) = connections.lock().ok().and_then(|mut connections| connections.remove(&connection_id)) {
+                            connection.shutdown(Shutdown::Both).unwrap_or_default();
+                        }
+                    });
+                }
+
+                // Clients blocked reading their next command see the connection close
+                if let Ok(connections) = connections.lock() {
+                    for connection in connections.values() {
+                        connection.shutdown(Shutdown::Both).unwrap_or_default();
+                    }
+                }
+
+                info!("Projector {}: Stopped accepting PJLink connections", policy.projector_name);
+            })
+            .map_err(|err| Error {message: format!("Cannot start access control thread. {}", err)})
+    }
+
+    fn handle_client<T: BridgeAccessHandler>(
+        mut client: TcpStream,
+        mut pjlink_client: PjLinkRS232Client,
+        policy: &BridgeAccessPolicy,
+        credentials: Option<BridgeProjectorCredentials>,
+        shared_handler: &Mutex<T>,
+        events: &BridgeProjectorEvents,
+        stopping: &AtomicBool
+    ) {
+        let address = match pjlink_client.address {
+            Some(address) => address,
+            None => return
+        };
+
+        if !policy.permits(&address) {
+            info!("Projector {}: Rejected connection from {}, not allowed", policy.projector_name, address);
+            Self::publish_denial(events, &pjlink_client, false, BridgeAccessDenial::NotAllowed, None);
+            return;
+        }
+
+        if policy.locked_until(&address).is_some() {
+            debug!("Projector {}: Rejected connection from {}, locked out", policy.projector_name, address);
+            Self::publish_denial(events, &pjlink_client, false, BridgeAccessDenial::LockedOut, None);
+            return;
+        }
+
+        // With credentials, the projector password isn't accepted from PJLink clients
+        let password = match &credentials {
+            Some(_) => None,
+            None => match shared_handler.lock() {
+                Ok(handler) => handler.client_password(),
+                Err(_) => {
+                    error!("Projector {}: Handler is unavailable, closing connection from {}", policy.projector_name, address);
+                    return;
+                }
+            }
+        };
+
+        let authenticates = credentials.is_some() || password.is_some();
+        let random = BridgeProjectorCredentials::challenge();
+        let mut query_only = false;
+
+        let greeting = if authenticates {format!("PJLINK 1 {}\r", random)} else {String::from("PJLINK 0\r")};
+
+        if client.set_read_timeout(Some(CLIENT_TIMEOUT)).is_err() || client.write_all(greeting.as_bytes()).is_err() {
+            return;
+        }
+
+        let mut client_reader = match client.try_clone() {
+            Ok(client_reader) => BufReader::new(client_reader),
+            Err(_) => return
+        };
+
+        let mut authenticated = !authenticates;
+
+        loop {
+            // The first line carries the authentication digest, and a line feed may be left over
+            // from the previous `\r\n` terminated command
+            let max_length = MAX_LINE_LENGTH + 1 + if authenticated {0} else {AUTH_DIGEST_LENGTH};
+
+            let line = match Self::read_line(&mut client_reader, max_length) {
+                Ok(Some(line)) => line,
+                Ok(None) => break,
+                Err(err) => {
+                    if err.kind() == ErrorKind::InvalidData {
+                        info!("Projector {}: Command line from {} is too long, closing connection", policy.projector_name, address);
+                    }
+                    break;
+                }
+            };
+
+            if stopping.load(Ordering::Relaxed) {
+                break;
+            }
+
+            let start = line.iter().position(|byte| !byte.is_ascii_whitespace()).unwrap_or(line.len());
+            let mut line = &line[start..];
+
+            if line.is_empty() {
+                continue;
+            }
+
+            if !authenticated {
+                // Lines too short for a digest are checked (and fail) like any other
+                let (digest, command) = Self::split_digest(line).unwrap_or(("", line));
+
+                // The lockout is checked again, other connections of the client may have failed since this one was accepted
+                let authentication = policy.attempt_auth(&address, || match (&credentials, &password) {
+                    (Some(credentials), _) => credentials.authenticate(&random, digest).map(|session| (
+                        match session.permission {
+                            BridgeCredentialPermission::Admin => PjLinkRS232Credentials::Admin,
+                            _ => PjLinkRS232Credentials::User
+                        },
+                        session.permission == BridgeCredentialPermission::Query,
+                        Some(session.name)
+                    )),
+                    (None, Some((password, credentials))) => Some((*credentials, false, None))
+                        .filter(|_| BridgeProjectorCredentials::digest(&random, password).eq_ignore_ascii_case(digest)),
+                    (None, None) => None
+                });
+
+                match authentication {
+                    Ok((credentials, query_credential, credential)) => {
+                        info!(
+                            "Projector {}: Client {} authenticated{}, ConnectionId: {}",
+                            policy.projector_name,
+                            address,
+                            credential.as_ref().map(|credential| format!(" as {}", credential)).unwrap_or_default(),
+                            pjlink_client.connection_id
+                        );
+
+                        pjlink_client.credentials = credentials;
+                        pjlink_client.credential = credential;
+                        query_only = query_credential;
+                        authenticated = true;
+                        line = command;
+                    },
+                    Err(denial) => {
+                        if denial == BridgeAccessDenial::LockedOut {
+                            debug!("Projector {}: Rejected authentication from {}, locked out", policy.projector_name, address);
+                        } else {
+                            info!("Projector {}: Authentication failed for client {}", policy.projector_name, address);
+                        }
+
+                        Self::publish_denial(events, &pjlink_client, false, denial, Some(command));
+                        client.write_all(b"PJLINK ERRA\r").unwrap_or_default();
+                        break;
+                    }
+                }
+            }
+
+            // Lines that don't parse can't be checked against the rules, so they never reach the handler
+            let (class, command, request_parameter) = match Self::parse_command(line) {
+                Some(parsed_command) => parsed_command,
+                None => {
+                    debug!("Projector {}: Dropped invalid command line from {}", policy.projector_name, address);
+                    continue;
+                }
+            };
+
+            let query = request_parameter == b"?";
+
+            let response = if (query_only && !query) || !policy.permits_command(&address, &command, query) {
+                info!(
+                    "Projector {}: Rejected command {} from {}, not allowed",
+                    policy.projector_name,
+                    command,
+                    address
+                );
+
+                Self::publish_denial(events, &pjlink_client, authenticates, BridgeAccessDenial::CommandNotAllowed, Some(line));
+                Vec::from(&b"ERRA"[..])
+            } else {
+                let mut request_body = [class as u8; 5];
+                request_body[1..].copy_from_slice(command.as_bytes());
+
+                match shared_handler.lock() {
+                    Ok(mut handler) => Self::response_payload(&handler.handle_client_command(&pjlink_client, request_body, request_parameter)),
+                    Err(_) => {
+                        error!("Projector {}: Handler is unavailable, closing connection from {}", policy.projector_name, address);
+                        break;
+                    }
+                }
+            };
+
+            let mut reply = format!("%{}{}=", class, command).into_bytes();
+            reply.extend_from_slice(&response);
+            reply.push(b'\r');
+
+            if client.write_all(&reply).is_err() {
+                break;
+            }
+        }
+    }
+
+    /// Publishes an `access_denied` event, which the audit log records, with the command line that was refused (if any).
+    fn publish_denial(
+        events: &BridgeProjectorEvents,
+        client: &PjLinkRS232Client,
+        authenticated: bool,
+        denial: BridgeAccessDenial,
+        line: Option<&[u8]>
+    ) {
+        let command = line.and_then(Self::parse_command);
+
+        events.publish(BridgeEventKind::AccessDenied {
+            connection_id: client.connection_id,
+            client_address: client.address.map(|address| address.to_string()).unwrap_or_default(),
+            authenticated,
+            credential: client.credential.clone(),
+            reason: denial.reason(),
+            pjlink_command: command.as_ref().map(|(class, command, _)| format!("{}{}", class, command)),
+            tx_param: command.as_ref().map(|(_, _, request_parameter)| String::from_utf8_lossy(request_parameter).into_owned()),
+        });
+    }
+
+    /// Reads a line up to `\r`, which isn't returned. `None` at the end of the stream.
+    /// Lines of more than `max_length` bytes (with the `\r`) are an `InvalidData` error.
+    fn read_line(reader: &mut impl BufRead, max_length: usize) -> io::Result<Option<Vec<u8>>> {
+        let mut line = Vec::new();
+        let length = reader.by_ref().take(max_length as u64).read_until(b'\r', &mut line)?;
+
+        if line.last() == Some(&b'\r') {
+            line.pop();
+        } else if length == max_length {
+            return Err(io::Error::new(ErrorKind::InvalidData, "Line too long"));
+        }
+
+        Ok(Some(line).filter(|_| length > 0))
+    }
+
+    /// Splits the first command line into the authentication digest and the command.
+    fn split_digest(line: &[u8]) -> Option<(&str, &[u8])> {
+        let digest = std::str::from_utf8(line.get(..AUTH_DIGEST_LENGTH)?).ok()?;
+
+        Some((digest, &line[AUTH_DIGEST_LENGTH..]))
+    }
+
+    /// Splits a PJLink command line (e.g. `%1POWR 1`) into class, command and parameter.
+    /// Returns `None` for anything else.
+    fn parse_command(line: &[u8]) -> Option<(char, String, &[u8])> {
+        // `%`, class digit, 4 character command and the separator before the parameter
+        if line.len() < 8 || line[0] != b'%' || !line[1].is_ascii_digit()
+            || !line[2..6].iter().all(u8::is_ascii_alphanumeric) || line[6] != b' ' {
+            return None;
+        }
+
+        let class = line[1] as char;
+        let command = String::from_utf8_lossy(&line[2..6]).to_ascii_uppercase();
+
+        Some((class, command, &line[7..]))
+    }
+
+    /// Response as sent to the client, after `%1POWR=`.
+    #[inline(always)]
+    fn response_payload(response: &PjLinkResponse) -> Vec<u8> {
+        match response {
+            PjLinkResponse::Single(value) => vec! [*value],
+            PjLinkResponse::Multiple(value) => value.clone(),
+            _ => PjLinkRS232Projector::response_text(response).into_bytes()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{io::Read, net::{SocketAddr, TcpListener}};
+
+    use crate::{rs232_bridge_credentials::BridgeCredentials, rs232_bridge_events::BridgeEvents};
+
+    /// Answers every command with `0`, recording what it was sent.
+    #[derive(Default)]
+    struct TestHandler {
+        password: Option<String>,
+        commands: Vec<(u64, String, String, Option<String>)>,
+    }
+
+    impl BridgeAccessHandler for TestHandler {
+        fn client_password(&self) -> Option<(String, PjLinkRS232Credentials)> {
+            self.password.clone().map(|password| (password, PjLinkRS232Credentials::User))
+        }
+
+        fn handle_client_command(&mut self, client: &PjLinkRS232Client, request_body: [u8; 5], request_parameter: &[u8]) -> PjLinkResponse {
+            self.commands.push((
+                client.connection_id,
+                String::from_utf8_lossy(&request_body).into_owned(),
+                String::from_utf8_lossy(request_parameter).into_owned(),
+                client.credential.clone()
+            ));
+
+            PjLinkResponse::Single(b'0')
+        }
+    }
+
+    fn spawn_server(
+        access: BridgeConfigAccess,
+        credentials: Option<BridgeProjectorCredentials>,
+        handler: TestHandler
+    ) -> (SocketAddr, Arc<Mutex<TestHandler>>) {
+        let (address, shared_handler, _) = spawn_server_with_events(access, credentials, handler, Arc::default(), Arc::default());
+
+        (address, shared_handler)
+    }
+
+    fn spawn_server_with_events(
+        access: BridgeConfigAccess,
+        credentials: Option<BridgeProjectorCredentials>,
+        handler: TestHandler,
+        events: Arc<BridgeEvents>,
+        stopping: Arc<AtomicBool>
+    ) -> (SocketAddr, Arc<Mutex<TestHandler>>, JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let shared_handler = Arc::new(Mutex::new(handler));
+        let events = Arc::new(events.for_projector(String::from("test")));
+
+        let policy = Arc::new(BridgeAccessPolicy::from_config(String::from("test"), &access).ok().unwrap());
+
+        let server = BridgeAccessServer::spawn(String::from("test"), listener, policy, credentials, shared_handler.clone(), events, stopping)
+            .ok().unwrap();
+
+        (address, shared_handler, server)
+    }
+
+    fn read_line(stream: &mut TcpStream) -> String {
+        let mut line = Vec::new();
+        let mut byte = [0u8; 1];
+
+        while stream.read(&mut byte).unwrap() == 1 && byte[0] != b'\r' {
+            line.push(byte[0]);
+        }
+
+        String::from_utf8(line).unwrap()
+    }
+
+    fn connect(address: SocketAddr) -> (TcpStream, String) {
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let greeting = read_line(&mut stream);
+
+        (stream, greeting)
+    }
+
+    fn policy(rules: Vec<BridgeAccessRule>, max_auth_failures: u32, lockout: Duration) -> BridgeAccessPolicy {
+        BridgeAccessPolicy {
+            projector_name: String::from("test"),
+            list: BridgeAccessList {allow: Vec::new(), deny: Vec::new()},
+            rules,
+            max_auth_failures,
+            lockout,
+            clients: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn rule(commands: &[&str], queries: bool, allow: &[&str], deny: &[&str]) -> BridgeAccessRule {
+        let networks = |networks: &[&str]| networks.iter().map(|network| network.to_string()).collect::<Vec<_>>();
+
+        BridgeAccessRule {
+            commands: networks(commands),
+            queries,
+            list: BridgeAccessList::parse(&networks(allow), &networks(deny)).ok().unwrap(),
+        }
+    }
+
+    #[test]
+    fn parses_command_lines() {
+        assert_eq!(BridgeAccessServer::parse_command(b"%1POWR 1"), Some(('1', String::from("POWR"), &b"1"[..])));
+        assert_eq!(BridgeAccessServer::parse_command(b"%2inpt ?"), Some(('2', String::from("INPT"), &b"?"[..])));
+        assert_eq!(BridgeAccessServer::parse_command(b"%1AVMT 31"), Some(('1', String::from("AVMT"), &b"31"[..])));
+    }
+
+    #[test]
+    fn rejects_invalid_command_lines() {
+        assert_eq!(BridgeAccessServer::parse_command(b""), None);
+        assert_eq!(BridgeAccessServer::parse_command(b"POWR 1"), None);
+        assert_eq!(BridgeAccessServer::parse_command(b"x%1POWR 1"), None);
+        assert_eq!(BridgeAccessServer::parse_command(b"%1POWR"), None);
+        assert_eq!(BridgeAccessServer::parse_command(b"%1POWR1"), None);
+        assert_eq!(BridgeAccessServer::parse_command(b"%XPOWR 1"), None);
+        assert_eq!(BridgeAccessServer::parse_command(b"%1PO R 1"), None);
+        assert_eq!(BridgeAccessServer::parse_command(b"0123456789abcdef0123456789abcdef%1POWR 1"), None);
+    }
+
+    #[test]
+    fn splits_authentication_digests() {
+        assert_eq!(
+            BridgeAccessServer::split_digest(b"0123456789abcdef0123456789abcdef%1POWR ?"),
+            Some(("0123456789abcdef0123456789abcdef", &b"%1POWR ?"[..]))
+        );
+        assert_eq!(BridgeAccessServer::split_digest(b"%1POWR ?"), None);
+    }
+
+    #[test]
+    fn authenticates_with_the_projector_password() {
+        let handler = TestHandler {password: Some(String::from("secret")), ..TestHandler::default()};
+        let (address, shared_handler) = spawn_server(BridgeConfigAccess::default(), None, handler);
+
+        let (mut stream, greeting) = connect(address);
+        let random = greeting.strip_prefix("PJLINK 1 ").unwrap();
+        let digest = BridgeProjectorCredentials::digest(random, "secret");
+
+        stream.write_all(format!("{}%1POWR ?\r", digest).as_bytes()).unwrap();
+        assert_eq!(read_line(&mut stream), "%1POWR=0");
+
+        // Later commands don't carry the digest
+        stream.write_all(b"%1INPT ?\r").unwrap();
+        assert_eq!(read_line(&mut stream), "%1INPT=0");
+
+        let commands = &shared_handler.lock().unwrap().commands;
+        assert_eq!(commands.len(), 2);
+        assert_eq!(commands[0].1, "1POWR");
+        assert_eq!(commands[1].1, "1INPT");
+    }
+
+    #[test]
+    fn stops_accepting_connections() {
+        let stopping = Arc::new(AtomicBool::new(false));
+        let (address, _, server) = spawn_server_with_events(
+            BridgeConfigAccess::default(), None, TestHandler::default(), Arc::default(), stopping.clone()
+        );
+
+        let (mut stream, greeting) = connect(address);
+        assert_eq!(greeting, "PJLINK 0");
+
+        stopping.store(true, Ordering::Relaxed);
+        server.join().unwrap();
+
+        // The open connection is closed and the port no longer accepts connections
+        assert_eq!(read_line(&mut stream), "");
+        assert!(TcpStream::connect(address).is_err());
+    }
+
+    #[test]
+    fn rejects_unauthenticated_loopback_clients() {
+        let handler = TestHandler {password: Some(String::from("secret")), ..TestHandler::default()};
+        let (address, shared_handler) = spawn_server(BridgeConfigAccess::default(), None, handler);
+
+        // Without the digest
+        let (mut stream, _) = connect(address);
+        stream.write_all(b"%1POWR 1\r").unwrap();
+        assert_eq!(read_line(&mut stream), "PJLINK ERRA");
+
+        // With a digest of another password
+        let (mut stream, greeting) = connect(address);
+        let digest = BridgeProjectorCredentials::digest(greeting.strip_prefix("PJLINK 1 ").unwrap(), "guess");
+        stream.write_all(format!("{}%1POWR 1\r", digest).as_bytes()).unwrap();
+        assert_eq!(read_line(&mut stream), "PJLINK ERRA");
+
+        assert!(shared_handler.lock().unwrap().commands.is_empty());
+    }
+
+    #[test]
+    fn authenticates_with_credentials() {
+        let credentials_path = std::env::temp_dir().join(format!("pjlink-bridge-credentials-{}.toml", BridgeProjectorCredentials::challenge()));
+        std::fs::write(&credentials_path, "[[credentials]]\nname = \"operator\"\npassword = \"query-secret\"\npermission = \"query\"\n").unwrap();
+        let credentials = Arc::new(BridgeCredentials::from_file(credentials_path.to_string_lossy().into_owned()).ok().unwrap());
+        std::fs::remove_file(&credentials_path).unwrap_or_default();
+
+        // The projector password isn't accepted when credentials are set
+        let handler = TestHandler {password: Some(String::from("secret")), ..TestHandler::default()};
+        let (address, shared_handler) = spawn_server(BridgeConfigAccess::default(), Some(credentials.for_projector(String::from("test"))), handler);
+
+        let (mut stream, greeting) = connect(address);
+        let digest = BridgeProjectorCredentials::digest(greeting.strip_prefix("PJLINK 1 ").unwrap(), "secret");
+        stream.write_all(format!("{}%1POWR ?\r", digest).as_bytes()).unwrap();
+        assert_eq!(read_line(&mut stream), "PJLINK ERRA");
+
+        let (mut stream, greeting) = connect(address);
+        let digest = BridgeProjectorCredentials::digest(greeting.strip_prefix("PJLINK 1 ").unwrap(), "query-secret");
+        stream.write_all(format!("{}%1POWR ?\r", digest).as_bytes()).unwrap();
+        assert_eq!(read_line(&mut stream), "%1POWR=0");
+
+        // Query credentials can't send set commands
+        stream.write_all(b"%1POWR 1\r").unwrap();
+        assert_eq!(read_line(&mut stream), "%1POWR=ERRA");
+
+        let commands = &shared_handler.lock().unwrap().commands;
+        assert_eq!(commands.len(), 1);
+        assert_eq!(commands[0].3.as_deref(), Some("operator"));
+    }
+
+    #[test]
+    fn drops_invalid_lines_and_applies_rules() {
+        let access = BridgeConfigAccess {
+            rules: vec! [crate::rs232_bridge_config::BridgeConfigAccessRule {
+                commands: vec! [String::from("POWR")],
+                queries: false,
+                allow: vec! [String::from("192.168.1.0/24")],
+                deny: vec! [],
+            }],
+            ..BridgeConfigAccess::default()
+        };
+        let (address, shared_handler) = spawn_server(access, None, TestHandler::default());
+
+        let (mut stream, greeting) = connect(address);
+        assert_eq!(greeting, "PJLINK 0");
+
+        stream.write_all(b"garbage\r\n%1POWR 1\r\n%1INPT 31\r").unwrap();
+        assert_eq!(read_line(&mut stream), "%1POWR=ERRA");
+        assert_eq!(read_line(&mut stream), "%1INPT=0");
+
+        let commands = &shared_handler.lock().unwrap().commands;
+        assert_eq!(commands.len(), 1);
+        assert_eq!((commands[0].1.as_str(), commands[0].2.as_str()), ("1INPT", "31"));
+    }
+
+    #[test]
+    fn locks_out_clients_after_authentication_failures() {
+        let access = BridgeConfigAccess {max_auth_failures: 2, ..BridgeConfigAccess::default()};
+        let handler = TestHandler {password: Some(String::from("secret")), ..TestHandler::default()};
+        let (address, _) = spawn_server(access, None, handler);
+
+        for _ in 0..2 {
+            let (mut stream, _) = connect(address);
+            stream.write_all(b"0123456789abcdef0123456789abcdef%1POWR ?\r").unwrap();
+            assert_eq!(read_line(&mut stream), "PJLINK ERRA");
+        }
+
+        // Locked out clients are disconnected before the greeting
+        let (_, greeting) = connect(address);
+        assert_eq!(greeting, "");
+    }
+
+    #[test]
+    fn publishes_access_denials() {
+        let access = BridgeConfigAccess {max_auth_failures: 1, ..BridgeConfigAccess::default()};
+        let handler = TestHandler {password: Some(String::from("secret")), ..TestHandler::default()};
+        let events = Arc::new(BridgeEvents::default());
+        let subscriber = events.subscribe();
+        let (address, _, _) = spawn_server_with_events(access, None, handler, events, Arc::default());
+
+        let (mut stream, _) = connect(address);
+        stream.write_all(b"0123456789abcdef0123456789abcdef%1POWR 1\r").unwrap();
+        assert_eq!(read_line(&mut stream), "PJLINK ERRA");
+
+        let (_, greeting) = connect(address);
+        assert_eq!(greeting, "");
+
+        let denials: Vec<_> = subscriber.try_iter()
+            .filter_map(|event| match &event.kind {
+                BridgeEventKind::AccessDenied {reason, pjlink_command, tx_param, ..} => Some((*reason, pjlink_command.clone(), tx_param.clone())),
+                _ => None
+            })
+            .collect();
+
+        assert_eq!(denials, vec! [
+            ("auth_failed", Some(String::from("1POWR")), Some(String::from("1"))),
+            ("locked_out", None, None),
+        ]);
+    }
+
+    #[test]
+    fn drops_clients_sending_long_lines() {
+        let (address, shared_handler) = spawn_server(BridgeConfigAccess::default(), None, TestHandler::default());
+
+        let (mut stream, _) = connect(address);
+        stream.write_all(format!("%1NAME {}\r", "a".repeat(128)).as_bytes()).unwrap();
+        assert_eq!(read_line(&mut stream), "%1NAME=0");
+
+        // The whole line is read before the connection closes, so that it isn't reset
+        stream.write_all(&[b'a'; MAX_LINE_LENGTH + 1]).unwrap();
+        assert_eq!(read_line(&mut stream), "");
+
+        assert_eq!(shared_handler.lock().unwrap().commands.len(), 1);
+    }
+
+    #[test]
+    fn matches_command_rules() {
+        let policy = policy(vec![
+            rule(&["POWR"], false, &["192.168.1.0/24"], &[]),
+            rule(&["*"], true, &[], &["10.0.0.1"]),
+        ], 3, Duration::from_secs(60));
+
+        let operator: IpAddr = "192.168.1.10".parse().unwrap();
+        let guest: IpAddr = "192.168.2.10".parse().unwrap();
+        let denied: IpAddr = "10.0.0.1".parse().unwrap();
+
+        assert!(policy.permits_command(&operator, "POWR", false));
+        assert!(policy.permits_command(&operator, "powr", false));
+        assert!(!policy.permits_command(&guest, "POWR", false));
+        // The POWR rule doesn't apply to queries
+        assert!(policy.permits_command(&guest, "POWR", true));
+        assert!(policy.permits_command(&guest, "INPT", false));
+        assert!(!policy.permits_command(&denied, "INPT", true));
+        assert!(!policy.permits_command(&denied, "NAME", false));
+    }
+
+    /// Ends the lockout of a client, as if it had expired.
+    fn expire_lockout(policy: &BridgeAccessPolicy, address: &IpAddr) {
+        if let Some(client) = policy.clients.lock().unwrap().get_mut(address) {
+            client.locked_until = Some(Instant::now());
+        }
+    }
+
+    #[test]
+    fn rejects_authentication_while_locked_out() {
+        let policy = policy(Vec::new(), 1, Duration::from_secs(60));
+        let address: IpAddr = "192.168.1.10".parse().unwrap();
+
+        assert_eq!(policy.attempt_auth(&address, || None::<()>), Err(BridgeAccessDenial::AuthFailed));
+
+        // Even with the right digest, and without counting as a failure
+        assert_eq!(policy.attempt_auth(&address, || Some(())), Err(BridgeAccessDenial::LockedOut));
+        assert_eq!(policy.clients.lock().unwrap()[&address].lockouts, 1);
+
+        expire_lockout(&policy, &address);
+        assert_eq!(policy.attempt_auth(&address, || Some(())), Ok(()));
+    }
+
+    #[test]
+    fn doubles_lockouts() {
+        let policy = policy(Vec::new(), 2, Duration::from_secs(60));
+        let address: IpAddr = "192.168.1.10".parse().unwrap();
+        let fail = |policy: &BridgeAccessPolicy| policy.attempt_auth(&address, || None::<()>).unwrap_err();
+        let lockout = |policy: &BridgeAccessPolicy| policy.locked_until(&address)
+            .map(|locked_until| (locked_until - Instant::now()).as_secs() + 1);
+
+        fail(&policy);
+        assert_eq!(lockout(&policy), None);
+
+        fail(&policy);
+        assert_eq!(lockout(&policy), Some(60));
+
+        expire_lockout(&policy, &address);
+        fail(&policy);
+        fail(&policy);
+        assert_eq!(lockout(&policy), Some(120));
+
+        expire_lockout(&policy, &address);
+        fail(&policy);
+        fail(&policy);
+        assert_eq!(lockout(&policy), Some(240));
+
+        expire_lockout(&policy, &address);
+        policy.attempt_auth(&address, || Some(())).unwrap();
+        assert!(policy.clients.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn caps_lockouts() {
+        let policy = policy(Vec::new(), 1, Duration::from_secs(12 * 60 * 60));
+        let address: IpAddr = "192.168.1.10".parse().unwrap();
+
+        for _ in 0..40 {
+            expire_lockout(&policy, &address);
+            policy.attempt_auth(&address, || None::<()>).unwrap_err();
+        }
+
+        let locked_until = policy.locked_until(&address).unwrap();
+        assert!(locked_until <= Instant::now() + MAX_LOCKOUT);
+        assert!(locked_until > Instant::now() + MAX_LOCKOUT - Duration::from_secs(60));
+    }
+}
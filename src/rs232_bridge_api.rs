@@ -0,0 +1,416 @@
+use std::{
+    collections::BTreeMap,
+    io::Read,
+    sync::{Arc, Mutex, atomic::{AtomicBool, Ordering}},
+    thread,
+    time::Duration
+};
+
+use log::{error, info, warn};
+use pjlink_bridge::PjLinkResponse;
+use serde::{Deserialize, Serialize};
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use crate::{
+    rs232_bridge::{PjLinkRS232Client, PjLinkRS232Credentials, PjLinkRS232Projector, PjLinkRS232ProjectorMode, PjLinkRS232ProjectorState, PjLinkRS232UsageCounter},
+    rs232_bridge_access::{BridgeAccessDenial, BridgeAccessPolicy}
+};
+
+/// Connection id used in logs for commands received through the HTTP API.
+pub const API_CONNECTION_ID: u64 = u64::MAX - 1;
+
+/// Request bodies are small JSON objects, larger ones are refused before they are read in full.
+const MAX_BODY_SIZE: u64 = 4096;
+
+/// How often the server checks whether the bridge is shutting down, while no request comes in.
+const REQUEST_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Serialize)]
+struct BridgeApiProjector {
+    name: String,
+    manufacturer_name: String,
+    product_name: String,
+    serial_number: String,
+    software_version: String,
+    class_type: String,
+    mode: PjLinkRS232ProjectorMode,
+    state: BridgeApiProjectorState,
+    usage: BridgeApiProjectorUsage,
+}
+
+/// Hours counted by the bridge, see `PjLinkRS232Projector::usage_hours`.
+#[derive(Serialize)]
+struct BridgeApiProjectorUsage {
+    lamp_hours: u64,
+    filter_hours: u64,
+}
+
+#[derive(Serialize)]
+struct BridgeApiProjectorState {
+    power: Option<String>,
+    input: Option<String>,
+    mute: Option<String>,
+    freeze: Option<String>,
+}
+
+#[derive(Serialize)]
+struct BridgeApiDefinition {
+    /// Supported transmission parameters, by command (e.g. `1POWR` => `["0", "1", "?"]`)
+    commands: BTreeMap<String, Vec<String>>,
+}
+
+#[derive(Deserialize)]
+struct BridgeApiCommandRequest {
+    value: String,
+}
+
+#[derive(Deserialize)]
+struct BridgeApiModeRequest {
+    value: PjLinkRS232ProjectorMode,
+}
+
+#[derive(Deserialize)]
+struct BridgeApiUsageResetRequest {
+    counter: PjLinkRS232UsageCounter,
+}
+
+#[derive(Serialize)]
+struct BridgeApiCommandResponse {
+    response: String,
+}
+
+#[derive(Serialize)]
+struct BridgeApiError {
+    error: String,
+}
+
+pub struct BridgeApiServer {
+}
+
+impl BridgeApiServer {
+    /// Serves the JSON control API of a projector. Requests must carry `Authorization: Bearer <token>`,
+    /// where token is `api_token` or, when not set, the projector password. No token or password disables authentication.
+    /// The admin password is accepted too, and is the only one accepted in maintenance mode or to switch modes.
+    /// Clients are subject to the projector access policy: allow and deny lists, command rules and lockouts.
+    /// Once `stopping` is set, the server stops accepting requests.
+    pub fn spawn(
+        projector_name: String,
+        listen_address: String,
+        api_token: Option<String>,
+        shared_handler: Arc<Mutex<PjLinkRS232Projector>>,
+        access_policy: Arc<BridgeAccessPolicy>,
+        stopping: Arc<AtomicBool>
+    ) {
+        let server = match Server::http(&listen_address) {
+            Ok(server) => server,
+            Err(err) => {
+                error!("Projector {}: Cannot start HTTP API listener on {}. {}", projector_name, listen_address, err);
+                return;
+            }
+        };
+
+        info!("Projector {}: HTTP API available at http://{}/projector", projector_name, listen_address);
+
+        thread::spawn(move || {
+            while !stopping.load(Ordering::Relaxed) {
+                let mut request = match server.recv_timeout(REQUEST_POLL_INTERVAL) {
+                    Ok(Some(request)) => request,
+                    Ok(None) => continue,
+                    Err(err) => {
+                        error!("Projector {}: Cannot receive HTTP API request. {}", projector_name, err);
+                        break;
+                    }
+                };
+
+                let response = Self::handle_request(&mut request, &shared_handler, &access_policy, api_token.as_deref());
+
+                if let Err(err) = request.respond(response) {
+                    error!("Projector {}: Cannot send HTTP API response. {}", projector_name, err);
+                }
+            }
+
+            info!("Projector {}: Stopped accepting HTTP API requests", projector_name);
+        });
+    }
+
+    /// Checks the client address and token, then reads the body, before locking the handler,
+    /// which is only held to handle the request.
+    fn handle_request(
+        request: &mut Request,
+        shared_handler: &Mutex<PjLinkRS232Projector>,
+        access_policy: &BridgeAccessPolicy,
+        api_token: Option<&str>
+    ) -> Response<std::io::Cursor<Vec<u8>>> {
+        let address = request.remote_addr().ip();
+
+        if !access_policy.permits(&address) {
+            info!("HTTP API request rejected, not allowed. Client: {}", address);
+            return Self::json_response(403, &BridgeApiError {error: String::from("Forbidden")});
+        }
+
+        let authorization = request.headers().iter()
+            .find(|header| header.field.equiv("Authorization"))
+            .map(|header| header.value.as_str().to_string())
+            .unwrap_or_default();
+
+        // Passwords change on reload, so they are read on every request
+        let (expected_token, admin_token, mode) = match shared_handler.lock() {
+            Ok(handler) => (
+                api_token.map(String::from).or_else(|| handler.options().password.clone()),
+                handler.options().admin_password.clone(),
+                handler.mode()
+            ),
+            Err(_) => return Self::json_response(503, &BridgeApiError {error: String::from("Projector handler is unavailable")})
+        };
+
+        // Without an admin password nobody could get through, so maintenance mode holds no request back
+        let maintenance = mode == PjLinkRS232ProjectorMode::Maintenance && admin_token.is_some();
+
+        // Tokens are checked like PJLink digests, counting failures towards the same lockout
+        let authentication = if admin_token.is_none() && expected_token.is_none() {
+            Ok(PjLinkRS232Credentials::None)
+        } else {
+            access_policy.attempt_auth(&address, || {
+                if admin_token.is_some_and(|admin_token| Self::token_matches(&authorization, &admin_token)) {
+                    Some(PjLinkRS232Credentials::Admin)
+                } else {
+                    match &expected_token {
+                        Some(expected_token) => Some(PjLinkRS232Credentials::User).filter(|_| Self::token_matches(&authorization, expected_token)),
+                        None => Some(PjLinkRS232Credentials::None)
+                    }
+                }
+            })
+        };
+
+        let credentials = match authentication {
+            Ok(credentials) => credentials,
+            Err(BridgeAccessDenial::LockedOut) => {
+                info!("HTTP API request rejected, locked out. Client: {}", address);
+                return Self::json_response(429, &BridgeApiError {error: String::from("Too many authentication failures")});
+            },
+            Err(_) => {
+                warn!("HTTP API authentication failed. Client: {}", address);
+                return Self::json_response(401, &BridgeApiError {error: String::from("Unauthorized")});
+            }
+        };
+
+        if maintenance && credentials != PjLinkRS232Credentials::Admin {
+            return Self::json_response(503, &BridgeApiError {error: String::from("Projector is in maintenance mode")});
+        }
+
+        let body = match Self::read_body(request.as_reader()) {
+            Ok(body) => body,
+            Err(response) => return response
+        };
+
+        match shared_handler.lock() {
+            Ok(mut handler) => Self::handle_authenticated_request(request, &body, credentials, access_policy, &mut handler),
+            Err(_) => Self::json_response(503, &BridgeApiError {error: String::from("Projector handler is unavailable")})
+        }
+    }
+
+    fn handle_authenticated_request(
+        request: &Request,
+        body: &str,
+        credentials: PjLinkRS232Credentials,
+        access_policy: &BridgeAccessPolicy,
+        handler: &mut PjLinkRS232Projector
+    ) -> Response<std::io::Cursor<Vec<u8>>> {
+        let command_body = match request.url() {
+            "/projector/power" => Some(*b"1POWR"),
+            "/projector/input" => Some(*b"1INPT"),
+            "/projector/mute" => Some(*b"1AVMT"),
+            "/projector/freeze" => Some(*b"2FREZ"),
+            _ => None
+        };
+
+        match (request.method(), request.url(), command_body) {
+            (Method::Get, "/projector", _) => Self::json_response(200, &Self::describe_projector(handler)),
+            (Method::Get, "/projector/definition", _) => Self::json_response(200, &Self::describe_definition(handler)),
+            (Method::Post, "/projector/usage/reset", _) => {
+                if handler.options().admin_password.is_some() && credentials != PjLinkRS232Credentials::Admin {
+                    return Self::json_response(403, &BridgeApiError {error: String::from("Admin password required")});
+                }
+
+                let reset_request = match Self::parse_json::<BridgeApiUsageResetRequest>(body) {
+                    Ok(reset_request) => reset_request,
+                    Err(response) => return response
+                };
+
+                info!("HTTP API usage reset: Client: {}, Counter: {:?}", request.remote_addr(), reset_request.counter);
+
+                match handler.reset_usage(reset_request.counter) {
+                    Ok(()) => Self::json_response(200, &Self::describe_projector(handler)),
+                    Err(err) => Self::json_response(500, &BridgeApiError {error: err.message})
+                }
+            },
+            (Method::Post, "/projector/mode", _) => {
+                // Without an admin password, anyone allowed to send commands may switch modes
+                if handler.options().admin_password.is_some() && credentials != PjLinkRS232Credentials::Admin {
+                    return Self::json_response(403, &BridgeApiError {error: String::from("Admin password required")});
+                }
+
+                let mode_request = match Self::parse_json::<BridgeApiModeRequest>(body) {
+                    Ok(mode_request) => mode_request,
+                    Err(response) => return response
+                };
+
+                if mode_request.value == PjLinkRS232ProjectorMode::Maintenance && handler.options().admin_password.is_none() {
+                    return Self::json_response(400, &BridgeApiError {error: String::from("Maintenance mode requires an admin password")});
+                }
+
+                info!("HTTP API mode change: Client: {}, Mode: {:?}", request.remote_addr(), mode_request.value);
+
+                match handler.set_mode(mode_request.value) {
+                    Ok(()) => Self::json_response(200, &Self::describe_projector(handler)),
+                    Err(err) => Self::json_response(500, &BridgeApiError {error: err.message})
+                }
+            },
+            (Method::Post, _, Some(command_body)) => {
+                let command_request = match Self::parse_json::<BridgeApiCommandRequest>(body) {
+                    Ok(command_request) => command_request,
+                    Err(response) => return response
+                };
+
+                let command = std::str::from_utf8(&command_body[1..]).unwrap_or_default();
+
+                if !access_policy.permits_command(&request.remote_addr().ip(), command, command_request.value == "?") {
+                    info!("HTTP API command rejected, not allowed. Client: {}, Command: {}", request.remote_addr(), command);
+                    return Self::json_response(403, &BridgeApiError {error: String::from("Command not allowed")});
+                }
+
+                info!(
+                    "HTTP API command: Client: {}, Command: {}, Tx: {}",
+                    request.remote_addr(),
+                    std::str::from_utf8(&command_body).unwrap_or_default(),
+                    command_request.value
+                );
+
+                let client = PjLinkRS232Client {
+                    connection_id: API_CONNECTION_ID,
+                    address: Some(request.remote_addr().ip()),
+                    credentials,
+                    credential: None,
+                };
+
+                let response = handler.handle_client_command(&client, command_body, command_request.value.as_bytes());
+                let status_code = match response {
+                    PjLinkResponse::Undefined | PjLinkResponse::OutOfParameter => 400,
+                    PjLinkResponse::UnavailableTime => 503,
+                    PjLinkResponse::Ok | PjLinkResponse::Single(_) | PjLinkResponse::Multiple(_) => 200,
+                    _ => 502
+                };
+
+                Self::json_response(status_code, &BridgeApiCommandResponse {response: PjLinkRS232Projector::response_text(&response)})
+            },
+            _ => Self::json_response(404, &BridgeApiError {error: String::from("Not Found")})
+        }
+    }
+
+    fn describe_projector(handler: &mut PjLinkRS232Projector) -> BridgeApiProjector {
+        let (lamp_hours, filter_hours) = handler.usage_hours();
+        let options = handler.options();
+        let state = handler.state().lock().map(|state| state.clone()).unwrap_or_default();
+
+        BridgeApiProjector {
+            name: String::from_utf8_lossy(&options.projector_name).into_owned(),
+            manufacturer_name: String::from_utf8_lossy(&options.manufacturer_name).into_owned(),
+            product_name: String::from_utf8_lossy(&options.product_name).into_owned(),
+            serial_number: String::from_utf8_lossy(&options.serial_number).into_owned(),
+            software_version: String::from_utf8_lossy(&options.software_version).into_owned(),
+            class_type: String::from_utf8_lossy(&[options.class_type]).into_owned(),
+            mode: handler.mode(),
+            state: Self::describe_state(&state),
+            usage: BridgeApiProjectorUsage {lamp_hours, filter_hours},
+        }
+    }
+
+    fn describe_state(state: &PjLinkRS232ProjectorState) -> BridgeApiProjectorState {
+        let known = |value: &[u8]| if value.iter().all(u8::is_ascii_alphanumeric) {
+            Some(String::from_utf8_lossy(value).into_owned())
+        } else {None};
+
+        BridgeApiProjectorState {
+            power: known(&[state.power_on]),
+            input: known(&state.input_status),
+            mute: known(&state.mute_status),
+            freeze: known(&[state.freeze_status]),
+        }
+    }
+
+    fn describe_definition(handler: &PjLinkRS232Projector) -> BridgeApiDefinition {
+        let commands = handler.options().commands.iter()
+            .map(|(command_body, command_spec)| {
+                let mut inputs: Vec<String> = command_spec.inputs.keys()
+                    .map(|input| String::from_utf8_lossy(input).into_owned())
+                    .collect();
+                inputs.sort();
+
+                (String::from_utf8_lossy(command_body).into_owned(), inputs)
+            })
+            .collect();
+
+        BridgeApiDefinition {commands}
+    }
+
+    /// Reads up to `MAX_BODY_SIZE` bytes, larger bodies are refused with 413.
+    fn read_body(reader: impl Read) -> Result<String, Response<std::io::Cursor<Vec<u8>>>> {
+        let mut body = String::new();
+
+        if let Err(err) = reader.take(MAX_BODY_SIZE + 1).read_to_string(&mut body) {
+            return Err(Self::json_response(400, &BridgeApiError {error: err.to_string()}));
+        }
+
+        if body.len() as u64 > MAX_BODY_SIZE {
+            return Err(Self::json_response(413, &BridgeApiError {error: String::from("Request body too large")}));
+        }
+
+        Ok(body)
+    }
+
+    fn parse_json<T: serde::de::DeserializeOwned>(body: &str) -> Result<T, Response<std::io::Cursor<Vec<u8>>>> {
+        serde_json::from_str::<T>(body).map_err(|err| Self::json_response(400, &BridgeApiError {error: err.to_string()}))
+    }
+
+    /// Whether `authorization` is `Bearer <token>`. Takes the same time wherever the first mismatch is,
+    /// so response times don't give the token away.
+    /// Compares an `Authorization` header with the expected token in constant time.
+    pub fn token_matches(authorization: &str, token: &str) -> bool {
+        let expected = format!("Bearer {}", token);
+
+        authorization.len() == expected.len()
+            && authorization.bytes().zip(expected.bytes()).fold(0, |difference, (byte, expected_byte)| difference | (byte ^ expected_byte)) == 0
+    }
+
+    fn json_response<T: Serialize>(status_code: u16, body: &T) -> Response<std::io::Cursor<Vec<u8>>> {
+        let content_type = Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..]).unwrap();
+
+        Response::from_data(serde_json::to_vec(body).unwrap_or_default())
+            .with_status_code(status_code)
+            .with_header(content_type)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_bearer_tokens() {
+        assert!(BridgeApiServer::token_matches("Bearer secret", "secret"));
+        assert!(!BridgeApiServer::token_matches("Bearer secreT", "secret"));
+        assert!(!BridgeApiServer::token_matches("Bearer secret2", "secret"));
+        assert!(!BridgeApiServer::token_matches("Bearer secre", "secret"));
+        assert!(!BridgeApiServer::token_matches("secret", "secret"));
+        assert!(!BridgeApiServer::token_matches("", "secret"));
+    }
+
+    #[test]
+    fn refuses_large_bodies() {
+        let body = "a".repeat(MAX_BODY_SIZE as usize);
+        assert_eq!(BridgeApiServer::read_body(body.as_bytes()).ok(), Some(body.clone()));
+
+        let response = BridgeApiServer::read_body(format!("{}a", body).as_bytes()).err().unwrap();
+        assert_eq!(response.status_code().0, 413);
+    }
+}
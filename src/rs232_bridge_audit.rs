@@ -0,0 +1,188 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufRead, BufReader},
+    sync::{Arc, Mutex}
+};
+
+use chrono::{DateTime, Local, SecondsFormat};
+use log::error;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    rs232_bridge_config::BridgeConfigAudit,
+    rs232_bridge_events::{BridgeEvent, BridgeEventKind, BridgeEvents},
+    rs232_bridge_logger::BridgeLoggerFile
+};
+
+/// A state-changing command (any command with a transmission parameter other than `?`),
+/// or a client or command refused by access control.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct BridgeAuditRecord {
+    pub timestamp: String,
+    pub projector: String,
+    pub connection_id: u64,
+    /// `pjlink`, `http_api`, `mqtt`, `schedule`, `idle` or `bridge`
+    pub source: String,
+    /// Address of PJLink and HTTP API clients
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_address: Option<String>,
+    pub authenticated: bool,
+    /// Name of the credential the PJLink client authenticated with
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub credential: Option<String>,
+    pub pjlink_command: String,
+    pub tx_param: String,
+    /// Every frame written to the serial port while handling the command, hex encoded
+    pub serial_tx: Vec<String>,
+    pub response: String,
+    /// Why access control refused the client or command, see `BridgeEventKind::AccessDenied`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub denied: Option<String>,
+}
+
+/// Records matching every `Some` field.
+pub struct BridgeAuditFilter {
+    pub projector: Option<String>,
+    pub pjlink_command: Option<String>,
+    pub since: Option<DateTime<Local>>,
+    pub limit: Option<usize>,
+}
+
+pub struct Error {
+    pub message: String
+}
+
+/// Append-only audit log, written as JSON lines and rotated by size.
+/// Fed by the event bus, so it records exactly what PJLink clients, the HTTP API and MQTT have sent.
+pub struct BridgeAudit {
+    file: Mutex<BridgeLoggerFile>,
+    /// Command being handled, by projector. Commands of a single projector never overlap.
+    pending: Mutex<HashMap<String, BridgeAuditRecord>>,
+}
+
+impl BridgeAudit {
+    pub fn spawn(config: &BridgeConfigAudit, events: &BridgeEvents) -> Result<(), Error> {
+        let file = BridgeLoggerFile::open(config.file.clone(), config.max_file_size, config.max_files)
+            .map_err(|err| Error {message: format!("Cannot open audit log. {}", err.message)})?;
+
+        let audit = Arc::new(BridgeAudit {
+            file: Mutex::new(file),
+            pending: Mutex::new(HashMap::new()),
+        });
+
+        events.add_listener(Box::new(move |event| audit.handle_event(event)));
+        Ok(())
+    }
+
+    fn handle_event(&self, event: &BridgeEvent) {
+        let mut pending = match self.pending.lock() {
+            Ok(pending) => pending,
+            Err(_) => return
+        };
+
+        match &event.kind {
+            BridgeEventKind::Request {connection_id, source, client_address, authenticated, credential, pjlink_command, tx_param} if tx_param != "?" => {
+                pending.insert(event.projector.clone(), BridgeAuditRecord {
+                    timestamp: Local::now().to_rfc3339_opts(SecondsFormat::Millis, false),
+                    projector: event.projector.clone(),
+                    connection_id: *connection_id,
+                    source: String::from(*source),
+                    client_address: client_address.clone(),
+                    authenticated: *authenticated,
+                    credential: credential.clone(),
+                    pjlink_command: pjlink_command.clone(),
+                    tx_param: tx_param.clone(),
+                    serial_tx: vec! [],
+                    response: String::new(),
+                    denied: None,
+                });
+            },
+            BridgeEventKind::SerialTx {serial_tx, ..} => if let Some(record) = pending.get_mut(&event.projector) {
+                record.serial_tx.push(serial_tx.clone());
+            },
+            BridgeEventKind::Response {response, ..} => if let Some(mut record) = pending.remove(&event.projector) {
+                record.response = response.clone();
+                self.write(&record);
+            },
+            BridgeEventKind::AccessDenied {connection_id, client_address, authenticated, credential, reason, pjlink_command, tx_param} => self.write(&BridgeAuditRecord {
+                timestamp: Local::now().to_rfc3339_opts(SecondsFormat::Millis, false),
+                projector: event.projector.clone(),
+                connection_id: *connection_id,
+                source: String::from("pjlink"),
+                client_address: Some(client_address.clone()),
+                authenticated: *authenticated,
+                credential: credential.clone(),
+                pjlink_command: pjlink_command.clone().unwrap_or_default(),
+                tx_param: tx_param.clone().unwrap_or_default(),
+                serial_tx: vec! [],
+                // Refused commands and authentication attempts are answered with ERRA, refused connections are just closed
+                response: String::from(match *reason {"auth_failed" | "command_not_allowed" => "ERRA", _ => ""}),
+                denied: Some(String::from(*reason)),
+            }),
+            _ => {}
+        }
+    }
+
+    fn write(&self, record: &BridgeAuditRecord) {
+        match (serde_json::to_string(record), self.file.lock()) {
+            (Ok(line), Ok(mut file)) => file.write(&line),
+            (Err(err), _) => error!("Cannot write audit record. {}", err),
+            (_, Err(_)) => error!("Cannot write audit record, audit log is unavailable")
+        }
+    }
+
+    /// Reads records from the audit log and its rotated files, oldest first.
+    /// With a `limit`, only the most recent matching records are returned.
+    pub fn query(config: &BridgeConfigAudit, filter: &BridgeAuditFilter) -> Result<Vec<BridgeAuditRecord>, Error> {
+        let mut records = vec! [];
+
+        for path in BridgeLoggerFile::paths(&config.file, config.max_files).iter().rev() {
+            let file = File::open(path).map_err(|err| Error {message: format!("Cannot open audit log {}: {}", path, err)})?;
+
+            for line in BufReader::new(file).lines() {
+                let line = line.map_err(|err| Error {message: format!("Cannot read audit log {}: {}", path, err)})?;
+
+                match serde_json::from_str::<BridgeAuditRecord>(&line) {
+                    Ok(record) => if Self::matches(&record, filter) {
+                        records.push(record);
+                    },
+                    Err(err) => error!("Skipping invalid audit record in {}. {}", path, err)
+                }
+            }
+        }
+
+        if let Some(limit) = filter.limit {
+            let skipped = records.len().saturating_sub(limit);
+            records.drain(..skipped);
+        }
+
+        Ok(records)
+    }
+
+    fn matches(record: &BridgeAuditRecord, filter: &BridgeAuditFilter) -> bool {
+        if let Some(projector) = &filter.projector {
+            if &record.projector != projector {
+                return false;
+            }
+        }
+
+        if let Some(pjlink_command) = &filter.pjlink_command {
+            // Either with or without class, e.g. `POWR` or `1POWR`
+            if !record.pjlink_command.eq_ignore_ascii_case(pjlink_command)
+                && !record.pjlink_command.get(1..).is_some_and(|command| command.eq_ignore_ascii_case(pjlink_command))
+            {
+                return false;
+            }
+        }
+
+        if let Some(since) = &filter.since {
+            match DateTime::parse_from_rfc3339(&record.timestamp) {
+                Ok(timestamp) if timestamp >= *since => {},
+                _ => return false
+            }
+        }
+
+        true
+    }
+}
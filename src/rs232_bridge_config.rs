@@ -0,0 +1,354 @@
+use std::fs;
+use serde::Deserialize;
+
+/// Bridge configuration file, loaded with `--config`.
+/// Values set through environment variables or command line flags take precedence over it.
+#[derive(Deserialize, Default)]
+pub struct BridgeConfig {
+    pub verbose: Option<i32>,
+    #[serde(default)]
+    pub no_log: bool,
+    #[serde(default)]
+    pub log: BridgeConfigLog,
+    pub state_directory: Option<String>,
+    #[serde(default)]
+    pub watch_definitions: bool,
+    pub metrics_listen_address: Option<String>,
+    pub events_listen_address: Option<String>,
+    /// Token event stream clients must send, required unless listening on a loopback address
+    pub events_token: Option<String>,
+    pub audit: Option<BridgeConfigAudit>,
+    /// Named PJLink passwords, replacing the projector passwords for PJLink clients
+    pub credentials_file: Option<String>,
+    pub mqtt: Option<BridgeConfigMqtt>,
+    #[serde(default)]
+    pub schedules: Vec<BridgeConfigSchedule>,
+    #[serde(default)]
+    pub projectors: Vec<BridgeConfigProjector>
+}
+
+/// A projector exposed by the bridge: its definition, serial port and PJLink listener.
+#[derive(Deserialize, Clone)]
+pub struct BridgeConfigProjector {
+    pub name: String,
+    pub definition: String,
+    pub serial_port: String,
+    pub baud_rate: Option<u32>,
+    pub serial_number: Option<String>,
+    pub password: Option<String>,
+    pub password_file: Option<String>,
+    /// Password accepted in maintenance mode, also allowed to switch modes through the HTTP API
+    pub admin_password: Option<String>,
+    pub admin_password_file: Option<String>,
+    #[serde(default = "default_listen_address")]
+    pub listen_address: String,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    #[serde(default)]
+    pub udp: bool,
+    #[serde(default = "default_listen_address")]
+    pub udp_listen_address: String,
+    pub api_listen_address: Option<String>,
+    pub api_token: Option<String>,
+    pub access: Option<BridgeConfigAccess>,
+    pub idle: Option<BridgeConfigIdle>,
+    /// Name of the projector state file inside `state_directory`, the projector name when unset
+    pub state_id: Option<String>,
+}
+
+/// PJLink client access control, by address.
+#[derive(Deserialize, Clone, PartialEq)]
+pub struct BridgeConfigAccess {
+    /// Networks (CIDR) or addresses allowed to connect, every address when empty
+    #[serde(default)]
+    pub allow: Vec<String>,
+    /// Networks (CIDR) or addresses never allowed to connect
+    #[serde(default)]
+    pub deny: Vec<String>,
+    #[serde(default)]
+    pub rules: Vec<BridgeConfigAccessRule>,
+    /// Consecutive authentication failures before a client is locked out
+    #[serde(default = "default_access_max_auth_failures")]
+    pub max_auth_failures: u32,
+    /// First lockout duration (in seconds), doubled on every following lockout
+    #[serde(default = "default_access_lockout")]
+    pub lockout: u64,
+}
+
+/// Powers the projector off when left on without activity.
+#[derive(Deserialize, Clone, PartialEq)]
+pub struct BridgeConfigIdle {
+    /// Seconds powered on without set commands (nor input signal) before powering off
+    pub timeout: u64,
+    /// Seconds before powering off at which the `idle_warning` event is published
+    #[serde(default = "default_idle_warning")]
+    pub warning: u64,
+    /// Seconds between power state checks
+    #[serde(default = "default_idle_check_interval")]
+    pub check_interval: u64,
+}
+
+/// Restricts some commands to a subset of the allowed clients.
+#[derive(Deserialize, Clone, PartialEq)]
+pub struct BridgeConfigAccessRule {
+    /// Commands without class (e.g. `POWR`), or `*` for every command
+    pub commands: Vec<String>,
+    /// Whether queries (`?`) are restricted too, otherwise only set commands are
+    #[serde(default)]
+    pub queries: bool,
+    #[serde(default)]
+    pub allow: Vec<String>,
+    #[serde(default)]
+    pub deny: Vec<String>,
+}
+
+/// Log format and outputs.
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct BridgeConfigLog {
+    pub format: BridgeConfigLogFormat,
+    pub outputs: Vec<BridgeConfigLogOutput>,
+    /// Log file path, required by the `file` output
+    pub file: Option<String>,
+    /// Size (in bytes) a log file may reach before being rotated
+    pub max_file_size: u64,
+    /// Rotated log files kept next to the current one (`<file>.1`, `<file>.2`, ...)
+    pub max_files: usize,
+    pub syslog_socket: String,
+}
+
+#[derive(Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum BridgeConfigLogFormat {
+    Text,
+    Json
+}
+
+#[derive(Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum BridgeConfigLogOutput {
+    Stderr,
+    Syslog,
+    File
+}
+
+/// Audit log of state-changing commands.
+#[derive(Deserialize)]
+pub struct BridgeConfigAudit {
+    pub file: String,
+    /// Size (in bytes) the audit log may reach before being rotated
+    #[serde(default = "default_audit_max_file_size")]
+    pub max_file_size: u64,
+    /// Rotated audit logs kept next to the current one (`<file>.1`, `<file>.2`, ...)
+    #[serde(default = "default_audit_max_files")]
+    pub max_files: usize,
+}
+
+/// MQTT broker the projectors state is published to, with Home Assistant discovery.
+#[derive(Deserialize, Clone)]
+pub struct BridgeConfigMqtt {
+    pub host: String,
+    #[serde(default = "default_mqtt_port")]
+    pub port: u16,
+    #[serde(default = "default_mqtt_client_id")]
+    pub client_id: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    #[serde(default = "default_mqtt_topic_prefix")]
+    pub topic_prefix: String,
+    /// Home Assistant discovery prefix (usually `homeassistant`), discovery payloads aren't published when unset
+    pub discovery_prefix: Option<String>,
+    /// Interval (in seconds) between state queries sent to the projectors, queries aren't sent when unset
+    pub poll_interval: Option<u64>,
+}
+
+/// PJLink commands sent at cron times.
+#[derive(Deserialize, Clone)]
+pub struct BridgeConfigSchedule {
+    /// Name used in logs
+    pub name: Option<String>,
+    /// `minute hour day-of-month month day-of-week`, in local time
+    pub cron: String,
+    /// Commands with class and transmission parameter, e.g. `1POWR 0`
+    #[serde(default)]
+    pub actions: Vec<String>,
+    /// Minutes the scheduled event lasts from its cron time, during which the idle policy of its projectors is suspended
+    pub duration: Option<u64>,
+    /// Projector names, every projector when empty
+    #[serde(default)]
+    pub projectors: Vec<String>,
+}
+
+pub struct Error {
+    pub message: String
+}
+
+impl Default for BridgeConfigAccess {
+    fn default() -> Self {
+        BridgeConfigAccess {
+            allow: vec! [],
+            deny: vec! [],
+            rules: vec! [],
+            max_auth_failures: default_access_max_auth_failures(),
+            lockout: default_access_lockout(),
+        }
+    }
+}
+
+impl Default for BridgeConfigProjector {
+    fn default() -> Self {
+        BridgeConfigProjector {
+            name: String::new(),
+            definition: String::from("projector_info.toml"),
+            serial_port: String::new(),
+            baud_rate: None,
+            serial_number: None,
+            password: None,
+            password_file: None,
+            admin_password: None,
+            admin_password_file: None,
+            listen_address: default_listen_address(),
+            port: default_port(),
+            udp: false,
+            udp_listen_address: default_listen_address(),
+            api_listen_address: None,
+            api_token: None,
+            access: None,
+            idle: None,
+            state_id: None,
+        }
+    }
+}
+
+impl Default for BridgeConfigLog {
+    fn default() -> Self {
+        BridgeConfigLog {
+            format: BridgeConfigLogFormat::Text,
+            outputs: vec! [BridgeConfigLogOutput::Stderr],
+            file: None,
+            max_file_size: 10 * 1024 * 1024,
+            max_files: 5,
+            syslog_socket: String::from("/dev/log"),
+        }
+    }
+}
+
+impl BridgeConfigProjector {
+    /// Resolves the projector password, reading it from `password_file` when set.
+    /// Trailing line breaks are removed from the file contents.
+    pub fn resolve_password(&self) -> Result<Option<String>, Error> {
+        Self::read_password(&self.password, &self.password_file)
+    }
+
+    /// Resolves the admin password, reading it from `admin_password_file` when set.
+    pub fn resolve_admin_password(&self) -> Result<Option<String>, Error> {
+        Self::read_password(&self.admin_password, &self.admin_password_file)
+    }
+
+    #[inline(always)]
+    fn read_password(password: &Option<String>, password_file: &Option<String>) -> Result<Option<String>, Error> {
+        match password_file {
+            Some(password_file) => match fs::read_to_string(password_file) {
+                Ok(file_content) => Ok(Some(String::from(file_content.trim_end_matches(&['\r', '\n'][..])))),
+                Err(err) => Err(Error {message: format!("Cannot read password file {}: {}", password_file, err)})
+            },
+            None => Ok(password.clone())
+        }
+    }
+
+    /// Identifies the projector state file, so it follows the projector rather than its serial port.
+    pub fn state_id(&self) -> &str {
+        self.state_id.as_deref().unwrap_or(&self.name)
+    }
+
+    /// Whether settings only applied on start differ: listeners, HTTP API, access control, idle policy and state file.
+    pub fn requires_restart(&self, other: &BridgeConfigProjector) -> bool {
+        self.listen_address != other.listen_address || self.port != other.port
+            || self.udp != other.udp || self.udp_listen_address != other.udp_listen_address
+            || self.api_listen_address != other.api_listen_address || self.api_token != other.api_token
+            || self.access != other.access || self.idle != other.idle || self.state_id() != other.state_id()
+    }
+
+    /// Takes the settings applied on reload: definition, serial port, serial number and passwords.
+    pub fn apply_reloadable(&mut self, other: &BridgeConfigProjector) {
+        self.definition = other.definition.clone();
+        self.serial_port = other.serial_port.clone();
+        self.baud_rate = other.baud_rate;
+        self.serial_number = other.serial_number.clone();
+        self.password = other.password.clone();
+        self.password_file = other.password_file.clone();
+        self.admin_password = other.admin_password.clone();
+        self.admin_password_file = other.admin_password_file.clone();
+    }
+}
+
+impl BridgeConfig {
+    pub fn from_file(file_name: String) -> Result<BridgeConfig, Error> {
+        match fs::read_to_string(file_name) {
+            Ok(file_content) => Self::parse_content(file_content),
+            Err(err) => Err(Error {message: err.to_string()})
+        }
+    }
+
+    fn parse_content(file_content: String) -> Result<BridgeConfig, Error> {
+        match toml::from_str::<BridgeConfig>(&file_content) {
+            Ok(content) => Ok(content),
+            Err(err) => Err(Error {message: err.to_string()})
+        }
+    }
+}
+
+#[inline(always)]
+fn default_listen_address() -> String {
+    String::from("0.0.0.0")
+}
+
+#[inline(always)]
+fn default_port() -> u16 {
+    4352
+}
+
+#[inline(always)]
+fn default_mqtt_port() -> u16 {
+    1883
+}
+
+#[inline(always)]
+fn default_mqtt_client_id() -> String {
+    String::from("pjlink-rs232-bridge")
+}
+
+#[inline(always)]
+fn default_mqtt_topic_prefix() -> String {
+    String::from("pjlink")
+}
+
+#[inline(always)]
+pub fn default_audit_max_file_size() -> u64 {
+    50 * 1024 * 1024
+}
+
+#[inline(always)]
+pub fn default_audit_max_files() -> usize {
+    10
+}
+
+#[inline(always)]
+fn default_access_max_auth_failures() -> u32 {
+    5
+}
+
+#[inline(always)]
+fn default_access_lockout() -> u64 {
+    60
+}
+
+#[inline(always)]
+fn default_idle_warning() -> u64 {
+    300
+}
+
+#[inline(always)]
+fn default_idle_check_interval() -> u64 {
+    60
+}
@@ -0,0 +1,246 @@
+use std::{
+    fs,
+    sync::{Arc, Mutex}
+};
+
+use chrono::{DateTime, FixedOffset, Local};
+use log::warn;
+use serde::Deserialize;
+use uuid::Uuid;
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum BridgeCredentialPermission {
+    /// Queries only, set commands are answered with ERRA
+    Query,
+    /// Queries and set commands
+    Control,
+    /// Queries and set commands, also accepted in maintenance mode
+    Admin
+}
+
+#[derive(Deserialize)]
+struct BridgeCredentialsFile {
+    #[serde(default)]
+    credentials: Vec<BridgeCredentialsFileEntry>,
+}
+
+#[derive(Deserialize)]
+struct BridgeCredentialsFileEntry {
+    name: String,
+    password: String,
+    permission: BridgeCredentialPermission,
+    /// RFC 3339 timestamp
+    expires: Option<String>,
+    /// Projector names, every projector when empty
+    #[serde(default)]
+    projectors: Vec<String>,
+}
+
+struct BridgeCredential {
+    name: String,
+    password: String,
+    permission: BridgeCredentialPermission,
+    expires: Option<DateTime<FixedOffset>>,
+    projectors: Vec<String>,
+}
+
+/// Credential a PJLink connection authenticated with.
+#[derive(Clone)]
+pub struct BridgeCredentialSession {
+    pub name: String,
+    pub permission: BridgeCredentialPermission,
+}
+
+pub struct Error {
+    pub message: String
+}
+
+/// Named PJLink passwords loaded from the credentials file, shared by every projector.
+pub struct BridgeCredentials {
+    path: String,
+    credentials: Mutex<Vec<BridgeCredential>>,
+}
+
+impl BridgeCredentials {
+    pub fn from_file(path: String) -> Result<BridgeCredentials, Error> {
+        let credentials = Self::read_file(&path)?;
+
+        Ok(BridgeCredentials {
+            path,
+            credentials: Mutex::new(credentials),
+        })
+    }
+
+    /// Reads the credentials file again, keeping the current credentials when it is invalid.
+    /// Returns how many credentials were loaded.
+    pub fn reload(&self) -> Result<usize, Error> {
+        let credentials = Self::read_file(&self.path)?;
+        let count = credentials.len();
+
+        match self.credentials.lock() {
+            Ok(mut current_credentials) => *current_credentials = credentials,
+            Err(_) => return Err(Error {message: String::from("Credentials are unavailable")})
+        }
+
+        Ok(count)
+    }
+
+    fn read_file(path: &str) -> Result<Vec<BridgeCredential>, Error> {
+        let file_content = fs::read_to_string(path)
+            .map_err(|err| Error {message: format!("Cannot read credentials file {}: {}", path, err)})?;
+
+        let credentials_file = toml::from_str::<BridgeCredentialsFile>(&file_content)
+            .map_err(|err| Error {message: format!("Invalid credentials file {}: {}", path, err)})?;
+
+        credentials_file.credentials.into_iter()
+            .map(|entry| {
+                let expires = match &entry.expires {
+                    Some(expires) => Some(DateTime::parse_from_rfc3339(expires)
+                        .map_err(|err| Error {message: format!("Invalid expiry for credential {}: {}", entry.name, err)})?),
+                    None => None
+                };
+
+                Ok(BridgeCredential {
+                    name: entry.name,
+                    password: entry.password,
+                    permission: entry.permission,
+                    expires,
+                    projectors: entry.projectors,
+                })
+            })
+            .collect()
+    }
+
+    /// Credentials a projector accepts, expired ones included.
+    pub fn count_for(&self, projector_name: &str) -> usize {
+        self.credentials.lock()
+            .map(|credentials| credentials.iter().filter(|credential| credential.applies_to(projector_name)).count())
+            .unwrap_or_default()
+    }
+
+    /// Finds the credential whose password produces `digest` (`MD5(random + password)`, as sent by PJLink clients).
+    fn authenticate(&self, projector_name: &str, random: &str, digest: &str) -> Option<BridgeCredentialSession> {
+        let credentials = self.credentials.lock().ok()?;
+        let now = Local::now();
+
+        let credential = credentials.iter()
+            .filter(|credential| credential.applies_to(projector_name))
+            .find(|credential| BridgeProjectorCredentials::digest(random, &credential.password).eq_ignore_ascii_case(digest))?;
+
+        if credential.expires.is_some_and(|expires| expires <= now) {
+            warn!("Projector {}: Credential {} has expired", projector_name, credential.name);
+            return None;
+        }
+
+        Some(BridgeCredentialSession {
+            name: credential.name.clone(),
+            permission: credential.permission,
+        })
+    }
+
+    pub fn for_projector(self: &Arc<Self>, projector_name: String) -> BridgeProjectorCredentials {
+        BridgeProjectorCredentials {
+            projector_name,
+            credentials: self.clone(),
+        }
+    }
+}
+
+impl BridgeCredential {
+    #[inline(always)]
+    fn applies_to(&self, projector_name: &str) -> bool {
+        self.projectors.is_empty() || self.projectors.iter().any(|projector| projector == projector_name)
+    }
+}
+
+/// Credentials a projector accepts.
+#[derive(Clone)]
+pub struct BridgeProjectorCredentials {
+    projector_name: String,
+    credentials: Arc<BridgeCredentials>,
+}
+
+impl BridgeProjectorCredentials {
+    /// Random number sent in the `PJLINK 1` greeting.
+    pub fn challenge() -> String {
+        Uuid::new_v4().to_simple().to_string()[..8].to_string()
+    }
+
+    /// Digest PJLink clients send to authenticate, `MD5(random + password)` in hex.
+    pub fn digest(random: &str, password: &str) -> String {
+        format!("{:x}", md5::compute(format!("{}{}", random, password)))
+    }
+
+    pub fn authenticate(&self, random: &str, digest: &str) -> Option<BridgeCredentialSession> {
+        self.credentials.authenticate(&self.projector_name, random, digest)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn credential(name: &str, expires: Option<&str>, projectors: &[&str]) -> BridgeCredential {
+        BridgeCredential {
+            name: String::from(name),
+            password: format!("{}-secret", name),
+            permission: BridgeCredentialPermission::Control,
+            expires: expires.map(|expires| DateTime::parse_from_rfc3339(expires).unwrap()),
+            projectors: projectors.iter().map(|projector| projector.to_string()).collect(),
+        }
+    }
+
+    fn credentials(credentials: Vec<BridgeCredential>) -> Arc<BridgeCredentials> {
+        Arc::new(BridgeCredentials {
+            path: String::new(),
+            credentials: Mutex::new(credentials),
+        })
+    }
+
+    #[test]
+    fn computes_pjlink_digests() {
+        // Example from the PJLink specification
+        assert_eq!(BridgeProjectorCredentials::digest("498e4a67", "JBMIAProjectorLink"), "5d8409bc1c3fa39749434aa3a5c38682");
+    }
+
+    #[test]
+    fn authenticates_matching_digests() {
+        let credentials = credentials(vec! [credential("operator", None, &[]), credential("guest", None, &[])]);
+        let projector_credentials = credentials.for_projector(String::from("room-101"));
+
+        let digest = BridgeProjectorCredentials::digest("01234567", "guest-secret");
+        let session = projector_credentials.authenticate("01234567", &digest).unwrap();
+        assert_eq!(session.name, "guest");
+        assert_eq!(session.permission, BridgeCredentialPermission::Control);
+
+        // Digests are hex, in either case
+        assert!(projector_credentials.authenticate("01234567", &digest.to_ascii_uppercase()).is_some());
+        // Another random number
+        assert!(projector_credentials.authenticate("76543210", &digest).is_none());
+        assert!(projector_credentials.authenticate("01234567", "0123456789abcdef0123456789abcdef").is_none());
+    }
+
+    #[test]
+    fn rejects_expired_credentials() {
+        let credentials = credentials(vec! [
+            credential("expired", Some("2001-01-01T00:00:00+00:00"), &[]),
+            credential("valid", Some("2999-01-01T00:00:00+00:00"), &[]),
+        ]);
+        let projector_credentials = credentials.for_projector(String::from("room-101"));
+
+        assert!(projector_credentials.authenticate("01234567", &BridgeProjectorCredentials::digest("01234567", "expired-secret")).is_none());
+        assert!(projector_credentials.authenticate("01234567", &BridgeProjectorCredentials::digest("01234567", "valid-secret")).is_some());
+    }
+
+    #[test]
+    fn restricts_credentials_to_their_projectors() {
+        let credentials = credentials(vec! [credential("operator", None, &["room-101"])]);
+        let digest = BridgeProjectorCredentials::digest("01234567", "operator-secret");
+
+        assert!(credentials.for_projector(String::from("room-101")).authenticate("01234567", &digest).is_some());
+        assert!(credentials.for_projector(String::from("room-102")).authenticate("01234567", &digest).is_none());
+        assert_eq!(credentials.count_for("room-101"), 1);
+        assert_eq!(credentials.count_for("room-102"), 0);
+    }
+}
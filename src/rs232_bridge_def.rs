@@ -10,6 +10,8 @@ pub struct BridgeDefinition {
     pub connection: BridgeDefinitionConnection,
     pub resolution: Option<BridgeDefinitionResolution>,
     pub behavior: Option<BridgeDefinitionBehavior>,
+    #[serde(default)]
+    pub unsolicited: Vec<BridgeDefinitionUnsolicited>,
     #[serde(deserialize_with = "deserialize_bridge_commands")]
     pub commands: BridgeDefinitionCommandsMap
 }
@@ -20,6 +22,10 @@ pub struct BridgeDefinitionGeneral {
     pub product_name: String,
     pub software_version: String,
     pub class_type: u8,
+    /// Answers `RLMP ?` when the command map doesn't
+    pub lamp_replacement_model: Option<String>,
+    /// Answers `RFIL ?` when the command map doesn't
+    pub filter_replacement_model: Option<String>,
 }
 
 #[derive(Deserialize)]
@@ -29,7 +35,11 @@ pub struct BridgeDefinitionConnection {
     pub parity: Option<char>,
     pub stop_bits: Option<u8>,
     pub hardware_flow_control: Option<bool>,
-    pub software_flow_control: Option<bool>
+    pub software_flow_control: Option<bool>,
+    /// Minimum time (in ms) between a reply and the next command
+    pub command_gap: Option<u32>,
+    /// Time (in ms) waited after writing each byte
+    pub byte_delay: Option<u32>
 }
 
 #[derive(Deserialize)]
@@ -41,7 +51,85 @@ pub struct BridgeDefinitionResolution {
 #[derive(Deserialize)]
 pub struct BridgeDefinitionBehavior {
     pub send_on_start: Option<Vec<u8>>,
-    pub wait_for_response: Option<u32>
+    pub send_on_shutdown: Option<Vec<u8>>,
+    pub wait_for_response: Option<u32>,
+    /// What to do with input pending when a command is about to be sent
+    #[serde(default)]
+    pub stale_input: BridgeDefinitionStaleInput,
+    /// Retries of every command, unless overridden by the command or its input
+    pub retry: Option<BridgeDefinitionRetry>,
+    /// Checksum carried by the last byte of every projector reply
+    pub reply_checksum: Option<BridgeDefinitionReplyChecksum>,
+    /// Preamble sent before commands to projectors whose serial interface sleeps
+    pub wake: Option<BridgeDefinitionWake>
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct BridgeDefinitionWake {
+    #[serde(default)]
+    pub send: Vec<u8>,
+    /// Duration (in ms) of a serial BREAK sent before `send`, none when unset
+    pub break_duration: Option<u32>,
+    /// Wait (in ms) after the preamble, before the command is sent
+    #[serde(default)]
+    pub delay: u32,
+    /// Seconds without serial traffic after which the preamble is sent
+    pub idle_after: Option<u64>,
+    /// Whether the preamble is sent while the tracked power state is standby
+    #[serde(default)]
+    pub on_standby: bool
+}
+
+/// Input pending before a command is sent: late replies to earlier commands, noise or unsolicited frames.
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum BridgeDefinitionStaleInput {
+    #[default]
+    Discard,
+    /// Unsolicited frames found in it are handled, the rest is discarded
+    Unsolicited
+}
+
+/// How a command is sent again when the projector reply is missing or can't be used.
+#[derive(Deserialize, Clone, Debug)]
+pub struct BridgeDefinitionRetry {
+    #[serde(default)]
+    pub times: u32,
+    /// Wait (in ms) before the first retry, doubled before each of the following ones
+    #[serde(default)]
+    pub backoff: u32,
+    /// Outcomes retried, every one of them when unset
+    pub on: Option<Vec<BridgeDefinitionRetryOutcome>>
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum BridgeDefinitionRetryOutcome {
+    /// Nothing was received before `wait_for_response`
+    Empty,
+    /// The reply doesn't end with the `reply_checksum` of its other bytes
+    Checksum,
+    /// The reply matches no output
+    Unmatched
+}
+
+impl BridgeDefinitionRetryOutcome {
+    pub fn name(&self) -> &'static str {
+        match self {
+            BridgeDefinitionRetryOutcome::Empty => "empty",
+            BridgeDefinitionRetryOutcome::Checksum => "checksum",
+            BridgeDefinitionRetryOutcome::Unmatched => "unmatched",
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum BridgeDefinitionReplyChecksum {
+    /// Sum of the other bytes, modulo 256
+    Sum8,
+    /// Exclusive or of the other bytes
+    Xor8
 }
 
 #[derive(Deserialize)]
@@ -49,7 +137,11 @@ pub struct BridgeDefinitionBehavior {
 pub struct BridgeDefinitionCommand {
     #[serde(deserialize_with = "deserialize_bridge_command_definition")]
     pub inputs: BridgeDefinitionCommandDefinitionsMap,
-    pub wait_for_response: Option<u32>
+    pub wait_for_response: Option<u32>,
+    pub retry: Option<BridgeDefinitionRetry>,
+    /// Whether sending the command twice has the same effect as sending it once (always the case for queries).
+    /// Set commands are only retried when idempotent, so toggles are never sent again.
+    pub idempotent: Option<bool>
 }
 
 #[derive(Deserialize)]
@@ -57,8 +149,12 @@ pub struct BridgeDefinitionCommand {
 pub struct BridgeDefinitionCommandDefinition {
     pub send: Vec<u8>,
     pub send_times: Option<u32>,
+    // Accepted so existing definitions keep parsing, not used yet
+    #[allow(dead_code)]
     pub send_timeout: Option<u32>,
     pub wait_for_response: Option<u32>,
+    pub retry: Option<BridgeDefinitionRetry>,
+    pub idempotent: Option<bool>,
     pub outputs: Vec<BridgeDefinitionCommandDefinitionOutput>
 }
 
@@ -66,13 +162,31 @@ pub struct BridgeDefinitionCommandDefinition {
 #[serde(tag = "response_type", content = "response_value", rename_all = "lowercase")]
 pub enum BridgeDefinitionCommandDefinitionOutputResponse {
     Default(String),
-    Value(String)
+    Value(String),
+    Received(BridgeDefinitionCommandDefinitionOutputReceivedFormat)
 } 
 
+/// How the bytes received after a matched `prefix` are turned into a PJLink response value.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum BridgeDefinitionCommandDefinitionOutputReceivedFormat {
+    /// Bytes are sent as-is, with trailing NUL, CR and LF removed.
+    Ascii,
+    /// Bytes are sent as uppercase hexadecimal.
+    Hex,
+    /// Two 16-bit little endian integers (width, height), sent as `WxH`.
+    ResolutionLe,
+    /// Two 16-bit big endian integers (width, height), sent as `WxH`.
+    ResolutionBe
+}
+
 #[derive(Deserialize, Debug)]
 #[serde(tag = "on_received_type", content = "on_received", rename_all = "snake_case")]
 pub enum BridgeDefinitionCommandDefinitionOutputProjectorResponse {
     Value(Vec<u8>),
+    Prefix(Vec<u8>),
+    // Accepted so existing definitions keep parsing, not implemented yet
+    #[allow(dead_code)]
     RuleMap(
         BridgeDefinitionCommandDefinitionOutputProjectorResponseRuleMap,
         Vec<BridgeDefinitionProjectorResponseRuleMapLsbMsbAttribute>
@@ -86,6 +200,7 @@ pub enum BridgeDefinitionCommandDefinitionOutputProjectorResponseRuleMap {
 }
 
 #[derive(Deserialize, Debug)]
+#[allow(dead_code)]
 pub struct BridgeDefinitionProjectorResponseRuleMapLsbMsbAttribute {
     pub rule_type: String,
     pub value: Vec<u8>    
@@ -101,6 +216,20 @@ pub struct BridgeDefinitionCommandDefinitionOutput {
     pub response: BridgeDefinitionCommandDefinitionOutputResponse,
 }
 
+/// Frame the projector sends on its own (e.g. power state changed after a button press),
+/// kept apart from command replies.
+#[derive(Deserialize)]
+#[derive(Debug)]
+pub struct BridgeDefinitionUnsolicited {
+    /// Command whose state the frame updates (e.g. `1POWR`), the frame is only notified when unset
+    pub pjlink_command: Option<String>,
+    /// Bytes following a matched `prefix` that belong to the frame
+    #[serde(default)]
+    pub length: usize,
+    #[serde(flatten)]
+    pub output: BridgeDefinitionCommandDefinitionOutput,
+}
+
 pub struct Error {
     pub message: String
 }
@@ -0,0 +1,444 @@
+use std::{
+    io::ErrorKind,
+    net::{TcpListener, TcpStream},
+    sync::{Arc, Mutex, mpsc::{Receiver, RecvTimeoutError, SyncSender, TrySendError, sync_channel}},
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH}
+};
+
+use log::{debug, error, info};
+use serde::Serialize;
+use tungstenite::{
+    Message,
+    handshake::server::{ErrorResponse, Request, Response},
+    http::StatusCode
+};
+
+use crate::rs232_bridge_api::BridgeApiServer;
+
+/// Events queued per subscriber before new ones are dropped for it (e.g. a slow WebSocket client).
+const SUBSCRIBER_QUEUE_SIZE: usize = 256;
+
+/// How long a WebSocket client waits for events before its socket is read, to answer pings and close frames.
+const EVENT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long reading a WebSocket client socket may block, while it sent nothing.
+const CLIENT_READ_TIMEOUT: Duration = Duration::from_millis(10);
+
+#[derive(Serialize, Clone)]
+pub struct BridgeEvent {
+    pub projector: String,
+    /// Unix time, in ms
+    pub timestamp: u128,
+    #[serde(flatten)]
+    pub kind: BridgeEventKind,
+}
+
+#[derive(Serialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum BridgeEventKind {
+    /// Command received from a PJLink client, or from the bridge itself (HTTP API, MQTT)
+    Request {
+        connection_id: u64,
+        source: &'static str,
+        /// Address of PJLink and HTTP API clients
+        #[serde(skip_serializing_if = "Option::is_none")]
+        client_address: Option<String>,
+        authenticated: bool,
+        /// Name of the credential the PJLink client authenticated with
+        #[serde(skip_serializing_if = "Option::is_none")]
+        credential: Option<String>,
+        pjlink_command: String,
+        tx_param: String
+    },
+    /// Bytes written to the serial port, hex encoded
+    SerialTx {connection_id: u64, serial_tx: String},
+    /// Bytes read from the serial port, hex encoded
+    SerialRx {connection_id: u64, serial_rx: String, elapsed_ms: u32},
+    /// Response sent back to the command originator
+    Response {connection_id: u64, pjlink_command: String, response: String},
+    StateChanged {pjlink_command: String, value: String},
+    ConnectorError {message: String},
+    /// The idle policy is about to power the projector off
+    IdleWarning {power_off_in: u64},
+    /// Frame the projector sent on its own, hex encoded, with the value it translates to
+    Unsolicited {serial_rx: String, pjlink_command: Option<String>, value: String},
+    /// PJLink client or command refused by access control
+    AccessDenied {
+        connection_id: u64,
+        client_address: String,
+        authenticated: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        credential: Option<String>,
+        /// `not_allowed`, `locked_out`, `too_many_connections`, `auth_failed` or `command_not_allowed`
+        reason: &'static str,
+        /// Refused command, when the client sent one
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pjlink_command: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        tx_param: Option<String>
+    },
+}
+
+impl BridgeEventKind {
+    /// Name used in `type` filters, matching the serialized `type` field.
+    pub fn name(&self) -> &'static str {
+        match self {
+            BridgeEventKind::Request {..} => "request",
+            BridgeEventKind::SerialTx {..} => "serial_tx",
+            BridgeEventKind::SerialRx {..} => "serial_rx",
+            BridgeEventKind::Response {..} => "response",
+            BridgeEventKind::StateChanged {..} => "state_changed",
+            BridgeEventKind::ConnectorError {..} => "connector_error",
+            BridgeEventKind::IdleWarning {..} => "idle_warning",
+            BridgeEventKind::Unsolicited {..} => "unsolicited",
+            BridgeEventKind::AccessDenied {..} => "access_denied",
+        }
+    }
+
+    pub fn connection_id(&self) -> Option<u64> {
+        match self {
+            BridgeEventKind::Request {connection_id, ..}
+            | BridgeEventKind::SerialTx {connection_id, ..}
+            | BridgeEventKind::SerialRx {connection_id, ..}
+            | BridgeEventKind::Response {connection_id, ..}
+            | BridgeEventKind::AccessDenied {connection_id, ..} => Some(*connection_id),
+            _ => None
+        }
+    }
+}
+
+impl BridgeEvent {
+    /// Logs the event, with its fields attached as key-values (used by the JSON log format).
+    fn log(&self) {
+        let projector = self.projector.as_str();
+
+        match &self.kind {
+            BridgeEventKind::Request {connection_id, source, client_address, authenticated, credential, pjlink_command, tx_param} => debug!(
+                projector, connection_id = *connection_id, source = *source, client_address = client_address.as_deref().unwrap_or_default(),
+                authenticated = *authenticated, credential = credential.as_deref().unwrap_or_default(),
+                pjlink_command = pjlink_command.as_str(), tx_param = tx_param.as_str();
+                "Projector {}: Received command: ConnectionId: {}, Command: {}, Tx: {}",
+                projector, connection_id, pjlink_command, tx_param
+            ),
+            BridgeEventKind::SerialTx {connection_id, serial_tx} => debug!(
+                projector, connection_id = *connection_id, serial_tx = serial_tx.as_str();
+                "Projector {}: Will send to projector: ConnectionId: {}, Request: {}",
+                projector, connection_id, serial_tx
+            ),
+            BridgeEventKind::SerialRx {connection_id, serial_rx, elapsed_ms} => debug!(
+                projector, connection_id = *connection_id, serial_rx = serial_rx.as_str(), elapsed_ms = *elapsed_ms;
+                "Projector {}: Received from projector: ConnectionId: {}, Response: {}, ElapsedTime: {}",
+                projector, connection_id, serial_rx, elapsed_ms
+            ),
+            BridgeEventKind::Response {connection_id, pjlink_command, response} => debug!(
+                projector, connection_id = *connection_id, pjlink_command = pjlink_command.as_str(), response = response.as_str();
+                "Projector {}: Sent response: ConnectionId: {}, Command: {}, Response: {}",
+                projector, connection_id, pjlink_command, response
+            ),
+            BridgeEventKind::StateChanged {pjlink_command, value} => debug!(
+                projector, pjlink_command = pjlink_command.as_str(), value = value.as_str();
+                "Projector {}: State changed: Command: {}, Value: {}",
+                projector, pjlink_command, value
+            ),
+            BridgeEventKind::ConnectorError {message} => error!(
+                projector;
+                "Projector {}: {}",
+                projector, message
+            ),
+            BridgeEventKind::IdleWarning {power_off_in} => debug!(
+                projector, power_off_in = *power_off_in;
+                "Projector {}: Idle warning: PowerOffIn: {}",
+                projector, power_off_in
+            ),
+            BridgeEventKind::Unsolicited {serial_rx, pjlink_command, value} => debug!(
+                projector, serial_rx = serial_rx.as_str(), pjlink_command = pjlink_command.as_deref().unwrap_or_default(), value = value.as_str();
+                "Projector {}: Received unsolicited frame: Rx: {}, Command: {}, Value: {}",
+                projector, serial_rx, pjlink_command.as_deref().unwrap_or_default(), value
+            ),
+            BridgeEventKind::AccessDenied {connection_id, client_address, authenticated, credential, reason, pjlink_command, tx_param} => debug!(
+                projector, connection_id = *connection_id, client_address = client_address.as_str(), authenticated = *authenticated,
+                credential = credential.as_deref().unwrap_or_default(), reason = *reason,
+                pjlink_command = pjlink_command.as_deref().unwrap_or_default(), tx_param = tx_param.as_deref().unwrap_or_default();
+                "Projector {}: Access denied: ConnectionId: {}, Client: {}, Reason: {}, Command: {}, Tx: {}",
+                projector, connection_id, client_address, reason, pjlink_command.as_deref().unwrap_or_default(), tx_param.as_deref().unwrap_or_default()
+            ),
+        }
+    }
+}
+
+pub type BridgeEventListener = Box<dyn Fn(&BridgeEvent) + Send>;
+
+/// Event bus shared by every projector. Events are logged, handed to every listener
+/// (synchronously, e.g. the audit log) and then queued for every subscriber.
+#[derive(Default)]
+pub struct BridgeEvents {
+    listeners: Mutex<Vec<BridgeEventListener>>,
+    subscribers: Mutex<Vec<SyncSender<Arc<BridgeEvent>>>>,
+}
+
+impl BridgeEvents {
+    pub fn add_listener(&self, listener: BridgeEventListener) {
+        if let Ok(mut listeners) = self.listeners.lock() {
+            listeners.push(listener);
+        }
+    }
+
+    pub fn subscribe(&self) -> Receiver<Arc<BridgeEvent>> {
+        let (tx, rx) = sync_channel(SUBSCRIBER_QUEUE_SIZE);
+
+        if let Ok(mut subscribers) = self.subscribers.lock() {
+            subscribers.push(tx);
+        }
+
+        rx
+    }
+
+    pub fn publish(&self, event: BridgeEvent) {
+        event.log();
+
+        if let Ok(listeners) = self.listeners.lock() {
+            for listener in listeners.iter() {
+                listener(&event);
+            }
+        }
+
+        if let Ok(mut subscribers) = self.subscribers.lock() {
+            if subscribers.is_empty() {
+                return;
+            }
+
+            let event = Arc::new(event);
+
+            // Full queues drop the event, disconnected subscribers are removed
+            subscribers.retain(|subscriber| !matches!(subscriber.try_send(event.clone()), Err(TrySendError::Disconnected(_))));
+        }
+    }
+
+    /// Publisher that tags every event with the projector name.
+    pub fn for_projector(self: &Arc<Self>, projector_name: String) -> BridgeProjectorEvents {
+        BridgeProjectorEvents {
+            projector_name,
+            events: self.clone(),
+        }
+    }
+}
+
+pub struct BridgeProjectorEvents {
+    projector_name: String,
+    events: Arc<BridgeEvents>,
+}
+
+impl BridgeProjectorEvents {
+    pub fn publish(&self, kind: BridgeEventKind) {
+        self.events.publish(BridgeEvent {
+            projector: self.projector_name.clone(),
+            timestamp: SystemTime::now().duration_since(UNIX_EPOCH).map(|duration| duration.as_millis()).unwrap_or_default(),
+            kind,
+        });
+    }
+
+    #[inline(always)]
+    pub fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+}
+
+/// Events a WebSocket client subscribed to, from the `projector`, `type` and `connection_id`
+/// query parameters (comma separated). A missing parameter doesn't filter.
+struct BridgeEventsFilter {
+    projectors: Option<Vec<String>>,
+    types: Option<Vec<String>>,
+    connection_ids: Option<Vec<u64>>,
+}
+
+impl BridgeEventsFilter {
+    fn from_query(query: Option<&str>) -> Self {
+        let mut filter = BridgeEventsFilter {projectors: None, types: None, connection_ids: None};
+
+        for (key, value) in Self::query_pairs(query) {
+            let values = value.split(',').map(Self::percent_decode);
+
+            match key {
+                "projector" => filter.projectors = Some(values.collect()),
+                "type" => filter.types = Some(values.collect()),
+                "connection_id" => filter.connection_ids = Some(values.filter_map(|value| value.parse().ok()).collect()),
+                _ => {}
+            }
+        }
+
+        filter
+    }
+
+    /// `token` query parameter, for clients that can't set headers on the handshake (e.g. browsers).
+    fn token(query: Option<&str>) -> Option<String> {
+        Self::query_pairs(query)
+            .find(|(key, _)| *key == "token")
+            .map(|(_, value)| Self::percent_decode(value))
+    }
+
+    fn query_pairs(query: Option<&str>) -> impl Iterator<Item = (&str, &str)> {
+        query.unwrap_or_default().split('&').filter_map(|pair| pair.split_once('='))
+    }
+
+    #[inline(always)]
+    fn percent_decode(value: &str) -> String {
+        let bytes = value.as_bytes();
+        let mut decoded = Vec::with_capacity(bytes.len());
+        let mut index = 0;
+
+        while index < bytes.len() {
+            let hex_value = bytes.get(index + 1..index + 3)
+                .and_then(|hex| std::str::from_utf8(hex).ok())
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+
+            match (bytes[index], hex_value) {
+                (b'%', Some(byte)) => {
+                    decoded.push(byte);
+                    index += 3;
+                },
+                (b'+', _) => {
+                    decoded.push(b' ');
+                    index += 1;
+                },
+                (byte, _) => {
+                    decoded.push(byte);
+                    index += 1;
+                }
+            }
+        }
+
+        String::from_utf8_lossy(&decoded).into_owned()
+    }
+
+    fn matches(&self, event: &BridgeEvent) -> bool {
+        self.projectors.as_ref().is_none_or(|projectors| projectors.contains(&event.projector))
+            && self.types.as_ref().is_none_or(|types| types.iter().any(|event_type| event_type == event.kind.name()))
+            && self.connection_ids.as_ref().is_none_or(|connection_ids| event.kind.connection_id().is_some_and(|connection_id| connection_ids.contains(&connection_id)))
+    }
+}
+
+pub struct BridgeEventsServer {
+}
+
+impl BridgeEventsServer {
+    /// Streams events as JSON text messages to every WebSocket client connected to `listen_address`.
+    /// Clients must send `token`, as `Authorization: Bearer <token>` or in the `token` query parameter.
+    /// Without a token, only loopback addresses are served.
+    pub fn spawn(listen_address: String, token: Option<String>, events: Arc<BridgeEvents>) {
+        let listener = match TcpListener::bind(&listen_address) {
+            Ok(listener) => listener,
+            Err(err) => {
+                error!("Cannot start event stream listener on {}. {}", listen_address, err);
+                return;
+            }
+        };
+
+        if token.is_none() && !listener.local_addr().is_ok_and(|address| address.ip().is_loopback()) {
+            error!("Event stream listener on {} needs events_token, only loopback addresses are served without one", listen_address);
+            return;
+        }
+
+        info!("Event stream available at ws://{}/events", listen_address);
+
+        let token = Arc::new(token);
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        let token = token.clone();
+                        let events = events.clone();
+                        thread::spawn(move || Self::handle_client(stream, token.as_deref(), events));
+                    },
+                    Err(err) => error!("Cannot accept event stream connection. {}", err)
+                }
+            }
+        });
+    }
+
+    // The handshake callback signature, with its large error response, is set by tungstenite
+    #[allow(clippy::result_large_err)]
+    fn handle_client(stream: TcpStream, token: Option<&str>, events: Arc<BridgeEvents>) {
+        let client_address = stream.peer_addr().map(|address| address.to_string()).unwrap_or_default();
+        let mut filter = BridgeEventsFilter::from_query(None);
+
+        let mut websocket = match tungstenite::accept_hdr(stream, |request: &Request, response: Response| {
+            if request.uri().path() != "/events" {
+                let mut not_found = ErrorResponse::new(Some(String::from("Not Found")));
+                *not_found.status_mut() = StatusCode::NOT_FOUND;
+                return Err(not_found);
+            }
+
+            if let Some(token) = token {
+                let authorization = request.headers().get("Authorization")
+                    .and_then(|authorization| authorization.to_str().ok())
+                    .map(String::from)
+                    .or_else(|| BridgeEventsFilter::token(request.uri().query()).map(|query_token| format!("Bearer {}", query_token)))
+                    .unwrap_or_default();
+
+                if !BridgeApiServer::token_matches(&authorization, token) {
+                    let mut unauthorized = ErrorResponse::new(Some(String::from("Unauthorized")));
+                    *unauthorized.status_mut() = StatusCode::UNAUTHORIZED;
+                    return Err(unauthorized);
+                }
+            }
+
+            filter = BridgeEventsFilter::from_query(request.uri().query());
+            Ok(response)
+        }) {
+            Ok(websocket) => websocket,
+            Err(err) => {
+                debug!("Event stream handshake failed. Client: {}, {}", client_address, err);
+                return;
+            }
+        };
+
+        info!("Event stream client connected. Client: {}", client_address);
+
+        // Reads only wait briefly, so that events are sent while the client is quiet
+        if let Err(err) = websocket.get_ref().set_read_timeout(Some(CLIENT_READ_TIMEOUT)) {
+            error!("Cannot set up event stream connection. Client: {}, {}", client_address, err);
+            return;
+        }
+
+        let subscriber = events.subscribe();
+
+        loop {
+            // Pings are answered and close frames acknowledged by tungstenite, on the next read or send
+            match websocket.read() {
+                Ok(Message::Close(_)) => {
+                    info!("Event stream client disconnected. Client: {}", client_address);
+                    websocket.flush().unwrap_or_default();
+                    break;
+                },
+                Ok(_) => continue,
+                Err(tungstenite::Error::Io(err)) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {},
+                Err(err) => {
+                    info!("Event stream client disconnected. Client: {}, {}", client_address, err);
+                    break;
+                }
+            }
+
+            let event = match subscriber.recv_timeout(EVENT_POLL_INTERVAL) {
+                Ok(event) => event,
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => break
+            };
+
+            // Events queued meanwhile are sent together, before the socket is read again
+            for event in std::iter::once(event).chain(subscriber.try_iter()) {
+                if !filter.matches(&event) {
+                    continue;
+                }
+
+                let message = Message::Text(serde_json::to_string(event.as_ref()).unwrap_or_default());
+
+                if let Err(err) = websocket.send(message) {
+                    info!("Event stream client disconnected. Client: {}, {}", client_address, err);
+                    return;
+                }
+            }
+        }
+    }
+}
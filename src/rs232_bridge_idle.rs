@@ -0,0 +1,176 @@
+use std::{
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant}
+};
+
+use chrono::Local;
+use log::{debug, error, info, warn};
+use pjlink_bridge::PjLinkResponse;
+
+use crate::{
+    rs232_bridge::{PjLinkRS232Credentials, PjLinkRS232Projector},
+    rs232_bridge_config::BridgeConfigIdle,
+    rs232_bridge_events::BridgeEventKind,
+    rs232_bridge_schedule::BridgeScheduleEvent
+};
+
+/// Connection id used in logs for commands sent by the idle policy.
+pub const IDLE_CONNECTION_ID: u64 = u64::MAX - 4;
+
+/// Bridge specific query a definition may map to tell whether the projector has no input signal
+/// (`1`) or has one (`0`).
+pub const IDLE_NO_SIGNAL_QUERY: &[u8; 5] = b"XNSIG";
+
+pub struct BridgeIdle {
+}
+
+impl BridgeIdle {
+    /// Powers the projector off once it has been on for `timeout` seconds without set commands
+    /// (and without input signal, when the definition maps `XNSIG ?`). An `idle_warning` event is
+    /// published `warning` seconds before. The policy is suspended while any of the projector scheduled `events` is in progress.
+    pub fn spawn(
+        projector_name: String,
+        config: &BridgeConfigIdle,
+        events: Vec<BridgeScheduleEvent>,
+        shared_handler: Arc<Mutex<PjLinkRS232Projector>>
+    ) {
+        let timeout = Duration::from_secs(config.timeout);
+        let warning = Duration::from_secs(config.warning.min(config.timeout));
+        let check_interval = Duration::from_secs(config.check_interval.max(1));
+
+        info!("Projector {}: Idle policy enabled, powering off after {} seconds without activity", projector_name, config.timeout);
+
+        thread::spawn(move || {
+            let mut idle_since: Option<Instant> = None;
+            let mut warned = false;
+
+            loop {
+                thread::sleep(check_interval);
+
+                let mut handler = match shared_handler.lock() {
+                    Ok(handler) => handler,
+                    Err(_) => {
+                        error!("Projector {}: Handler is unavailable, idle policy stopped", projector_name);
+                        return;
+                    }
+                };
+
+                if !Self::is_powered_on(&mut handler) {
+                    idle_since = None;
+                    warned = false;
+                    continue;
+                }
+
+                let now = Instant::now();
+                let suspended = events.iter().find(|event| event.is_in_progress(&Local::now()));
+
+                if let Some(event) = suspended {
+                    debug!("Projector {}: Scheduled event {} in progress, idle policy suspended", projector_name, event.name);
+                }
+
+                // Power on, scheduled events and input signal all restart the idle time
+                let restarted = idle_since.is_none() || suspended.is_some() || Self::has_signal(&mut handler);
+                let since = if restarted {now} else {idle_since.unwrap_or(now)}.max(handler.last_activity());
+                idle_since = Some(since);
+
+                let idle_time = now.duration_since(since);
+
+                if idle_time < timeout - warning {
+                    warned = false;
+                } else if idle_time < timeout {
+                    if !warned {
+                        let power_off_in = (timeout - idle_time).as_secs();
+                        warn!("Projector {}: Idle, powering off in {} seconds", projector_name, power_off_in);
+                        handler.events().publish(BridgeEventKind::IdleWarning {power_off_in});
+                        warned = true;
+                    }
+                } else {
+                    let response = handler.handle_bridge_command(*b"1POWR", b"0", &IDLE_CONNECTION_ID, PjLinkRS232Credentials::User);
+
+                    match response {
+                        PjLinkResponse::Ok => info!("Projector {}: Powered off after {} seconds idle", projector_name, idle_time.as_secs()),
+                        _ => warn!(
+                            "Projector {}: Cannot power off idle projector. Response: {}",
+                            projector_name,
+                            PjLinkRS232Projector::response_text(&response)
+                        )
+                    }
+
+                    idle_since = None;
+                    warned = false;
+                }
+            }
+        });
+    }
+
+    /// Queries the power state when the definition maps it, otherwise relies on the last known one.
+    fn is_powered_on(handler: &mut PjLinkRS232Projector) -> bool {
+        if handler.has_dynamic_content(b"1POWR", b"?") {
+            handler.handle_bridge_command(*b"1POWR", b"?", &IDLE_CONNECTION_ID, PjLinkRS232Credentials::User);
+        }
+
+        handler.state().lock().map(|state| state.power_on == b'1').unwrap_or_default()
+    }
+
+    fn has_signal(handler: &mut PjLinkRS232Projector) -> bool {
+        if !handler.has_dynamic_content(IDLE_NO_SIGNAL_QUERY, b"?") {
+            return false;
+        }
+
+        // Values mapped by definitions may come as single or multiple characters
+        match handler.handle_bridge_command(*IDLE_NO_SIGNAL_QUERY, b"?", &IDLE_CONNECTION_ID, PjLinkRS232Credentials::User) {
+            PjLinkResponse::Single(value) => value == b'0',
+            PjLinkResponse::Multiple(value) => value == b"0",
+            _ => false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+
+    use crate::rs232_bridge::test_projector;
+
+    #[test]
+    fn queries_the_input_signal() {
+        let (mut serial_port, mut projector) = test_projector("Room 101", r#"
+            [general]
+            manufacturer_name = "Test"
+            product_name = "Projector"
+            software_version = "1"
+            class_type = 1
+
+            [connection]
+            baud_rate = 9600
+
+            [commands."XNSIG".inputs."?"]
+            send = [0x02]
+            wait_for_response = 50
+            outputs = [
+                {on_received_type = "value", on_received = [0x10], response_type = "value", response_value = "0"},
+                {on_received_type = "value", on_received = [0x11], response_type = "value", response_value = "1"}
+            ]
+        "#);
+
+        // The projector reports a signal, then none
+        serialport::SerialPort::set_timeout(&mut serial_port, Duration::from_secs(10)).unwrap();
+        let projector_thread = thread::spawn(move || {
+            let mut received = [0u8; 1];
+
+            for reply in [0x10, 0x11] {
+                serial_port.read_exact(&mut received).unwrap();
+                serial_port.write_all(&[reply]).unwrap();
+            }
+
+            serial_port
+        });
+
+        assert!(BridgeIdle::has_signal(&mut projector));
+        assert!(!BridgeIdle::has_signal(&mut projector));
+
+        let _serial_port = projector_thread.join().unwrap();
+    }
+}
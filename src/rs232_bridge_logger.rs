@@ -0,0 +1,259 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    sync::Mutex
+};
+
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+
+use chrono::{Local, SecondsFormat};
+use log::{Level, LevelFilter, Log, Metadata, Record, kv::{Key, Value, VisitSource}};
+use serde_json::{Map, Value as JsonValue};
+
+use crate::rs232_bridge_config::{BridgeConfigLog, BridgeConfigLogFormat, BridgeConfigLogOutput};
+
+/// syslog facility used for every message (daemon).
+const SYSLOG_FACILITY: u8 = 3;
+
+/// Logger writing text or JSON lines to stderr, syslog and/or a size-rotated file.
+/// Key-values attached to a record (e.g. `connection_id`, `serial_tx`) become JSON fields.
+pub struct BridgeLogger {
+    default_level: LevelFilter,
+    module_levels: Vec<(String, LevelFilter)>,
+    format: BridgeConfigLogFormat,
+    outputs: Vec<Mutex<BridgeLoggerOutput>>,
+}
+
+enum BridgeLoggerOutput {
+    Stderr,
+    #[cfg(unix)]
+    Syslog(UnixDatagram),
+    File(BridgeLoggerFile),
+}
+
+/// Append-only file, rotated once it reaches `max_size`. Also used by the audit log.
+pub struct BridgeLoggerFile {
+    path: String,
+    file: File,
+    size: u64,
+    max_size: u64,
+    max_files: usize,
+}
+
+pub struct Error {
+    pub message: String
+}
+
+impl BridgeLogger {
+    pub fn new(config: &BridgeConfigLog) -> Result<BridgeLogger, Error> {
+        let outputs = config.outputs.iter()
+            .map(|output| Self::open_output(output, config).map(Mutex::new))
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        Ok(BridgeLogger {
+            default_level: LevelFilter::Warn,
+            module_levels: vec! [],
+            format: config.format.clone(),
+            outputs,
+        })
+    }
+
+    pub fn with_level(mut self, level: LevelFilter) -> BridgeLogger {
+        self.default_level = level;
+        self
+    }
+
+    pub fn with_module_level(mut self, module: &str, level: LevelFilter) -> BridgeLogger {
+        self.module_levels.push((String::from(module), level));
+        // Most specific module first
+        self.module_levels.sort_by_key(|(module_name, _)| std::cmp::Reverse(module_name.len()));
+        self
+    }
+
+    pub fn init(self) -> Result<(), Error> {
+        let max_level = self.module_levels.iter()
+            .map(|(_, level)| *level)
+            .fold(self.default_level, Ord::max);
+
+        log::set_boxed_logger(Box::new(self))
+            .map(|()| log::set_max_level(max_level))
+            .map_err(|err| Error {message: err.to_string()})
+    }
+
+    fn open_output(output: &BridgeConfigLogOutput, config: &BridgeConfigLog) -> Result<BridgeLoggerOutput, Error> {
+        match output {
+            BridgeConfigLogOutput::Stderr => Ok(BridgeLoggerOutput::Stderr),
+            #[cfg(unix)]
+            BridgeConfigLogOutput::Syslog => {
+                let socket = UnixDatagram::unbound()
+                    .and_then(|socket| socket.connect(&config.syslog_socket).map(|()| socket))
+                    .map_err(|err| Error {message: format!("Cannot connect to syslog socket {}: {}", config.syslog_socket, err)})?;
+
+                Ok(BridgeLoggerOutput::Syslog(socket))
+            },
+            #[cfg(not(unix))]
+            BridgeConfigLogOutput::Syslog => Err(Error {message: String::from("syslog output is only available on Unix")}),
+            BridgeConfigLogOutput::File => {
+                let path = config.file.clone()
+                    .ok_or_else(|| Error {message: String::from("A log file must be set to log to a file")})?;
+
+                Ok(BridgeLoggerOutput::File(BridgeLoggerFile::open(path, config.max_file_size, config.max_files)?))
+            }
+        }
+    }
+
+    fn level_for(&self, target: &str) -> LevelFilter {
+        self.module_levels.iter()
+            .find(|(module, _)| target.starts_with(module.as_str()))
+            .map_or(self.default_level, |(_, level)| *level)
+    }
+
+    fn format_record(&self, record: &Record) -> String {
+        match self.format {
+            BridgeConfigLogFormat::Text => format!(
+                "{} {:<5} [{}] {}",
+                Local::now().format("%Y-%m-%d %H:%M:%S,%3f"),
+                record.level(),
+                record.target(),
+                record.args()
+            ),
+            BridgeConfigLogFormat::Json => {
+                let mut fields = Map::new();
+                fields.insert(String::from("timestamp"), Local::now().to_rfc3339_opts(SecondsFormat::Millis, false).into());
+                fields.insert(String::from("level"), record.level().as_str().into());
+                fields.insert(String::from("target"), record.target().into());
+                fields.insert(String::from("message"), record.args().to_string().into());
+                record.key_values().visit(&mut BridgeLoggerFields(&mut fields)).unwrap_or_default();
+
+                JsonValue::Object(fields).to_string()
+            }
+        }
+    }
+}
+
+impl Log for BridgeLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level_for(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let line = self.format_record(record);
+
+        for output in &self.outputs {
+            if let Ok(mut output) = output.lock() {
+                output.write(record.level(), &line);
+            }
+        }
+    }
+
+    fn flush(&self) {
+        for output in &self.outputs {
+            if let Ok(mut output) = output.lock() {
+                if let BridgeLoggerOutput::File(log_file) = &mut *output {
+                    log_file.file.flush().unwrap_or_default();
+                }
+            }
+        }
+    }
+}
+
+impl BridgeLoggerOutput {
+    fn write(&mut self, level: Level, line: &str) {
+        match self {
+            BridgeLoggerOutput::Stderr => {
+                writeln!(io::stderr(), "{}", line).unwrap_or_default();
+            },
+            #[cfg(unix)]
+            BridgeLoggerOutput::Syslog(socket) => {
+                let severity = match level {
+                    Level::Error => 3,
+                    Level::Warn => 4,
+                    Level::Info => 6,
+                    Level::Debug | Level::Trace => 7
+                };
+
+                let message = format!("<{}>pjlink-rs232-bridge[{}]: {}", SYSLOG_FACILITY * 8 + severity, std::process::id(), line);
+                socket.send(message.as_bytes()).unwrap_or_default();
+            },
+            BridgeLoggerOutput::File(log_file) => log_file.write(line)
+        }
+    }
+}
+
+impl BridgeLoggerFile {
+    pub fn open(path: String, max_size: u64, max_files: usize) -> Result<BridgeLoggerFile, Error> {
+        let file = Self::open_file(&path)?;
+        let size = file.metadata().map(|metadata| metadata.len()).unwrap_or_default();
+
+        Ok(BridgeLoggerFile {path, file, size, max_size, max_files})
+    }
+
+    /// Current file first, then `<file>.1`, `<file>.2`, ... (newest to oldest). Missing files are skipped.
+    pub fn paths(path: &str, max_files: usize) -> Vec<String> {
+        std::iter::once(String::from(path))
+            .chain((1..=max_files).map(|index| format!("{}.{}", path, index)))
+            .filter(|path| std::path::Path::new(path).exists())
+            .collect()
+    }
+
+    pub fn write(&mut self, line: &str) {
+        let line_size = line.len() as u64 + 1;
+
+        if self.size > 0 && self.size + line_size > self.max_size {
+            self.rotate();
+        }
+
+        if writeln!(self.file, "{}", line).is_ok() {
+            self.size += line_size;
+        }
+    }
+
+    #[inline(always)]
+    fn open_file(path: &str) -> Result<File, Error> {
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|err| Error {message: format!("Cannot open log file {}: {}", path, err)})
+    }
+
+    /// Renames `<file>` to `<file>.1`, `<file>.1` to `<file>.2` and so on, dropping files past `max_files`.
+    fn rotate(&mut self) {
+        for index in (1..self.max_files).rev() {
+            fs::rename(format!("{}.{}", self.path, index), format!("{}.{}", self.path, index + 1)).unwrap_or_default();
+        }
+
+        if self.max_files > 0 {
+            fs::rename(&self.path, format!("{}.1", self.path)).unwrap_or_default();
+        } else {
+            fs::remove_file(&self.path).unwrap_or_default();
+        }
+
+        match Self::open_file(&self.path) {
+            Ok(file) => {
+                self.file = file;
+                self.size = 0;
+            },
+            Err(err) => writeln!(io::stderr(), "{}", err.message).unwrap_or_default()
+        }
+    }
+}
+
+struct BridgeLoggerFields<'a>(&'a mut Map<String, JsonValue>);
+
+impl<'a, 'kvs> VisitSource<'kvs> for BridgeLoggerFields<'a> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), log::kv::Error> {
+        let json_value = match value.to_u64() {
+            Some(number) => number.into(),
+            None => value.to_string().into()
+        };
+
+        self.0.insert(key.to_string(), json_value);
+        Ok(())
+    }
+}
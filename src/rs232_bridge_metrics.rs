@@ -0,0 +1,221 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{Arc, Mutex, atomic::{AtomicU64, Ordering}},
+    thread
+};
+
+use log::{error, info};
+use pjlink_bridge::PjLinkResponse;
+use tiny_http::{Header, Method, Response, Server};
+
+use crate::{rs232_bridge::PjLinkRS232ProjectorState, rs232_bridge_def::BridgeDefinitionRetryOutcome};
+
+/// Upper bounds (in ms) of the serial round-trip latency histogram buckets.
+const SERIAL_LATENCY_BUCKETS: [u32; 10] = [10, 25, 50, 75, 100, 250, 500, 1000, 2500, 5000];
+
+/// Counters of a single projector, shared between its handler and connector thread.
+#[derive(Default)]
+pub struct BridgeMetrics {
+    /// PJLink commands by (command, response)
+    commands: Mutex<BTreeMap<(String, &'static str), u64>>,
+    serial_latency: Mutex<BridgeMetricsHistogram>,
+    unmatched_replies: AtomicU64,
+    /// Commands sent again, by reason
+    retries: Mutex<BTreeMap<&'static str, u64>>,
+    serial_errors: AtomicU64,
+    /// Serial port re-opens after a definition reload
+    reopens: AtomicU64,
+    stale_inputs: AtomicU64,
+}
+
+#[derive(Default)]
+struct BridgeMetricsHistogram {
+    buckets: [u64; SERIAL_LATENCY_BUCKETS.len()],
+    count: u64,
+    sum: u64,
+}
+
+impl BridgeMetrics {
+    pub fn record_command(&self, request_body: &[u8; 5], response: &PjLinkResponse) {
+        let command = String::from_utf8_lossy(&request_body[1..]).into_owned();
+
+        if let Ok(mut commands) = self.commands.lock() {
+            *commands.entry((command, Self::response_label(response))).or_insert(0) += 1;
+        }
+    }
+
+    pub fn record_serial_latency(&self, elapsed_time: u32) {
+        if let Ok(mut serial_latency) = self.serial_latency.lock() {
+            for (bucket, upper_bound) in serial_latency.buckets.iter_mut().zip(SERIAL_LATENCY_BUCKETS.iter()) {
+                if elapsed_time <= *upper_bound {
+                    *bucket += 1;
+                }
+            }
+
+            serial_latency.count += 1;
+            serial_latency.sum += elapsed_time as u64;
+        }
+    }
+
+    pub fn record_unmatched_reply(&self) {
+        self.unmatched_replies.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_retry(&self, outcome: BridgeDefinitionRetryOutcome) {
+        if let Ok(mut retries) = self.retries.lock() {
+            *retries.entry(outcome.name()).or_insert(0) += 1;
+        }
+    }
+
+    pub fn record_serial_error(&self) {
+        self.serial_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_reopen(&self) {
+        self.reopens.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_stale_input(&self) {
+        self.stale_inputs.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[inline(always)]
+    fn response_label(response: &PjLinkResponse) -> &'static str {
+        match response {
+            PjLinkResponse::Undefined => "ERR1",
+            PjLinkResponse::OutOfParameter => "ERR2",
+            PjLinkResponse::UnavailableTime => "ERR3",
+            PjLinkResponse::Ok | PjLinkResponse::Single(_) | PjLinkResponse::Multiple(_) => "OK",
+            _ => "ERR4"
+        }
+    }
+
+    fn render(&self, projector_name: &str, output: &mut BridgeMetricsOutput) {
+        let projector = Self::escape_label(projector_name);
+
+        if let Ok(commands) = self.commands.lock() {
+            for ((command, response), count) in commands.iter() {
+                writeln!(output.commands, "pjlink_bridge_commands_total{{projector=\"{}\",command=\"{}\",response=\"{}\"}} {}", projector, Self::escape_label(command), response, count).unwrap_or_default();
+            }
+        }
+
+        if let Ok(serial_latency) = self.serial_latency.lock() {
+            for (bucket, upper_bound) in serial_latency.buckets.iter().zip(SERIAL_LATENCY_BUCKETS.iter()) {
+                writeln!(output.serial_latency, "pjlink_bridge_serial_latency_ms_bucket{{projector=\"{}\",le=\"{}\"}} {}", projector, upper_bound, bucket).unwrap_or_default();
+            }
+
+            writeln!(output.serial_latency, "pjlink_bridge_serial_latency_ms_bucket{{projector=\"{}\",le=\"+Inf\"}} {}", projector, serial_latency.count).unwrap_or_default();
+            writeln!(output.serial_latency, "pjlink_bridge_serial_latency_ms_sum{{projector=\"{}\"}} {}", projector, serial_latency.sum).unwrap_or_default();
+            writeln!(output.serial_latency, "pjlink_bridge_serial_latency_ms_count{{projector=\"{}\"}} {}", projector, serial_latency.count).unwrap_or_default();
+        }
+
+        writeln!(output.unmatched_replies, "pjlink_bridge_unmatched_replies_total{{projector=\"{}\"}} {}", projector, self.unmatched_replies.load(Ordering::Relaxed)).unwrap_or_default();
+        if let Ok(retries) = self.retries.lock() {
+            for (reason, count) in retries.iter() {
+                writeln!(output.retries, "pjlink_bridge_serial_retries_total{{projector=\"{}\",reason=\"{}\"}} {}", projector, reason, count).unwrap_or_default();
+            }
+        }
+
+        writeln!(output.serial_errors, "pjlink_bridge_serial_errors_total{{projector=\"{}\"}} {}", projector, self.serial_errors.load(Ordering::Relaxed)).unwrap_or_default();
+        writeln!(output.reopens, "pjlink_bridge_serial_reopens_total{{projector=\"{}\"}} {}", projector, self.reopens.load(Ordering::Relaxed)).unwrap_or_default();
+        writeln!(output.stale_inputs, "pjlink_bridge_stale_input_flushes_total{{projector=\"{}\"}} {}", projector, self.stale_inputs.load(Ordering::Relaxed)).unwrap_or_default();
+    }
+
+    fn render_state(projector_name: &str, state: &PjLinkRS232ProjectorState, output: &mut BridgeMetricsOutput) {
+        let projector = Self::escape_label(projector_name);
+
+        // Unknown states (never queried nor set) are reported as -1
+        let power = if state.power_on.is_ascii_digit() {(state.power_on - b'0') as i32} else {-1};
+        writeln!(output.power_state, "pjlink_bridge_power_state{{projector=\"{}\"}} {}", projector, power).unwrap_or_default();
+
+        if state.input_status.iter().all(u8::is_ascii_alphanumeric) {
+            writeln!(
+                output.input_state,
+                "pjlink_bridge_input_state{{projector=\"{}\",input=\"{}\"}} 1",
+                projector,
+                String::from_utf8_lossy(&state.input_status)
+            ).unwrap_or_default();
+        }
+    }
+
+    #[inline(always)]
+    fn escape_label(value: &str) -> String {
+        value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+    }
+}
+
+/// Rendered samples, grouped by metric family as required by the Prometheus text format.
+#[derive(Default)]
+struct BridgeMetricsOutput {
+    commands: String,
+    serial_latency: String,
+    unmatched_replies: String,
+    retries: String,
+    serial_errors: String,
+    reopens: String,
+    stale_inputs: String,
+    power_state: String,
+    input_state: String,
+}
+
+pub type BridgeMetricsProjectors = Vec<(String, Arc<BridgeMetrics>, Arc<Mutex<PjLinkRS232ProjectorState>>)>;
+
+pub struct BridgeMetricsServer {
+}
+
+impl BridgeMetricsServer {
+    /// Serves the metrics of every projector in Prometheus text format at `/metrics`.
+    pub fn spawn(listen_address: String, projectors: BridgeMetricsProjectors) {
+        let server = match Server::http(&listen_address) {
+            Ok(server) => server,
+            Err(err) => {
+                error!("Cannot start metrics listener on {}. {}", listen_address, err);
+                return;
+            }
+        };
+
+        info!("Metrics available at http://{}/metrics", listen_address);
+
+        thread::spawn(move || {
+            for request in server.incoming_requests() {
+                let response = if *request.method() == Method::Get && request.url() == "/metrics" {
+                    let content_type = Header::from_bytes(&b"Content-Type"[..], &b"text/plain; version=0.0.4"[..]).unwrap();
+                    Response::from_string(Self::render(&projectors)).with_header(content_type)
+                } else {
+                    Response::from_string("Not Found").with_status_code(404)
+                };
+
+                if let Err(err) = request.respond(response) {
+                    error!("Cannot send metrics response. {}", err);
+                }
+            }
+        });
+    }
+
+    fn render(projectors: &BridgeMetricsProjectors) -> String {
+        let mut output = BridgeMetricsOutput::default();
+
+        for (projector_name, metrics, state) in projectors {
+            metrics.render(projector_name, &mut output);
+
+            if let Ok(state) = state.lock() {
+                BridgeMetrics::render_state(projector_name, &state, &mut output);
+            }
+        }
+
+        [
+            ("pjlink_bridge_commands_total", "counter", output.commands),
+            ("pjlink_bridge_serial_latency_ms", "histogram", output.serial_latency),
+            ("pjlink_bridge_unmatched_replies_total", "counter", output.unmatched_replies),
+            ("pjlink_bridge_serial_retries_total", "counter", output.retries),
+            ("pjlink_bridge_serial_errors_total", "counter", output.serial_errors),
+            ("pjlink_bridge_serial_reopens_total", "counter", output.reopens),
+            ("pjlink_bridge_stale_input_flushes_total", "counter", output.stale_inputs),
+            ("pjlink_bridge_power_state", "gauge", output.power_state),
+            ("pjlink_bridge_input_state", "gauge", output.input_state),
+        ].iter()
+            .map(|(name, metric_type, samples)| format!("# TYPE {} {}\n{}", name, metric_type, samples))
+            .collect()
+    }
+}
@@ -0,0 +1,66 @@
+use std::{
+    sync::{Arc, Mutex},
+    thread
+};
+
+use log::{error, info, warn};
+use signal_hook::{consts::{SIGUSR1, SIGUSR2}, iterator::Signals};
+
+use crate::rs232_bridge::{PjLinkRS232Projector, PjLinkRS232ProjectorMode};
+
+pub type BridgeModeProjectors = Vec<(String, Arc<Mutex<PjLinkRS232Projector>>)>;
+
+pub struct BridgeMode {
+}
+
+impl BridgeMode {
+    /// Toggles read-only mode of every projector on SIGUSR1 and maintenance mode on SIGUSR2.
+    /// Each projector toggles on its own, e.g. SIGUSR1 puts a read-only projector back to normal.
+    pub fn spawn(projectors: BridgeModeProjectors) {
+        let mut signals = match Signals::new([SIGUSR1, SIGUSR2]) {
+            Ok(signals) => signals,
+            Err(err) => {
+                error!("Cannot listen for SIGUSR1/SIGUSR2, modes can't be toggled by signal. {}", err);
+                return;
+            }
+        };
+
+        thread::spawn(move || {
+            for signal in signals.forever() {
+                let toggled_mode = if signal == SIGUSR1 {
+                    info!("Received SIGUSR1, toggling read-only mode");
+                    PjLinkRS232ProjectorMode::ReadOnly
+                } else {
+                    info!("Received SIGUSR2, toggling maintenance mode");
+                    PjLinkRS232ProjectorMode::Maintenance
+                };
+
+                Self::toggle(&projectors, toggled_mode);
+            }
+        });
+    }
+
+    fn toggle(projectors: &BridgeModeProjectors, toggled_mode: PjLinkRS232ProjectorMode) {
+        for (name, shared_handler) in projectors {
+            let mut handler = match shared_handler.lock() {
+                Ok(handler) => handler,
+                Err(_) => {
+                    error!("Projector {}: Handler is unavailable, mode not changed", name);
+                    continue;
+                }
+            };
+
+            let mode = if handler.mode() == toggled_mode {PjLinkRS232ProjectorMode::Normal} else {toggled_mode};
+
+            if mode == PjLinkRS232ProjectorMode::Maintenance && handler.options().admin_password.is_none() {
+                warn!("Projector {}: Maintenance mode requires an admin password, mode not changed", name);
+                continue;
+            }
+
+            match handler.set_mode(mode) {
+                Ok(()) => info!("Projector {}: Switched to {:?} mode", name, mode),
+                Err(err) => error!("Projector {}: Switched to {:?} mode, but cannot persist it. {}", name, mode, err.message)
+            }
+        }
+    }
+}
@@ -0,0 +1,594 @@
+use std::{
+    sync::{Arc, Mutex, mpsc::channel},
+    thread,
+    time::Duration
+};
+
+use log::{debug, error, info, warn};
+use rumqttc::{Client, Event, LastWill, MqttOptions, Packet, QoS};
+use serde::Serialize;
+use serde_json::json;
+
+use crate::{
+    rs232_bridge::{PjLinkRS232Credentials, PjLinkRS232Projector, PjLinkRS232ProjectorState},
+    rs232_bridge_config::BridgeConfigMqtt
+};
+
+/// Connection id used in logs for commands received through MQTT.
+pub const MQTT_CONNECTION_ID: u64 = u64::MAX - 2;
+
+/// Queries sent on every poll, when mapped by the projector definition.
+const MQTT_POLL_QUERIES: [&[u8; 5]; 6] = [b"1POWR", b"1INPT", b"1AVMT", b"2FREZ", b"1LAMP", b"1ERST"];
+
+/// Commands that can be sent through `<topic_prefix>/<projector>/<command>/set`.
+const MQTT_COMMANDS: [(&str, &[u8; 5]); 4] = [("power", b"1POWR"), ("input", b"1INPT"), ("mute", b"1AVMT"), ("freeze", b"2FREZ")];
+
+/// Requests queued for each projector once connected: command subscriptions, discovery payloads and state,
+/// with room to spare. The request channel is sized from it so a reconnection never fills it up.
+const MQTT_REQUESTS_PER_PROJECTOR: usize = 16;
+
+/// Work handed over by the connection thread. It is done on a worker thread, so the connection thread
+/// keeps sending queued requests (and keep alives) while projectors are busy with serial exchanges.
+enum BridgeMqttTask {
+    Connected,
+    Command {topic: String, payload: Vec<u8>},
+}
+
+pub struct Error {
+    pub message: String
+}
+
+#[derive(Serialize, PartialEq, Clone)]
+struct BridgeMqttState {
+    power: Option<String>,
+    input: Option<String>,
+    mute: Option<String>,
+    freeze: Option<String>,
+    lamp_hours: Option<String>,
+    errors: Option<String>,
+}
+
+pub type BridgeMqttProjectors = Vec<(String, Arc<Mutex<PjLinkRS232Projector>>)>;
+
+struct BridgeMqttProjector {
+    /// Projector name, as used in topics
+    topic_name: String,
+    shared_handler: Arc<Mutex<PjLinkRS232Projector>>,
+    state: Arc<Mutex<PjLinkRS232ProjectorState>>,
+    published_state: Mutex<Option<BridgeMqttState>>,
+}
+
+pub struct BridgeMqtt {
+}
+
+impl BridgeMqtt {
+    /// Projector names are turned into topic names, which must not collide (e.g. `Room 101` and `room-101`).
+    pub fn check_topic_names<'a>(projector_names: impl IntoIterator<Item = &'a str>) -> Result<(), Error> {
+        let mut topic_names = Vec::<(String, &str)>::new();
+
+        for projector_name in projector_names {
+            let topic_name = Self::topic_name(projector_name);
+
+            if let Some((_, other_projector_name)) = topic_names.iter().find(|(other_topic_name, _)| *other_topic_name == topic_name) {
+                return Err(Error {
+                    message: format!("Projectors {} and {} have the same MQTT topic name {}, rename one of them", other_projector_name, projector_name, topic_name)
+                });
+            }
+
+            topic_names.push((topic_name, projector_name));
+        }
+
+        Ok(())
+    }
+
+    /// Connects to the broker, publishing every projector state under `<topic_prefix>/<projector>/state`
+    /// and handling commands from `<topic_prefix>/<projector>/<power|input|mute|freeze>/set`.
+    pub fn spawn(config: BridgeConfigMqtt, projectors: BridgeMqttProjectors) {
+        let projectors: Arc<Vec<BridgeMqttProjector>> = Arc::new(projectors.into_iter()
+            .filter_map(|(projector_name, shared_handler)| {
+                let state = match shared_handler.lock() {
+                    Ok(handler) => handler.state(),
+                    Err(_) => {
+                        error!("Projector {}: Handler is unavailable, it won't be published to MQTT", projector_name);
+                        return None;
+                    }
+                };
+
+                Some(BridgeMqttProjector {
+                    topic_name: Self::topic_name(&projector_name),
+                    shared_handler,
+                    state,
+                    published_state: Mutex::new(None),
+                })
+            })
+            .collect());
+
+        let availability_topic = format!("{}/availability", config.topic_prefix);
+
+        let mut mqtt_options = MqttOptions::new(config.client_id.clone(), config.host.clone(), config.port);
+        mqtt_options.set_keep_alive(Duration::from_secs(30));
+        mqtt_options.set_last_will(LastWill::new(&availability_topic, "offline", QoS::AtLeastOnce, true));
+        if let Some(username) = &config.username {
+            mqtt_options.set_credentials(username.clone(), config.password.clone().unwrap_or_default());
+        }
+
+        let (client, mut connection) = Client::new(mqtt_options, MQTT_REQUESTS_PER_PROJECTOR * (projectors.len() + 1));
+        let (task_tx, task_rx) = channel::<BridgeMqttTask>();
+
+        info!("Connecting to MQTT broker {}:{}", config.host, config.port);
+
+        thread::spawn(move || {
+            for notification in connection.iter() {
+                let task = match notification {
+                    Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                        info!("Connected to MQTT broker");
+                        BridgeMqttTask::Connected
+                    },
+                    Ok(Event::Incoming(Packet::Publish(publish))) => BridgeMqttTask::Command {
+                        topic: publish.topic,
+                        payload: publish.payload.to_vec()
+                    },
+                    Ok(_) => continue,
+                    Err(err) => {
+                        error!("MQTT connection error, reconnecting. {}", err);
+                        thread::sleep(Duration::from_secs(5));
+                        continue;
+                    }
+                };
+
+                if task_tx.send(task).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let worker_client = client.clone();
+        let worker_config = config.clone();
+        let worker_projectors = projectors.clone();
+
+        thread::spawn(move || {
+            let mut client = worker_client;
+
+            for task in task_rx {
+                match task {
+                    BridgeMqttTask::Connected => Self::on_connect(&mut client, &worker_config, &worker_projectors),
+                    BridgeMqttTask::Command {topic, payload} => Self::on_command(&mut client, &worker_config, &worker_projectors, &topic, &payload)
+                }
+            }
+        });
+
+        thread::spawn(move || {
+            let mut client = client;
+            let interval = Duration::from_secs(config.poll_interval.unwrap_or(1).max(1));
+
+            loop {
+                for projector in projectors.iter() {
+                    if config.poll_interval.is_some() {
+                        Self::poll(projector);
+                    }
+
+                    Self::publish_state(&mut client, &config, projector, false);
+                }
+
+                thread::sleep(interval);
+            }
+        });
+    }
+
+    fn on_connect(client: &mut Client, config: &BridgeConfigMqtt, projectors: &[BridgeMqttProjector]) {
+        let availability_topic = format!("{}/availability", config.topic_prefix);
+        client.publish(availability_topic, QoS::AtLeastOnce, true, "online").unwrap_or_default();
+
+        for projector in projectors {
+            for (command_name, _) in MQTT_COMMANDS.iter() {
+                let command_topic = format!("{}/{}/{}/set", config.topic_prefix, projector.topic_name, command_name);
+
+                if let Err(err) = client.subscribe(command_topic, QoS::AtLeastOnce) {
+                    error!("Cannot subscribe to MQTT command topics. {}", err);
+                }
+            }
+
+            if let Some(discovery_prefix) = &config.discovery_prefix {
+                Self::publish_discovery(client, config, discovery_prefix, projector);
+            }
+
+            // Retained state is re-published, as the broker may have lost it
+            Self::publish_state(client, config, projector, true);
+        }
+    }
+
+    fn on_command(
+        client: &mut Client,
+        config: &BridgeConfigMqtt,
+        projectors: &[BridgeMqttProjector],
+        topic: &str,
+        payload: &[u8]
+    ) {
+        let topic_parts: Vec<&str> = match topic.strip_prefix(&config.topic_prefix).and_then(|topic| topic.strip_prefix('/')) {
+            Some(topic) => topic.split('/').collect(),
+            None => return
+        };
+
+        let (projector, command_name) = match topic_parts.as_slice() {
+            [topic_name, command_name, "set"] => match projectors.iter().find(|projector| projector.topic_name == *topic_name) {
+                Some(projector) => (projector, *command_name),
+                None => return
+            },
+            _ => return
+        };
+
+        let command_body = match MQTT_COMMANDS.iter().find(|(name, _)| *name == command_name) {
+            Some((_, command_body)) => **command_body,
+            None => return
+        };
+
+        info!(
+            "MQTT command: Projector: {}, Command: {}, Tx: {}",
+            projector.topic_name,
+            std::str::from_utf8(&command_body).unwrap_or_default(),
+            String::from_utf8_lossy(payload)
+        );
+
+        // Publishers are authenticated by the broker, not the bridge
+        let response = match projector.shared_handler.lock() {
+            Ok(mut handler) => handler.handle_bridge_command(command_body, payload, &MQTT_CONNECTION_ID, PjLinkRS232Credentials::None),
+            Err(_) => {
+                error!("MQTT command: Projector {} handler is unavailable", projector.topic_name);
+                return;
+            }
+        };
+
+        let result_topic = format!("{}/{}/{}/result", config.topic_prefix, projector.topic_name, command_name);
+        client.publish(result_topic, QoS::AtLeastOnce, false, PjLinkRS232Projector::response_text(&response)).unwrap_or_default();
+
+        Self::publish_state(client, config, projector, false);
+    }
+
+    /// Refreshes the state cache by querying the projector, through the same handler as PJLink clients.
+    fn poll(projector: &BridgeMqttProjector) {
+        for command_body in MQTT_POLL_QUERIES.iter() {
+            match projector.shared_handler.lock() {
+                Ok(mut handler) => if handler.has_dynamic_content(command_body, b"?") {
+                    handler.handle_bridge_command(**command_body, b"?", &MQTT_CONNECTION_ID, PjLinkRS232Credentials::None);
+                },
+                Err(_) => {
+                    warn!("MQTT poll: Projector {} handler is unavailable", projector.topic_name);
+                    return;
+                }
+            }
+        }
+    }
+
+    fn publish_state(client: &mut Client, config: &BridgeConfigMqtt, projector: &BridgeMqttProjector, force: bool) {
+        let state = match projector.state.lock() {
+            Ok(state) => Self::describe_state(&state),
+            Err(_) => return
+        };
+
+        let mut published_state = match projector.published_state.lock() {
+            Ok(published_state) => published_state,
+            Err(_) => return
+        };
+
+        if !force && published_state.as_ref() == Some(&state) {
+            return;
+        }
+
+        let state_topic = format!("{}/{}/state", config.topic_prefix, projector.topic_name);
+        debug!("Publishing MQTT state to {}", state_topic);
+
+        match client.try_publish(state_topic, QoS::AtLeastOnce, true, serde_json::to_vec(&state).unwrap_or_default()) {
+            Ok(()) => *published_state = Some(state),
+            Err(err) => error!("Cannot publish MQTT state. {}", err)
+        }
+    }
+
+    fn publish_discovery(client: &mut Client, config: &BridgeConfigMqtt, discovery_prefix: &str, projector: &BridgeMqttProjector) {
+        let (projector_name, serial_number, manufacturer_name, product_name, inputs) = match projector.shared_handler.lock() {
+            Ok(handler) => {
+                let options = handler.options();
+                let mut inputs: Vec<String> = options.commands.get(b"1INPT")
+                    .map(|command_spec| command_spec.inputs.keys()
+                        .filter(|input| input.as_slice() != b"?")
+                        .map(|input| String::from_utf8_lossy(input).into_owned())
+                        .collect())
+                    .unwrap_or_default();
+                inputs.sort();
+
+                (
+                    String::from_utf8_lossy(&options.projector_name).into_owned(),
+                    String::from_utf8_lossy(&options.serial_number).into_owned(),
+                    String::from_utf8_lossy(&options.manufacturer_name).into_owned(),
+                    String::from_utf8_lossy(&options.product_name).into_owned(),
+                    inputs
+                )
+            },
+            Err(_) => return
+        };
+
+        let base_topic = format!("{}/{}", config.topic_prefix, projector.topic_name);
+        let device = json!({
+            "identifiers": [format!("pjlink_{}", serial_number)],
+            "name": projector_name,
+            "manufacturer": manufacturer_name,
+            "model": product_name,
+        });
+        let common = |key: &str, name: &str| json!({
+            "name": format!("{} {}", projector_name, name),
+            "unique_id": format!("pjlink_{}_{}", serial_number, key),
+            "state_topic": format!("{}/state", base_topic),
+            "availability_topic": format!("{}/availability", config.topic_prefix),
+            "device": device,
+        });
+
+        let mut entities = vec! [
+            ("switch", "power", {
+                let mut payload = common("power", "Power");
+                payload["command_topic"] = json!(format!("{}/power/set", base_topic));
+                payload["value_template"] = json!("{{ 'ON' if value_json.power in ['1', '3'] else 'OFF' }}");
+                payload["payload_on"] = json!("1");
+                payload["payload_off"] = json!("0");
+                payload["state_on"] = json!("ON");
+                payload["state_off"] = json!("OFF");
+                payload
+            }),
+            ("switch", "mute", {
+                let mut payload = common("mute", "Mute");
+                payload["command_topic"] = json!(format!("{}/mute/set", base_topic));
+                payload["value_template"] = json!("{{ 'ON' if value_json.mute in ['11', '21', '31'] else 'OFF' }}");
+                payload["payload_on"] = json!("31");
+                payload["payload_off"] = json!("30");
+                payload["state_on"] = json!("ON");
+                payload["state_off"] = json!("OFF");
+                payload
+            }),
+            ("switch", "freeze", {
+                let mut payload = common("freeze", "Freeze");
+                payload["command_topic"] = json!(format!("{}/freeze/set", base_topic));
+                payload["value_template"] = json!("{{ 'ON' if value_json.freeze == '1' else 'OFF' }}");
+                payload["payload_on"] = json!("1");
+                payload["payload_off"] = json!("0");
+                payload["state_on"] = json!("ON");
+                payload["state_off"] = json!("OFF");
+                payload
+            }),
+            ("sensor", "lamp_hours", {
+                let mut payload = common("lamp_hours", "Lamp Hours");
+                payload["value_template"] = json!("{{ value_json.lamp_hours }}");
+                payload["unit_of_measurement"] = json!("h");
+                payload
+            }),
+            ("sensor", "errors", {
+                let mut payload = common("errors", "Errors");
+                payload["value_template"] = json!("{{ value_json.errors }}");
+                payload
+            }),
+        ];
+
+        if !inputs.is_empty() {
+            let mut payload = common("input", "Input");
+            payload["command_topic"] = json!(format!("{}/input/set", base_topic));
+            payload["value_template"] = json!("{{ value_json.input }}");
+            payload["options"] = json!(inputs);
+            entities.push(("select", "input", payload));
+        }
+
+        for (component, key, payload) in entities {
+            let discovery_topic = format!("{}/{}/pjlink_{}/{}/config", discovery_prefix, component, serial_number, key);
+
+            if let Err(err) = client.publish(discovery_topic, QoS::AtLeastOnce, true, payload.to_string()) {
+                error!("Cannot publish MQTT discovery payload. {}", err);
+            }
+        }
+    }
+
+    fn describe_state(state: &PjLinkRS232ProjectorState) -> BridgeMqttState {
+        let known = |value: &[u8]| if !value.is_empty() && value.iter().all(u8::is_ascii_alphanumeric) {
+            Some(String::from_utf8_lossy(value).into_owned())
+        } else {None};
+
+        BridgeMqttState {
+            power: known(&[state.power_on]),
+            input: known(&state.input_status),
+            mute: known(&state.mute_status),
+            freeze: known(&[state.freeze_status]),
+            lamp_hours: known(&state.lamp_hours),
+            errors: known(&[
+                state.error_fan_status,
+                state.error_lamp_status,
+                state.error_temperature_status,
+                state.error_cover_open_status,
+                state.error_filter_status,
+                state.error_other_status
+            ]),
+        }
+    }
+
+    #[inline(always)]
+    fn topic_name(projector_name: &str) -> String {
+        if projector_name.is_empty() {
+            return String::from("projector");
+        }
+
+        projector_name.chars()
+            .map(|character| if character.is_ascii_alphanumeric() {character.to_ascii_lowercase()} else {'_'})
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        io::{Read, Write},
+        net::{TcpListener, TcpStream},
+        sync::mpsc::{Receiver, Sender}
+    };
+
+    use crate::rs232_bridge::test_projector;
+
+    const DEFINITION: &str = r#"
+        [general]
+        manufacturer_name = "Test"
+        product_name = "Projector"
+        software_version = "1"
+        class_type = 1
+
+        [connection]
+        baud_rate = 9600
+
+        [commands."1POWR".inputs."1"]
+        send = [0x01]
+        wait_for_response = 50
+        outputs = [{on_received_type = "value", on_received = [0x06], response_type = "default", response_value = "OK"}]
+    "#;
+
+    #[test]
+    fn rejects_colliding_topic_names() {
+        assert!(BridgeMqtt::check_topic_names(vec! ["Room 101", "Room 102", ""]).is_ok());
+
+        let err = BridgeMqtt::check_topic_names(vec! ["Room 101", "Room 102", "room-101"]).err().unwrap();
+        assert_eq!(err.message, "Projectors Room 101 and room-101 have the same MQTT topic name room_101, rename one of them");
+    }
+
+    /// Packets received by the broker, as `(topic, payload)`. Subscriptions have an empty payload.
+    fn spawn_broker(command: (String, Vec<u8>), subscriptions: usize) -> (u16, Receiver<(String, Vec<u8>)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (packet_tx, packet_rx) = channel();
+
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut subscribed = 0;
+
+            while let Some((packet_type, body)) = read_packet(&mut stream) {
+                match packet_type >> 4 {
+                    // CONNECT
+                    1 => stream.write_all(&[0x20, 0x02, 0x00, 0x00]).unwrap(),
+                    // PUBLISH
+                    3 => {
+                        let topic_length = u16::from_be_bytes([body[0], body[1]]) as usize;
+                        let topic = String::from_utf8_lossy(&body[2..2 + topic_length]).into_owned();
+                        let mut payload_start = 2 + topic_length;
+
+                        if packet_type & 0x06 != 0 {
+                            stream.write_all(&[0x40, 0x02, body[payload_start], body[payload_start + 1]]).unwrap();
+                            payload_start += 2;
+                        }
+
+                        report(&packet_tx, topic, body[payload_start..].to_vec());
+                    },
+                    // SUBSCRIBE
+                    8 => {
+                        let mut position = 2;
+                        let mut granted = Vec::new();
+
+                        while position < body.len() {
+                            let topic_length = u16::from_be_bytes([body[position], body[position + 1]]) as usize;
+                            report(&packet_tx, String::from_utf8_lossy(&body[position + 2..position + 2 + topic_length]).into_owned(), vec! []);
+                            position += 2 + topic_length + 1;
+                            granted.push(0x01);
+                            subscribed += 1;
+                        }
+
+                        stream.write_all(&[&[0x90, 2 + granted.len() as u8, body[0], body[1]][..], &granted].concat()).unwrap();
+
+                        if subscribed == subscriptions {
+                            let (topic, payload) = &command;
+                            let remaining_length = 2 + topic.len() + payload.len();
+                            stream.write_all(&[&[0x30, remaining_length as u8, 0x00, topic.len() as u8][..], topic.as_bytes(), payload].concat()).unwrap();
+                        }
+                    },
+                    // PINGREQ
+                    12 => stream.write_all(&[0xd0, 0x00]).unwrap(),
+                    _ => {}
+                }
+            }
+        });
+
+        (port, packet_rx)
+    }
+
+    fn report(packet_tx: &Sender<(String, Vec<u8>)>, topic: String, payload: Vec<u8>) {
+        packet_tx.send((topic, payload)).unwrap_or_default();
+    }
+
+    fn read_packet(stream: &mut TcpStream) -> Option<(u8, Vec<u8>)> {
+        let mut header = [0u8; 1];
+        stream.read_exact(&mut header).ok()?;
+
+        let mut remaining_length = 0usize;
+        let mut multiplier = 1;
+
+        loop {
+            let mut byte = [0u8; 1];
+            stream.read_exact(&mut byte).ok()?;
+            remaining_length += (byte[0] & 0x7f) as usize * multiplier;
+            multiplier *= 128;
+
+            if byte[0] & 0x80 == 0 {
+                break;
+            }
+        }
+
+        let mut body = vec! [0u8; remaining_length];
+        stream.read_exact(&mut body).ok()?;
+        Some((header[0], body))
+    }
+
+    #[test]
+    fn handles_commands_with_many_projectors() {
+        // More requests are queued on connection than the former fixed capacity of 64
+        let projector_count = 8;
+        let mut serial_ports = Vec::new();
+        let mut projectors = BridgeMqttProjectors::new();
+
+        for index in 1..=projector_count {
+            let projector_name = format!("Room {}", index);
+            let (serial_port, projector) = test_projector(&projector_name, DEFINITION);
+            serial_ports.push(serial_port);
+            projectors.push((projector_name, Arc::new(Mutex::new(projector))));
+        }
+
+        // The last projector answers power commands, keeping its end of the terminal open until the test is over
+        let mut serial_port = serial_ports.pop().unwrap();
+        serialport::SerialPort::set_timeout(&mut serial_port, Duration::from_secs(10)).unwrap();
+        thread::spawn(move || {
+            let mut received = [0u8; 1];
+
+            while serial_port.read_exact(&mut received).is_ok() {
+                if received == [0x01] {
+                    serial_port.write_all(&[0x06]).unwrap_or_default();
+                }
+            }
+        });
+
+        let command = (String::from("pjlink/room_8/power/set"), b"1".to_vec());
+        let (port, packet_rx) = spawn_broker(command, projector_count * MQTT_COMMANDS.len());
+
+        let config = toml::from_str::<BridgeConfigMqtt>(&format!(
+            "host = \"127.0.0.1\"\nport = {}\ndiscovery_prefix = \"homeassistant\"",
+            port
+        )).unwrap();
+        BridgeMqtt::spawn(config, projectors);
+
+        let mut subscriptions = 0;
+        let mut discovery_payloads = 0;
+
+        loop {
+            let (topic, payload) = packet_rx.recv_timeout(Duration::from_secs(10)).unwrap();
+
+            if topic.ends_with("/set") {
+                subscriptions += 1;
+            } else if topic.starts_with("homeassistant/") {
+                discovery_payloads += 1;
+            } else if topic == "pjlink/room_8/power/result" {
+                assert_eq!(payload, b"OK");
+                break;
+            }
+        }
+
+        assert_eq!(subscriptions, projector_count * MQTT_COMMANDS.len());
+        assert_eq!(discovery_payloads, projector_count * 5);
+    }
+}
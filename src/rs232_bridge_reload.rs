@@ -0,0 +1,204 @@
+use std::{
+    collections::HashMap,
+    fs,
+    sync::{Arc, Mutex, mpsc::{Sender, channel}},
+    thread,
+    time::{Duration, SystemTime}
+};
+
+use log::{error, info, warn};
+use signal_hook::{consts::SIGHUP, iterator::Signals};
+
+use crate::{
+    rs232_bridge::PjLinkRS232Projector,
+    rs232_bridge_config::{BridgeConfig, BridgeConfigProjector},
+    rs232_bridge_credentials::BridgeCredentials,
+    rs232_bridge_def::BridgeDefinition
+};
+
+/// How often definition files are checked for changes when watching is enabled.
+const DEFINITION_WATCH_INTERVAL: Duration = Duration::from_secs(2);
+
+pub type BridgeReloadProjectors = Vec<(BridgeConfigProjector, Arc<Mutex<PjLinkRS232Projector>>)>;
+
+/// Applies the projector options set through flags or environment, which take precedence over the configuration file.
+pub type BridgeReloadOverrides = Box<dyn Fn(&mut BridgeConfigProjector) + Send>;
+
+/// Configuration file re-read on reload for the projector settings.
+pub struct BridgeReloadConfig {
+    pub path: String,
+    pub apply_overrides: Option<BridgeReloadOverrides>,
+}
+
+pub struct BridgeReload {
+}
+
+impl BridgeReload {
+    /// Reloads every projector definition on SIGHUP and, if `watch_definitions` is set,
+    /// whenever a definition file (or the configuration file) is modified.
+    /// The configuration and credentials files are reloaded along with them.
+    pub fn spawn(
+        mut projectors: BridgeReloadProjectors,
+        watch_definitions: bool,
+        credentials: Option<Arc<BridgeCredentials>>,
+        config: Option<BridgeReloadConfig>
+    ) {
+        let (reload_tx, reload_rx) = channel::<()>();
+
+        match Signals::new([SIGHUP]) {
+            Ok(mut signals) => {
+                let signal_reload_tx = reload_tx.clone();
+                thread::spawn(move || {
+                    for _ in signals.forever() {
+                        info!("Received SIGHUP, reloading definitions");
+                        signal_reload_tx.send(()).unwrap_or_default();
+                    }
+                });
+            },
+            Err(err) => error!("Cannot listen for SIGHUP, definitions won't be reloaded. {}", err)
+        }
+
+        if watch_definitions {
+            let mut definition_paths: Vec<String> = projectors.iter().map(|(projector, _)| projector.definition.clone()).collect();
+            definition_paths.extend(config.as_ref().map(|config| config.path.clone()));
+
+            thread::spawn(move || Self::watch_definitions(definition_paths, reload_tx));
+        }
+
+        thread::spawn(move || {
+            while let Ok(()) = reload_rx.recv() {
+                if let Some(config) = &config {
+                    Self::reload_config(config, &mut projectors);
+                }
+
+                Self::reload(&projectors);
+
+                if let Some(credentials) = &credentials {
+                    match credentials.reload() {
+                        Ok(count) => info!("Credentials reloaded, {} credentials", count),
+                        Err(err) => error!("Cannot reload credentials, keeping the current ones. {}", err.message)
+                    }
+                }
+            }
+        });
+    }
+
+    /// Takes the reloadable settings of every projector from the configuration file.
+    /// Projectors are matched by name; the current settings are kept when the file is invalid.
+    fn reload_config(config: &BridgeReloadConfig, projectors: &mut BridgeReloadProjectors) {
+        let mut config_projectors = match BridgeConfig::from_file(config.path.clone()) {
+            Ok(config_file) => config_file.projectors,
+            Err(err) => {
+                error!("Cannot reload configuration file {}, keeping the current projector settings. {}", config.path, err.message);
+                return;
+            }
+        };
+
+        if let Some(apply_overrides) = &config.apply_overrides {
+            if config_projectors.is_empty() {
+                config_projectors.push(BridgeConfigProjector::default());
+            }
+
+            if config_projectors.len() > 1 {
+                error!("Cannot reload configuration file {}, projector options set from command line or environment need a single projector", config.path);
+                return;
+            }
+
+            apply_overrides(&mut config_projectors[0]);
+        }
+
+        for (projector, _) in projectors.iter_mut() {
+            let config_projector = match config_projectors.iter().find(|config_projector| config_projector.name == projector.name) {
+                Some(config_projector) => config_projector,
+                None => {
+                    warn!("Projector {}: Removed from the configuration file, keeps running until the bridge is restarted", projector.name);
+                    continue;
+                }
+            };
+
+            if config_projector.serial_port.is_empty() {
+                error!("Projector {}: No serial port in the configuration file, keeping the current settings", projector.name);
+                continue;
+            }
+
+            if projector.requires_restart(config_projector) {
+                warn!("Projector {}: Listener, API, access or idle settings changed, restart the bridge to apply them", projector.name);
+            }
+
+            projector.apply_reloadable(config_projector);
+        }
+
+        for config_projector in &config_projectors {
+            if !projectors.iter().any(|(projector, _)| projector.name == config_projector.name) {
+                warn!("Projector {}: Added to the configuration file, restart the bridge to start it", config_projector.name);
+            }
+        }
+    }
+
+    fn reload(projectors: &BridgeReloadProjectors) {
+        for (projector, shared_handler) in projectors {
+            let definition = match BridgeDefinition::from_file(projector.definition.clone()) {
+                Ok(definition) => definition,
+                Err(err) => {
+                    error!("Projector {}: Cannot reload definition, keeping the current one. {}", projector.name, err.message);
+                    continue;
+                }
+            };
+
+            let passwords = projector.resolve_password()
+                .and_then(|password| projector.resolve_admin_password().map(|admin_password| (password, admin_password)));
+
+            let (password, admin_password) = match passwords {
+                Ok(passwords) => passwords,
+                Err(err) => {
+                    error!("Projector {}: Cannot reload password, keeping the current one. {}", projector.name, err.message);
+                    continue;
+                }
+            };
+
+            match shared_handler.lock() {
+                Ok(mut handler) => {
+                    handler.set_password(password);
+                    handler.set_admin_password(admin_password);
+
+                    match handler.reload_definition(definition, projector) {
+                        Ok(()) => info!("Projector {}: Definition reloaded", projector.name),
+                        Err(err) => error!("Projector {}: Cannot apply reloaded definition. {}", projector.name, err.message)
+                    }
+                },
+                Err(_) => error!("Projector {}: Handler is unavailable, definition not reloaded", projector.name)
+            }
+        }
+    }
+
+    fn watch_definitions(definition_paths: Vec<String>, reload_tx: Sender<()>) {
+        let mut modified_times: HashMap<String, Option<SystemTime>> = definition_paths.iter()
+            .map(|path| (path.clone(), Self::modified_time(path)))
+            .collect();
+
+        loop {
+            thread::sleep(DEFINITION_WATCH_INTERVAL);
+
+            let mut changed = false;
+
+            for (path, modified_time) in modified_times.iter_mut() {
+                let current_modified_time = Self::modified_time(path);
+
+                if current_modified_time != *modified_time {
+                    info!("Definition {} changed, reloading definitions", path);
+                    *modified_time = current_modified_time;
+                    changed = true;
+                }
+            }
+
+            if changed && reload_tx.send(()).is_err() {
+                break;
+            }
+        }
+    }
+
+    #[inline(always)]
+    fn modified_time(path: &str) -> Option<SystemTime> {
+        fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+    }
+}
@@ -0,0 +1,425 @@
+use std::{
+    convert::TryInto,
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration
+};
+
+use chrono::{DateTime, Datelike, Local, Timelike};
+use log::{error, info, warn};
+
+use crate::{
+    rs232_bridge::{PjLinkRS232Credentials, PjLinkRS232Projector},
+    rs232_bridge_config::BridgeConfigSchedule
+};
+
+/// Connection id used in logs for commands sent by schedules.
+pub const SCHEDULE_CONNECTION_ID: u64 = u64::MAX - 3;
+
+pub type BridgeScheduleProjectors = Vec<(String, Arc<Mutex<PjLinkRS232Projector>>)>;
+
+/// Cron expression (`minute hour day-of-month month day-of-week`), each field as a bit set.
+/// Fields accept `*`, values, ranges (`1-5`), steps (`*/15`, `0-30/10`) and lists of them (`0,30`).
+#[derive(Clone)]
+pub struct BridgeCron {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    /// Standard cron: when both day fields are restricted, matching either one is enough
+    days_restricted: bool,
+    weekdays_restricted: bool,
+}
+
+/// PJLink command sent by a schedule, e.g. `1POWR 0`.
+struct BridgeScheduleAction {
+    request_body: [u8; 5],
+    request_parameter: Vec<u8>,
+}
+
+struct BridgeScheduleEntry {
+    name: String,
+    cron: BridgeCron,
+    actions: Vec<BridgeScheduleAction>,
+    projectors: Vec<String>,
+    duration: Option<Duration>,
+}
+
+/// Scheduled event a projector takes part in, lasting `duration` from each cron time.
+#[derive(Clone)]
+pub struct BridgeScheduleEvent {
+    pub name: String,
+    cron: BridgeCron,
+    duration: Duration,
+}
+
+pub struct Error {
+    pub message: String
+}
+
+impl BridgeCron {
+    pub fn parse(expression: &str) -> Result<BridgeCron, Error> {
+        let fields: Vec<&str> = expression.split_whitespace().collect();
+
+        if fields.len() != 5 {
+            return Err(Error {message: format!("Invalid cron expression \"{}\", expected 5 fields", expression)});
+        }
+
+        let weekdays = Self::parse_field(fields[4], 0, 7)?;
+
+        Ok(BridgeCron {
+            minutes: Self::parse_field(fields[0], 0, 59)?,
+            hours: Self::parse_field(fields[1], 0, 23)?,
+            days: Self::parse_field(fields[2], 1, 31)?,
+            months: Self::parse_field(fields[3], 1, 12)?,
+            // Both 0 and 7 are Sunday
+            weekdays: if weekdays & (1 << 7) != 0 {weekdays | 1} else {weekdays},
+            days_restricted: fields[2] != "*",
+            weekdays_restricted: fields[4] != "*",
+        })
+    }
+
+    fn parse_field(field: &str, min: u32, max: u32) -> Result<u64, Error> {
+        let invalid = || Error {message: format!("Invalid cron field \"{}\", values must be between {} and {}", field, min, max)};
+        let mut bits = 0u64;
+
+        for part in field.split(',') {
+            let (range, step) = match part.split_once('/') {
+                Some((range, step)) => (range, step.parse::<u32>().map_err(|_| invalid())?),
+                None => (part, 1)
+            };
+
+            let (start, end) = match range {
+                "*" => (min, max),
+                _ => match range.split_once('-') {
+                    Some((start, end)) => (start.parse::<u32>().map_err(|_| invalid())?, end.parse::<u32>().map_err(|_| invalid())?),
+                    None => {
+                        let value = range.parse::<u32>().map_err(|_| invalid())?;
+                        // `5/10` means from 5 to the end, every 10
+                        (value, if step > 1 {max} else {value})
+                    }
+                }
+            };
+
+            if step == 0 || start < min || end > max || start > end {
+                return Err(invalid());
+            }
+
+            for value in (start..=end).step_by(step as usize) {
+                bits |= 1 << value;
+            }
+        }
+
+        Ok(bits)
+    }
+
+    pub fn matches(&self, time: &DateTime<Local>) -> bool {
+        let day_matches = self.days & (1 << time.day()) != 0;
+        let weekday_matches = self.weekdays & (1 << time.weekday().num_days_from_sunday()) != 0;
+
+        let date_matches = if self.days_restricted && self.weekdays_restricted {day_matches || weekday_matches}
+            else {day_matches && weekday_matches};
+
+        self.minutes & (1 << time.minute()) != 0
+            && self.hours & (1 << time.hour()) != 0
+            && self.months & (1 << time.month()) != 0
+            && date_matches
+    }
+}
+
+impl BridgeScheduleEvent {
+    /// Whether the event started at a minute within `duration` before `time` (its own minute included).
+    pub fn is_in_progress(&self, time: &DateTime<Local>) -> bool {
+        (0..self.duration.as_secs().div_ceil(60) as i64)
+            .filter_map(|minutes| time.checked_sub_signed(chrono::Duration::minutes(minutes)))
+            .any(|start| self.cron.matches(&start))
+    }
+}
+
+impl BridgeScheduleAction {
+    fn parse(action: &str) -> Result<BridgeScheduleAction, Error> {
+        let (command, parameter) = action.trim().split_once(' ').unwrap_or((action.trim(), ""));
+        let command = command.trim_start_matches('%').to_ascii_uppercase();
+
+        let request_body: [u8; 5] = command.as_bytes().try_into()
+            .map_err(|_| Error {message: format!("Invalid action \"{}\", expected a command with class, e.g. 1POWR 0", action)})?;
+
+        if parameter.is_empty() {
+            return Err(Error {message: format!("Invalid action \"{}\", missing transmission parameter", action)});
+        }
+
+        Ok(BridgeScheduleAction {
+            request_body,
+            request_parameter: Vec::from(parameter.as_bytes()),
+        })
+    }
+}
+
+impl BridgeScheduleEntry {
+    #[inline(always)]
+    fn applies_to(&self, projector_name: &str) -> bool {
+        self.projectors.is_empty() || self.projectors.iter().any(|projector| projector == projector_name)
+    }
+}
+
+pub struct BridgeSchedule {
+    entries: Vec<BridgeScheduleEntry>,
+}
+
+impl BridgeSchedule {
+    /// Parses every schedule, so mistakes are reported on start rather than when they should have run.
+    pub fn from_config(schedules: &[BridgeConfigSchedule]) -> Result<BridgeSchedule, Error> {
+        Ok(BridgeSchedule {entries: Self::parse_schedules(schedules)?})
+    }
+
+    /// Scheduled events (schedules with a `duration`) `projector_name` takes part in.
+    pub fn events_for(&self, projector_name: &str) -> Vec<BridgeScheduleEvent> {
+        self.entries.iter()
+            .filter(|entry| entry.applies_to(projector_name))
+            .filter_map(|entry| entry.duration.map(|duration| BridgeScheduleEvent {
+                name: entry.name.clone(),
+                cron: entry.cron.clone(),
+                duration,
+            }))
+            .collect()
+    }
+
+    /// Runs schedule actions at their cron times (local time), through the same handler as PJLink clients.
+    /// Actions are rejected in maintenance mode, and set commands in read-only mode.
+    pub fn spawn(self, projectors: BridgeScheduleProjectors) {
+        if self.entries.iter().all(|entry| entry.actions.is_empty()) {
+            return;
+        }
+
+        let projectors = Arc::new(projectors);
+        let entries = Arc::new(self.entries);
+
+        thread::spawn(move || {
+            let mut last_minute = None;
+
+            loop {
+                // Wake up right after the start of every minute
+                let now = Local::now();
+                let elapsed = Duration::new(now.second() as u64, now.nanosecond() % 1_000_000_000);
+                thread::sleep(Duration::from_secs(60).checked_sub(elapsed).unwrap_or_default() + Duration::from_millis(10));
+
+                let now = Local::now();
+
+                // An early wake up (e.g. the clock was set back a little) must not run the same minute twice
+                if !Self::is_new_minute(&mut last_minute, &now) {
+                    continue;
+                }
+
+                Self::run_due(&entries, &projectors, &now);
+            }
+        });
+    }
+
+    /// Records the minute of `now`, telling whether it differs from the last one recorded.
+    fn is_new_minute(last_minute: &mut Option<DateTime<Local>>, now: &DateTime<Local>) -> bool {
+        let minute = now.with_second(0).and_then(|minute| minute.with_nanosecond(0));
+
+        if minute.is_some() && minute == *last_minute {
+            return false;
+        }
+
+        *last_minute = minute;
+        true
+    }
+
+    fn run_due(entries: &Arc<Vec<BridgeScheduleEntry>>, projectors: &Arc<BridgeScheduleProjectors>, now: &DateTime<Local>) {
+        for (index, entry) in entries.iter().enumerate() {
+            if !entry.actions.is_empty() && entry.cron.matches(now) {
+                let projectors = projectors.clone();
+                let entries = entries.clone();
+
+                // Slow projectors mustn't delay the next minute
+                thread::spawn(move || Self::run(&entries[index], &projectors));
+            }
+        }
+    }
+
+    fn parse_schedules(schedules: &[BridgeConfigSchedule]) -> Result<Vec<BridgeScheduleEntry>, Error> {
+        schedules.iter()
+            .enumerate()
+            .map(|(index, schedule)| {
+                let name = schedule.name.clone().unwrap_or_else(|| format!("#{}", index + 1));
+                let with_name = |err: Error| Error {message: format!("Schedule {}: {}", name, err.message)};
+
+                if schedule.actions.is_empty() && schedule.duration.is_none() {
+                    return Err(with_name(Error {message: String::from("Set actions, a duration or both")}));
+                }
+
+                Ok(BridgeScheduleEntry {
+                    cron: BridgeCron::parse(&schedule.cron).map_err(with_name)?,
+                    actions: schedule.actions.iter()
+                        .map(|action| BridgeScheduleAction::parse(action))
+                        .collect::<Result<Vec<_>, Error>>()
+                        .map_err(with_name)?,
+                    projectors: schedule.projectors.clone(),
+                    duration: schedule.duration.map(|duration| Duration::from_secs(duration * 60)),
+                    name,
+                })
+            })
+            .collect()
+    }
+
+    fn run(entry: &BridgeScheduleEntry, projectors: &BridgeScheduleProjectors) {
+        info!("Running schedule {}", entry.name);
+
+        let scheduled_projectors = projectors.iter()
+            .filter(|(name, _)| entry.applies_to(name));
+
+        for (name, shared_handler) in scheduled_projectors {
+            for action in &entry.actions {
+                let response = match shared_handler.lock() {
+                    Ok(mut handler) => handler.handle_bridge_command(
+                        action.request_body,
+                        &action.request_parameter,
+                        &SCHEDULE_CONNECTION_ID,
+                        PjLinkRS232Credentials::User
+                    ),
+                    Err(_) => {
+                        error!("Projector {}: Handler is unavailable, schedule {} not run", name, entry.name);
+                        break;
+                    }
+                };
+
+                let response_text = PjLinkRS232Projector::response_text(&response);
+                let log_message = format!(
+                    "Projector {}: Schedule {}: Command: {}, Tx: {}, Response: {}",
+                    name,
+                    entry.name,
+                    String::from_utf8_lossy(&action.request_body),
+                    String::from_utf8_lossy(&action.request_parameter),
+                    response_text
+                );
+
+                if response_text.starts_with("ERR") {warn!("{}", log_message)} else {info!("{}", log_message)}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn at(day: u32, hour: u32, minute: u32) -> DateTime<Local> {
+        // March 2021 starts on a Monday
+        Local.with_ymd_and_hms(2021, 3, day, hour, minute, 30).unwrap()
+    }
+
+    fn schedule(toml: &str) -> Result<BridgeSchedule, Error> {
+        #[derive(serde::Deserialize)]
+        struct Schedules {
+            schedules: Vec<BridgeConfigSchedule>,
+        }
+
+        BridgeSchedule::from_config(&toml::from_str::<Schedules>(toml).unwrap().schedules)
+    }
+
+    #[test]
+    fn parses_cron_fields() {
+        assert_eq!(BridgeCron::parse_field("*", 0, 7).ok(), Some(0b1111_1111));
+        assert_eq!(BridgeCron::parse_field("5", 0, 59).ok(), Some(1 << 5));
+        assert_eq!(BridgeCron::parse_field("1-5", 0, 7).ok(), Some(0b11_1110));
+        assert_eq!(BridgeCron::parse_field("*/15", 0, 59).ok(), Some(1 << 0 | 1 << 15 | 1 << 30 | 1 << 45));
+        assert_eq!(BridgeCron::parse_field("0-30/10", 0, 59).ok(), Some(1 << 0 | 1 << 10 | 1 << 20 | 1 << 30));
+        assert_eq!(BridgeCron::parse_field("50/5", 0, 59).ok(), Some(1 << 50 | 1 << 55));
+        assert_eq!(BridgeCron::parse_field("0,30", 0, 59).ok(), Some(1 << 0 | 1 << 30));
+        assert_eq!(BridgeCron::parse_field("1-2,10-20/5", 1, 31).ok(), Some(1 << 1 | 1 << 2 | 1 << 10 | 1 << 15 | 1 << 20));
+    }
+
+    #[test]
+    fn rejects_invalid_cron_expressions() {
+        for expression in ["0 8 * *", "0 8 * * * *", "60 8 * * *", "0 24 * * *", "0 8 0 * *", "0 8 * 13 *", "0 8 * * 8",
+            "5-1 8 * * *", "*/0 8 * * *", "a 8 * * *", "0-30/ 8 * * *"]
+        {
+            assert!(BridgeCron::parse(expression).is_err(), "{}", expression);
+        }
+    }
+
+    #[test]
+    fn matches_cron_times() {
+        let cron = BridgeCron::parse("55 7 * * 1-5").ok().unwrap();
+        assert!(cron.matches(&at(1, 7, 55)));
+        assert!(cron.matches(&at(5, 7, 55)));
+        assert!(!cron.matches(&at(1, 7, 56)));
+        assert!(!cron.matches(&at(1, 8, 55)));
+        // Saturday and Sunday
+        assert!(!cron.matches(&at(6, 7, 55)));
+        assert!(!cron.matches(&at(7, 7, 55)));
+
+        // Sunday is either 0 or 7
+        assert!(BridgeCron::parse("0 0 * * 0").ok().unwrap().matches(&at(7, 0, 0)));
+        assert!(BridgeCron::parse("0 0 * * 7").ok().unwrap().matches(&at(7, 0, 0)));
+
+        assert!(BridgeCron::parse("0 0 * 3 *").ok().unwrap().matches(&at(7, 0, 0)));
+        assert!(!BridgeCron::parse("0 0 * 4 *").ok().unwrap().matches(&at(7, 0, 0)));
+    }
+
+    #[test]
+    fn matches_either_day_field_when_both_are_restricted() {
+        // The 16th (a Tuesday) or any Monday
+        let cron = BridgeCron::parse("0 12 16 * 1").ok().unwrap();
+        assert!(cron.matches(&at(16, 12, 0)));
+        assert!(cron.matches(&at(8, 12, 0)));
+        assert!(!cron.matches(&at(9, 12, 0)));
+
+        // Only the day of month is restricted
+        let cron = BridgeCron::parse("0 12 16 * *").ok().unwrap();
+        assert!(cron.matches(&at(16, 12, 0)));
+        assert!(!cron.matches(&at(8, 12, 0)));
+
+        // Only the weekday is restricted
+        let cron = BridgeCron::parse("0 12 * * 1").ok().unwrap();
+        assert!(cron.matches(&at(8, 12, 0)));
+        assert!(!cron.matches(&at(16, 12, 0)));
+    }
+
+    #[test]
+    fn runs_each_minute_once() {
+        let mut last_minute = None;
+
+        assert!(BridgeSchedule::is_new_minute(&mut last_minute, &at(1, 8, 0)));
+        assert!(!BridgeSchedule::is_new_minute(&mut last_minute, &Local.with_ymd_and_hms(2021, 3, 1, 8, 0, 59).unwrap()));
+        assert!(BridgeSchedule::is_new_minute(&mut last_minute, &at(1, 8, 1)));
+    }
+
+    #[test]
+    fn tracks_scheduled_events_in_progress() {
+        let schedule = schedule(r#"
+            [[schedules]]
+            name = "Lectures"
+            cron = "0 8 * * 1-5"
+            duration = 120
+            projectors = ["Lecture hall"]
+
+            [[schedules]]
+            cron = "0 22 * * *"
+            actions = ["1POWR 0"]
+        "#).ok().unwrap();
+
+        assert!(schedule.events_for("Room 101").is_empty());
+
+        let events = schedule.events_for("Lecture hall");
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].name, "Lectures");
+
+        assert!(!events[0].is_in_progress(&at(1, 7, 59)));
+        assert!(events[0].is_in_progress(&at(1, 8, 0)));
+        assert!(events[0].is_in_progress(&at(1, 9, 59)));
+        assert!(!events[0].is_in_progress(&at(1, 10, 0)));
+        // Sunday
+        assert!(!events[0].is_in_progress(&at(7, 9, 0)));
+    }
+
+    #[test]
+    fn requires_actions_or_a_duration() {
+        assert!(schedule("[[schedules]]\ncron = \"0 8 * * *\"").is_err());
+        assert!(schedule("[[schedules]]\ncron = \"0 8 * * *\"\nduration = 60").is_ok());
+    }
+}
@@ -0,0 +1,103 @@
+use std::{
+    fs,
+    io::ErrorKind,
+    net::{SocketAddr, UdpSocket},
+    sync::{Arc, atomic::{AtomicBool, Ordering}},
+    thread::{self, JoinHandle},
+    time::Duration
+};
+
+use log::{debug, error, info};
+
+/// PJLink class 2 search requests and replies use this UDP port, whatever the projector TCP port.
+const SEARCH_PORT: u16 = 4352;
+
+/// How often the server checks whether the bridge is shutting down, while no search request comes in.
+const RECEIVE_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+pub struct Error {
+    pub message: String
+}
+
+/// Answers PJLink class 2 search requests (`%2SRCH`) with the MAC address of the bridge host (`%2ACKN=`),
+/// like the pjlink_bridge UDP listener, for projectors served by the access server.
+pub struct BridgeSearchServer {
+}
+
+impl BridgeSearchServer {
+    /// Once `stopping` is set, the server stops answering search requests.
+    pub fn spawn(projector_name: String, listen_address: &str, stopping: Arc<AtomicBool>) -> Result<JoinHandle<()>, Error> {
+        let mac_address = Self::mac_address()
+            .ok_or_else(|| Error {message: String::from("Cannot find the MAC address of a network interface")})?;
+        let socket = UdpSocket::bind((listen_address, SEARCH_PORT))
+            .map_err(|err| Error {message: format!("Cannot listen on {}:{} (UDP). {}", listen_address, SEARCH_PORT, err)})?;
+        socket.set_read_timeout(Some(RECEIVE_POLL_INTERVAL))
+            .map_err(|err| Error {message: format!("Cannot set up search listener. {}", err)})?;
+
+        info!("Projector {}: Answering PJLink search requests on {}:{} (UDP)", projector_name, listen_address, SEARCH_PORT);
+
+        thread::Builder::new()
+            .name(format!("search server {}", projector_name))
+            .spawn(move || {
+                let reply = format!("%2ACKN={}\r", mac_address);
+                let mut datagram = [0u8; 64];
+
+                while !stopping.load(Ordering::Relaxed) {
+                    let (length, source) = match socket.recv_from(&mut datagram) {
+                        Ok(received) => received,
+                        Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => continue,
+                        Err(err) => {
+                            error!("Projector {}: Cannot receive PJLink search requests. {}", projector_name, err);
+                            break;
+                        }
+                    };
+
+                    if !Self::is_search(&datagram[..length]) {
+                        continue;
+                    }
+
+                    // Controllers listen for replies on the PJLink port
+                    let controller = SocketAddr::new(source.ip(), SEARCH_PORT);
+                    debug!("Projector {}: Search request from {}", projector_name, source.ip());
+
+                    if let Err(err) = socket.send_to(reply.as_bytes(), controller) {
+                        debug!("Projector {}: Cannot answer search request from {}. {}", projector_name, controller, err);
+                    }
+                }
+            })
+            .map_err(|err| Error {message: format!("Cannot start search thread. {}", err)})
+    }
+
+    fn is_search(datagram: &[u8]) -> bool {
+        datagram.strip_suffix(b"\r").unwrap_or(datagram) == b"%2SRCH"
+    }
+
+    /// Address of the first network interface (by name) that has one, as listed by Linux.
+    fn mac_address() -> Option<String> {
+        let mut interfaces: Vec<_> = fs::read_dir("/sys/class/net").ok()?
+            .filter_map(|interface| interface.ok())
+            .map(|interface| interface.path())
+            .collect();
+        interfaces.sort();
+
+        interfaces.iter()
+            .filter_map(|interface| fs::read_to_string(interface.join("address")).ok())
+            .map(|address| address.trim().to_ascii_lowercase())
+            // The loopback interface reports a zero address
+            .find(|address| address.len() == 17 && address != "00:00:00:00:00:00")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recognizes_search_requests() {
+        assert!(BridgeSearchServer::is_search(b"%2SRCH\r"));
+        assert!(BridgeSearchServer::is_search(b"%2SRCH"));
+        assert!(!BridgeSearchServer::is_search(b"%1SRCH\r"));
+        assert!(!BridgeSearchServer::is_search(b"%2ACKN=00:11:22:33:44:55\r"));
+        assert!(!BridgeSearchServer::is_search(b""));
+    }
+}
@@ -0,0 +1,145 @@
+use std::{
+    sync::{Arc, Mutex, MutexGuard, TryLockError, atomic::{AtomicBool, Ordering}, mpsc::channel},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant}
+};
+
+use log::{error, info, warn};
+use signal_hook::{consts::{SIGINT, SIGTERM}, iterator::Signals};
+
+use crate::{rs232_bridge::{PjLinkRS232ConnectorHealth, PjLinkRS232Projector}, rs232_bridge_systemd::BridgeSystemd};
+
+/// How long in-flight serial exchanges may take, across every projector, before projectors are shut down anyway.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long the connector thread may take to close the serial port, once asked to after `SHUTDOWN_TIMEOUT`.
+const CONNECTOR_STOP_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Interval between attempts to acquire a projector handler during shutdown.
+const SHUTDOWN_LOCK_INTERVAL: Duration = Duration::from_millis(10);
+
+pub type BridgeShutdownProjectors = Vec<(String, Arc<Mutex<PjLinkRS232Projector>>, Arc<PjLinkRS232ConnectorHealth>)>;
+
+enum BridgeShutdownReason {
+    Signal(i32),
+    ListenersStopped
+}
+
+pub struct BridgeShutdown {
+}
+
+impl BridgeShutdown {
+    /// Blocks until SIGTERM/SIGINT is received or every listener has stopped, then sets `stopping` so listeners
+    /// stop accepting clients, and shuts down every projector in parallel.
+    /// Returns the process exit status: 0 when stopped by a signal and every projector closed cleanly, 1 otherwise.
+    pub fn wait(projectors: BridgeShutdownProjectors, handles: Vec<JoinHandle<()>>, stopping: Arc<AtomicBool>) -> i32 {
+        let (shutdown_tx, shutdown_rx) = channel::<BridgeShutdownReason>();
+
+        match Signals::new([SIGTERM, SIGINT]) {
+            Ok(mut signals) => {
+                let signal_shutdown_tx = shutdown_tx.clone();
+                thread::spawn(move || {
+                    if let Some(signal) = signals.forever().next() {
+                        signal_shutdown_tx.send(BridgeShutdownReason::Signal(signal)).unwrap_or_default();
+                    }
+                });
+            },
+            Err(err) => error!("Cannot listen for SIGTERM/SIGINT, shutdown won't be graceful. {}", err)
+        }
+
+        thread::spawn(move || {
+            for handle in handles {
+                if handle.join().is_err() {
+                    error!("A projector listener has stopped unexpectedly");
+                }
+            }
+
+            shutdown_tx.send(BridgeShutdownReason::ListenersStopped).unwrap_or_default();
+        });
+
+        let mut exit_status = match shutdown_rx.recv() {
+            Ok(BridgeShutdownReason::Signal(signal)) => {
+                info!("Received signal {}, shutting down", signal);
+                0
+            },
+            Ok(BridgeShutdownReason::ListenersStopped) | Err(_) => {
+                error!("Every projector listener has stopped, shutting down");
+                1
+            }
+        };
+
+        BridgeSystemd::notify_stopping();
+        stopping.store(true, Ordering::Relaxed);
+
+        let deadline = Instant::now() + SHUTDOWN_TIMEOUT;
+        let shutdowns: Vec<_> = projectors.into_iter()
+            .map(|(projector_name, shared_handler, health)| thread::spawn(move || Self::shutdown(&projector_name, &shared_handler, &health, deadline)))
+            .collect();
+
+        for shutdown in shutdowns {
+            if !shutdown.join().unwrap_or(false) {
+                exit_status = 1;
+            }
+        }
+
+        exit_status
+    }
+
+    /// Shuts down one projector, closing its serial port without shutdown sequence if it's still busy at `deadline`.
+    /// Returns whether it was shut down cleanly.
+    fn shutdown(
+        projector_name: &str,
+        shared_handler: &Arc<Mutex<PjLinkRS232Projector>>,
+        health: &PjLinkRS232ConnectorHealth,
+        deadline: Instant
+    ) -> bool {
+        match Self::lock(shared_handler, deadline) {
+            Some(mut handler) => if let Err(err) = handler.shutdown() {
+                error!("Projector {}: {}", projector_name, err.message);
+                false
+            } else {
+                info!("Projector {}: Serial port closed", projector_name);
+                true
+            },
+            None => {
+                warn!("Projector {}: Serial exchange didn't finish in time, closing the serial port without shutdown sequence", projector_name);
+                health.request_stop();
+
+                if Self::wait_stopped(health) {
+                    info!("Projector {}: Serial port closed", projector_name);
+                } else {
+                    error!("Projector {}: Serial port didn't close in time", projector_name);
+                }
+
+                false
+            }
+        }
+    }
+
+    /// Waits for the connector thread to close the serial port, up to `CONNECTOR_STOP_TIMEOUT`.
+    fn wait_stopped(health: &PjLinkRS232ConnectorHealth) -> bool {
+        let deadline = Instant::now() + CONNECTOR_STOP_TIMEOUT;
+
+        while !health.is_stopped() {
+            if Instant::now() >= deadline {
+                return false;
+            }
+
+            thread::sleep(SHUTDOWN_LOCK_INTERVAL);
+        }
+
+        true
+    }
+
+    /// Waits for the in-flight command (if any) to finish, up to `deadline`.
+    fn lock(shared_handler: &Arc<Mutex<PjLinkRS232Projector>>, deadline: Instant) -> Option<MutexGuard<'_, PjLinkRS232Projector>> {
+        loop {
+            match shared_handler.try_lock() {
+                Ok(handler) => return Some(handler),
+                Err(TryLockError::Poisoned(poisoned)) => return Some(poisoned.into_inner()),
+                Err(TryLockError::WouldBlock) if Instant::now() < deadline => thread::sleep(SHUTDOWN_LOCK_INTERVAL),
+                Err(TryLockError::WouldBlock) => return None
+            }
+        }
+    }
+}
@@ -0,0 +1,109 @@
+use std::{fs, io::ErrorKind, path::{Path, PathBuf}};
+use serde::{Deserialize, Serialize};
+
+use crate::rs232_bridge::PjLinkRS232ProjectorMode;
+
+/// State persisted by the bridge across restarts, one file per projector (see `BridgeConfigProjector::state_id`).
+#[derive(Deserialize, Serialize, Default)]
+pub struct BridgeState {
+    #[serde(skip)]
+    path: PathBuf,
+    pub serial_number: Option<String>,
+    pub mode: Option<PjLinkRS232ProjectorMode>,
+    /// Powered-on time counted by the bridge since the last lamp replacement, in seconds
+    pub lamp_seconds: Option<u64>,
+    /// Powered-on time counted by the bridge since the last filter replacement, in seconds
+    pub filter_seconds: Option<u64>,
+}
+
+pub struct Error {
+    pub message: String
+}
+
+impl BridgeState {
+    /// Loads the state stored for `state_id` inside `state_directory`, creating the directory if needed.
+    /// A missing state file results in an empty state.
+    pub fn open(state_directory: &str, state_id: &str) -> Result<BridgeState, Error> {
+        if let Err(err) = fs::create_dir_all(state_directory) {
+            return Err(Error {message: format!("Cannot create state directory {}: {}", state_directory, err)});
+        }
+
+        let path = Path::new(state_directory).join(Self::file_name(state_id));
+
+        let mut state = match fs::read_to_string(&path) {
+            Ok(file_content) => match toml::from_str::<BridgeState>(&file_content) {
+                Ok(content) => content,
+                Err(err) => return Err(Error {message: format!("Cannot parse state file {}: {}", path.display(), err)})
+            },
+            Err(err) if err.kind() == ErrorKind::NotFound => BridgeState::default(),
+            Err(err) => return Err(Error {message: format!("Cannot read state file {}: {}", path.display(), err)})
+        };
+
+        state.path = path;
+        Ok(state)
+    }
+
+    /// Writes the state to a temporary file and renames it over the previous one,
+    /// so an interrupted write never leaves a truncated state file behind.
+    pub fn save(&self) -> Result<(), Error> {
+        let file_content = match toml::to_string(self) {
+            Ok(file_content) => file_content,
+            Err(err) => return Err(Error {message: err.to_string()})
+        };
+
+        let temporary_path = self.path.with_extension("toml.tmp");
+
+        fs::write(&temporary_path, file_content)
+            .and_then(|_| fs::rename(&temporary_path, &self.path))
+            .map_err(|err| Error {message: format!("Cannot write state file {}: {}", self.path.display(), err)})
+    }
+
+    /// State ids are turned into file names, which must not collide (e.g. `Room 101` and `Room_101`).
+    pub fn check_state_ids<'a>(state_ids: impl IntoIterator<Item = &'a str>) -> Result<(), Error> {
+        let mut file_names = Vec::<(String, &str)>::new();
+
+        for state_id in state_ids {
+            let file_name = Self::file_name(state_id);
+
+            if let Some((_, other_state_id)) = file_names.iter().find(|(other_file_name, _)| *other_file_name == file_name) {
+                return Err(Error {
+                    message: format!("Projectors {} and {} have the same state file {}, set a different state_id on one of them", other_state_id, state_id, file_name)
+                });
+            }
+
+            file_names.push((file_name, state_id));
+        }
+
+        Ok(())
+    }
+
+    #[inline(always)]
+    fn file_name(state_id: &str) -> String {
+        let sanitized_state_id: String = state_id
+            .trim_start_matches(|character: char| !character.is_ascii_alphanumeric())
+            .chars()
+            .map(|character| if character.is_ascii_alphanumeric() || character == '-' {character} else {'_'})
+            .collect();
+
+        if sanitized_state_id.is_empty() {
+            return String::from("projector.toml");
+        }
+
+        format!("{}.toml", sanitized_state_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_state_files_after_state_ids() {
+        assert_eq!(BridgeState::file_name("Room 101"), "Room_101.toml");
+        assert_eq!(BridgeState::file_name("/dev/ttyUSB0"), "dev_ttyUSB0.toml");
+        assert_eq!(BridgeState::file_name(""), "projector.toml");
+
+        assert!(BridgeState::check_state_ids(vec! ["Room 101", "Room 102"]).is_ok());
+        assert!(BridgeState::check_state_ids(vec! ["Room 101", "Room_101"]).is_err());
+    }
+}
@@ -0,0 +1,87 @@
+use std::{net::TcpListener, os::unix::io::FromRawFd, sync::Arc, thread, time::Duration};
+
+use log::{debug, error, info, warn};
+use sd_notify::NotifyState;
+
+use crate::rs232_bridge::PjLinkRS232ConnectorHealth;
+
+pub type BridgeSystemdProjectors = Vec<(String, Arc<PjLinkRS232ConnectorHealth>)>;
+
+/// systemd service integration. Every call is a no-op when not running under systemd.
+pub struct BridgeSystemd {
+}
+
+impl BridgeSystemd {
+    /// Reports readiness once every projector has been started, with the projectors that failed to start (if any) as status.
+    pub fn notify_ready(failed_projectors: &[String]) {
+        let status = if failed_projectors.is_empty() {
+            String::from("Every projector is running")
+        } else {
+            format!("Projectors failed to start: {}", failed_projectors.join(", "))
+        };
+
+        if let Err(err) = sd_notify::notify(false, &[NotifyState::Ready, NotifyState::Status(&status)]) {
+            error!("Cannot notify systemd about readiness. {}", err);
+        }
+    }
+
+    pub fn notify_stopping() {
+        if let Err(err) = sd_notify::notify(false, &[NotifyState::Stopping]) {
+            error!("Cannot notify systemd about shutdown. {}", err);
+        }
+    }
+
+    /// Pings the systemd watchdog at half its interval for as long as every connector thread is healthy.
+    /// A hung connector stops the pings, so systemd restarts the service.
+    pub fn spawn_watchdog(projectors: BridgeSystemdProjectors) {
+        let mut watchdog_usec = 0;
+
+        if !sd_notify::watchdog_enabled(false, &mut watchdog_usec) {
+            return;
+        }
+
+        let interval = Duration::from_micros(watchdog_usec / 2);
+        debug!("systemd watchdog enabled, pinging every {:?}", interval);
+
+        thread::spawn(move || loop {
+            let unhealthy_projectors: Vec<&str> = projectors.iter()
+                .filter(|(_, health)| !health.is_healthy())
+                .map(|(projector_name, _)| projector_name.as_str())
+                .collect();
+
+            if unhealthy_projectors.is_empty() {
+                sd_notify::notify(false, &[NotifyState::Watchdog]).unwrap_or_default();
+            } else {
+                warn!("Connector thread hung or stopped, skipping watchdog ping. Projectors: {}", unhealthy_projectors.join(", "));
+            }
+
+            thread::sleep(interval);
+        });
+    }
+
+    /// Listening TCP sockets passed by systemd for socket activation (`LISTEN_FDS`, `LISTEN_PID`).
+    pub fn activated_listeners() -> Vec<TcpListener> {
+        let listen_fds = match sd_notify::listen_fds() {
+            Ok(listen_fds) => listen_fds,
+            Err(err) => {
+                error!("Cannot read sockets passed by systemd. {}", err);
+                return vec! [];
+            }
+        };
+
+        listen_fds
+            // systemd passes the sockets of `ListenStream=`, owned by this process from now on
+            .map(|fd| unsafe { TcpListener::from_raw_fd(fd) })
+            .filter_map(|listener| match listener.local_addr() {
+                Ok(local_address) => {
+                    info!("systemd passed a listening socket on {}", local_address);
+                    Some(listener)
+                },
+                Err(err) => {
+                    warn!("Ignoring a socket passed by systemd. {}", err);
+                    None
+                }
+            })
+            .collect()
+    }
+}